@@ -7,4 +7,31 @@ pub fn format_bytes(bytes: u64) -> String {
         n if n >= 10u64.pow(3) => format!("{:.2} KB", (n as f64)/10u64.pow(3) as f64),
         _ => format!("{} bytes", bytes),
     }
-}
\ No newline at end of file
+}
+
+// Parses an amount of bytes, optionally followed by a unit, eg. "500", "250K" or "1.5 MB"
+// Units use the same powers of 10 as format_bytes. Returns None if the input is invalid
+pub fn parse_bytes(input: &str) -> Option<u64> {
+    let input = input.trim().to_uppercase();
+    let input = input.trim_end_matches('B').trim_end_matches("BYTES").trim();
+    let (number, multiplier) = match input.chars().last() {
+        Some('K') => (&input[..input.len()-1], 10u64.pow(3)),
+        Some('M') => (&input[..input.len()-1], 10u64.pow(6)),
+        Some('G') => (&input[..input.len()-1], 10u64.pow(9)),
+        _ => (input, 1),
+    };
+    match number.trim().parse::<f64>() {
+        Ok(n) if n >= 0. => Some((n * multiplier as f64) as u64),
+        _ => None,
+    }
+}
+
+#[test]
+fn test_parse_bytes() {
+    assert_eq!(parse_bytes("500"), Some(500));
+    assert_eq!(parse_bytes("250K"), Some(250_000));
+    assert_eq!(parse_bytes("1.5 MB"), Some(1_500_000));
+    assert_eq!(parse_bytes("2g"), Some(2_000_000_000));
+    assert_eq!(parse_bytes("fast"), None);
+    assert_eq!(parse_bytes("-3"), None);
+}
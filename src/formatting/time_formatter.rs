@@ -30,4 +30,50 @@ pub fn time_since_timestamp(secs: i64) -> Time {
         minutes: ((diff % SECONDS_PER_HOUR)/SECONDS_PER_MINUTE) as i32,
         seconds: (diff % 60.) as i32,
     }
-}
\ No newline at end of file
+}
+
+// Formats a unix timestamp as a local date and time, eg. 2018-06-02 14:05:00
+pub fn format_timestamp(secs: i64) -> String {
+    match time::strftime("%Y-%m-%d %H:%M:%S", &time::at(time::Timespec::new(secs, 0))) {
+        Ok(v) => v,
+        Err(_e) => secs.to_string(),
+    }
+}
+
+// Reads a local date and time as a unix timestamp, the reverse of format_timestamp
+// Accepts "2018-06-02 14:05:00", "2018-06-02 14:05", "2018-06-02" and plain unix timestamps
+pub fn parse_timestamp(value: &str) -> Option<i64> {
+    let value = value.trim();
+    if let Ok(secs) = value.parse() {
+        return Some(secs);
+    }
+    for format in &["%Y-%m-%d %H:%M:%S", "%Y-%m-%d %H:%M", "%Y-%m-%d"] {
+        if let Ok(tm) = time::strptime(value, format) {
+            // strptime reads the time as UTC, shift it by the local offset
+            return Some(tm.to_timespec().sec - time::now().tm_utcoff as i64);
+        }
+    }
+    None
+}
+
+// Reads how often something should happen as a number of seconds
+// Accepts "hourly", "daily", "weekly" and a number of hours, days or weeks like "12h", "3d" or "2w"
+pub fn parse_interval(value: &str) -> Option<i64> {
+    let value = value.trim().to_lowercase();
+    match value.as_ref() {
+        "hourly" => return Some(60*60),
+        "daily" => return Some(24*60*60),
+        "weekly" => return Some(7*24*60*60),
+        _ => (),
+    }
+    let unit = match value.chars().last() {
+        Some('h') => 60*60,
+        Some('d') => 24*60*60,
+        Some('w') => 7*24*60*60,
+        _ => return None,
+    };
+    match value[..value.len()-1].trim().parse::<i64>() {
+        Ok(n) if n > 0 => Some(n * unit),
+        _ => None,
+    }
+}
@@ -1,13 +1,26 @@
 extern crate raze;
+extern crate reqwest;
 #[macro_use] extern crate text_io;
 #[macro_use] extern crate serde_derive;
 extern crate serde;
 extern crate serde_json;
+extern crate toml;
 extern crate time;
 extern crate glob;
 extern crate progress;
 extern crate sha1;
 extern crate scoped_pool;
+extern crate chacha20poly1305;
+extern crate scrypt;
+extern crate hmac;
+extern crate sha2;
+extern crate rand;
+extern crate base64;
+extern crate rpassword;
+extern crate zstd;
+extern crate fastcdc;
+#[cfg(unix)]
+extern crate xattr;
 
 use raze::engine::engine;
 use std::io::Write;
@@ -17,60 +30,163 @@ use storage::storage as storage_helper;
 mod formatting;
 use formatting::time_formatter::*;
 mod procedures;
+mod remote;
 
 
-// Name of the file containing program info/options
-const PERSISTENT_DATA_FILE_NAME: &str = &"backupdata";
-// Name of the file containing the list of folders to backup
-const BACKUP_LIST_FILE_NAME: &str = &"backuplist";
-// Name of the file containing credentials
-const CREDENTIALS_FILE_NAME: &str = &"raze_credentials";
-// After this many bytes, switch to upload_file_streaming to reduce memory usage
-const STREAM_UPLOAD_THRESHOLD: u64 = 5*1000*1000;
-// The amount of simultaneous uploads
-const UPLOAD_THREADS: usize = 4; // TODO Base this on CPU count?
-// The amount of simultaneous delete request senders
-const DELETE_THREADS: usize = 16; // Shouldn't be based on CPU count
+// Name of the configuration file, read from the configuration directory unless given with '--config'
+const CONFIG_FILE_NAME: &str = "raze.toml";
+// Purge refuses to hide more than this percentage of the backup at once unless forced, see 'purge_threshold'
+const DEFAULT_PURGE_THRESHOLD: u8 = 25;
+// Files hidden for at least this many days are deleted by 'delete-hidden', unless told otherwise
+const HIDDEN_GRACE_DAYS: u64 = 30;
+// Files in the bucket below this prefix belong to the program itself, not to the backup
+const REPOSITORY_PREFIX: &str = ".raze/";
+
+// The configuration in use, see storage::config
+fn config() -> &'static storage::config::Config {
+    storage::config::get()
+}
 
 fn main() {
     println!("Raze CLI - {}", env!("CARGO_PKG_VERSION"));
-    // First off, create the backuplist file if it doesn't exist
-    if !std::path::Path::new(BACKUP_LIST_FILE_NAME).exists() {
-        let mut write = std::fs::File::create(std::path::Path::new(BACKUP_LIST_FILE_NAME)).unwrap();
+    // If a command was given as arguments, run only that instead of the prompt
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    // Options before the command decide which configuration is used
+    let (global, args) = match procedures::command_line::parse_global_options(&args) {
+        Ok(v) => v,
+        Err(e) => {
+            println!("{}", e);
+            std::process::exit(procedures::command_line::EXIT_USAGE);
+        },
+    };
+    let dirs = match global.config_dir {
+        Some(ref dir) => storage::dirs::Dirs::single(dir),
+        None => match storage::dirs::Dirs::locate() {
+            Ok(v) => v,
+            Err(e) => {
+                println!("{}", e);
+                std::process::exit(procedures::command_line::EXIT_FAILURE);
+            },
+        },
+    };
+    if let Err(e) = dirs.create() {
+        println!("{}", e);
+        std::process::exit(procedures::command_line::EXIT_FAILURE);
+    }
+    // Older versions kept everything in the working directory, so the configuration file comes along first
+    // A directory given explicitly is used as it is
+    if global.config_dir.is_none() && global.config.is_none() {
+        let config_file = std::path::PathBuf::from(CONFIG_FILE_NAME);
+        if let Err(e) = storage::dirs::migrate(&[(config_file, dirs.config.join(CONFIG_FILE_NAME))]) {
+            println!("{}", e);
+            std::process::exit(procedures::command_line::EXIT_FAILURE);
+        }
+    }
+    match storage::config::Config::load(global.config.as_deref(), &global.overrides, global.profile.as_deref(), dirs) {
+        Ok(v) => storage::config::install(v),
+        Err(e) => {
+            println!("{}", e);
+            std::process::exit(procedures::command_line::EXIT_USAGE);
+        },
+    }
+    // Then the files it names
+    if global.config_dir.is_none() {
+        let moved = match storage::dirs::migrate(&config().legacy_files()) {
+            Ok(v) => v,
+            Err(e) => {
+                println!("{}", e);
+                std::process::exit(procedures::command_line::EXIT_FAILURE);
+            },
+        };
+        // Older versions left the credentials readable by anyone, which is refused now
+        let credentials = config().credentials_file();
+        if moved.contains(&credentials) {
+            if let Err(e) = procedures::authenticate::restrict_to_owner(&credentials) {
+                println!("Can't make '{}' readable by you only: {}", credentials.display(), e);
+            }
+        }
+    }
+    let command = match args.len() {
+        0 => None,
+        _ => match procedures::command_line::parse_command(&args, false) {
+            Ok(procedures::command_line::Command::Help) => {
+                procedures::command_line::print_help();
+                std::process::exit(procedures::command_line::EXIT_SUCCESS);
+            },
+            Ok(v) => Some(v),
+            Err(e) => {
+                println!("{}", e);
+                println!("Run 'raze-cli help' for a list of commands");
+                std::process::exit(procedures::command_line::EXIT_USAGE);
+            },
+        },
+    };
+    // First off, create the backuplist file if it doesn't exist and the configuration doesn't list the roots
+    if config().roots.is_none() && !config().backup_list_file().exists() {
+        let mut write = std::fs::File::create(config().backup_list_file()).unwrap();
         write.write_all("Absolute paths of directories to back up goes here\n\
         eg. /home/MyUser/Documents".as_bytes()).unwrap();
     }
-    // Then make sure a credentials file exists
-    if !std::path::Path::new(CREDENTIALS_FILE_NAME).exists() {
-        let mut write = std::fs::File::create(std::path::Path::new(CREDENTIALS_FILE_NAME)).unwrap();
-        write.write_all("xxxxxxxxxxxx:yyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyy".as_bytes()).unwrap();
-    }
 
 
     let mut raze = engine::Raze::new();
     println!("Authenticating...");
-    procedures::authenticate::auth(&mut raze);
+    let session = procedures::authenticate::auth(&mut raze, command.is_none());
 
+    if let Some(ref profile) = config().profile {
+        println!("Using profile '{}'", profile);
+    }
     // Find out when the last backup was performed, if ever
-    let mut persistent_data = match storage_helper::PersistentData::from_file(&std::path::Path::new(PERSISTENT_DATA_FILE_NAME)) {
+    let mut persistent_data = match storage_helper::PersistentData::from_file(&config().data_file()) {
+        Ok(ref v) if v.last_backup == 0 => {
+            println!("No successful backups have been made yet!");
+            v.clone()
+        },
         Ok(v) => {
             println!("It has been {} since the last successful backup", time_since_timestamp(v.last_backup));
             v
         },
-        Err(_e) => {
+        Err(storage_helper::StorageError::IOError(ref e)) if e.kind() == std::io::ErrorKind::NotFound => {
             println!("No successful backups have been made yet!");
-            storage_helper::PersistentData {
-                last_backup: time::get_time().sec,
-                active_bucket: String::new(),
-                bandwidth_limit: 0,
-            }
+            storage_helper::PersistentData::new()
+        },
+        // Starting over would lose the selected bucket and settings, so leave the file for the user to look at
+        Err(e) => {
+            println!("Can't read {}: {}", config().data_file().display(), e);
+            std::process::exit(procedures::command_line::EXIT_FAILURE);
         },
     };
-    persistent_data.save_to_file(&std::path::Path::new(PERSISTENT_DATA_FILE_NAME));
+    if let Err(e) = persistent_data.save_to_file(&config().data_file()) {
+        println!("Can't save {}: {}", config().data_file().display(), e);
+        std::process::exit(procedures::command_line::EXIT_FAILURE);
+    }
+    // A bucket in the configuration takes the place of the one picked with 'set_bucket'
+    if let Some(ref name) = config().bucket {
+        match procedures::settings::find_bucket(&mut raze, name) {
+            Ok(id) => persistent_data.override_bucket(id),
+            Err(e) => {
+                println!("{}", e);
+                std::process::exit(procedures::command_line::EXIT_FAILURE);
+            },
+        }
+    }
+    // So is its throttle, which the 'throttle' command can still change
+    // Neither is saved, so the ones picked with the commands are back once the configuration leaves them out
+    if let Some(limit) = config().throttle_limit() {
+        persistent_data.override_bandwidth_limit(limit);
+    }
+
+    if let Some(command) = command {
+        let result = procedures::command_line::run_command(&mut raze, &session, &mut persistent_data, command);
+        if let Err(ref e) = result {
+            println!("{}", e);
+        }
+        std::process::exit(procedures::command_line::exit_code(&result));
+    }
 
     println!("Type 'help' for a list of commands");
     // Continuously ask for commands, until the program exits
     loop {
-        procedures::command_prompt::command_prompt(&mut raze, &mut persistent_data);
+        procedures::command_prompt::command_prompt(&mut raze, &session, &mut persistent_data);
     }
 }
\ No newline at end of file
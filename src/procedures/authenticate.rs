@@ -1,43 +1,248 @@
 use std;
 use std::io::{stdout, Write};
+use std::path::Path;
+use std::process::Stdio;
 use raze::engine::engine::Raze;
 use raze;
+use rpassword;
+use serde_json;
+use remote::session::Session;
+use storage::crypto::SealedSecret;
+use storage::config::CredentialStore;
+
+// The key id and application key, named like the official B2 tools name them
+const KEY_ID_VARIABLE: &str = "B2_APPLICATION_KEY_ID";
+const KEY_VARIABLE: &str = "B2_APPLICATION_KEY";
+// The passphrase of an encrypted credentials file, asked for if it isn't set
+const CREDENTIALS_PASSPHRASE_VARIABLE: &str = "RAZE_CREDENTIALS_PASSPHRASE";
 
 /// Authenticates a raze instance
 ///
 /// This uses the users account id and API key to authenticate \
-/// This will attempt to read from a file. If the file is not found, it will prompt for this information.
-/// If the user supplies this information and the authentication succeeds, the auth information will be stored
-pub fn auth(raze: &mut Raze){
-    match raze.new_from_auth_file(std::path::Path::new(::CREDENTIALS_FILE_NAME)) {
-        Some(e) => {
-            println!("Failed to authenticate with credentials file");
-            match e {
-                raze::B2Error::B2Error(x) => println!("Server response: {}", x.message),
-                x => println!("Unexpected error: {:?}", x),
-            }
-            println!("Please manually enter authentication");
-            println!("Your account id and API key can be found via the website");
-            print!("Account id: ");
-            stdout().flush().unwrap();
-            let account_id: String = read!("{}\n");
-            print!("API key: ");
-            stdout().flush().unwrap();
-            let api_key: String = read!("{}\n");
-            let auth = format!("{}:{}", account_id, api_key);
-            match raze.authenticate(&auth) {
-                Some(e) => {
-                    println!("Authentication failure!");
-                    println!("{:?}", e);
-                    std::process::exit(0);
+/// They're taken from the environment, the command in credentials.command or the credentials file, in that order.
+/// If none of them has any, or they're rejected, it will prompt for this information.
+/// If the user supplies this information and the authentication succeeds, it is stored as credentials.store says
+///
+/// When not interactive, a failure exits the program instead of prompting
+///
+/// Returns a Session for the API calls the engine doesn't provide, authenticated with the same credentials
+pub fn auth(raze: &mut Raze, interactive: bool) -> Session {
+    match find_credentials(interactive) {
+        Ok(Some((source, auth))) => match authenticate_both(raze, &auth) {
+            Ok(session) => {
+                println!("Successfully authenticated");
+                return session
+            },
+            Err(e) => {
+                println!("Failed to authenticate with the credentials from {}", source);
+                match e {
+                    raze::B2Error::B2Error(x) => println!("Server response: {}", x.message),
+                    x => println!("Unexpected error: {:?}", x),
                 }
+            },
+        },
+        Ok(None) => println!("No credentials found"),
+        Err(e) => println!("{}", e),
+    }
+    if !interactive {
+        println!("Set {} and {}, set credentials.command or run without a command to enter the credentials",
+                 KEY_ID_VARIABLE, KEY_VARIABLE);
+        std::process::exit(::procedures::command_line::EXIT_FAILURE);
+    }
+    println!("Please manually enter authentication");
+    println!("Your account id and API key can be found via the website");
+    print!("Account id: ");
+    stdout().flush().unwrap();
+    let account_id: String = read!("{}\n");
+    let api_key = match rpassword::prompt_password("API key: ") {
+        Ok(v) => v,
+        Err(e) => {
+            println!("Failed to read the API key: {}", e);
+            std::process::exit(::procedures::command_line::EXIT_FAILURE);
+        },
+    };
+    let auth = format!("{}:{}", account_id.trim(), api_key.trim());
+    match authenticate_both(raze, &auth) {
+        Err(e) => {
+            println!("Authentication failure!");
+            println!("{:?}", e);
+            std::process::exit(::procedures::command_line::EXIT_FAILURE);
+        }
+        Ok(session) => {
+            println!("Successfully authenticated");
+            store_credentials(&auth);
+            session
+        },
+    }
+}
+
+// Authenticates both the engine and a raw API session with the same auth string
+fn authenticate_both(raze: &mut Raze, auth: &str) -> Result<Session, raze::B2Error> {
+    if let Some(e) = raze.authenticate(auth) {
+        return Err(e);
+    }
+    Session::new(auth)
+}
+
+// Looks for the auth string in every source in turn, returning where it was found along with it
+fn find_credentials(interactive: bool) -> Result<Option<(String, String)>, String> {
+    match (std::env::var(KEY_ID_VARIABLE), std::env::var(KEY_VARIABLE)) {
+        (Ok(id), Ok(key)) => return Ok(Some(("the environment".to_owned(), format!("{}:{}", id.trim(), key.trim())))),
+        (Ok(_), Err(_)) => return Err(format!("{} is set, but {} isn't", KEY_ID_VARIABLE, KEY_VARIABLE)),
+        (Err(_), Ok(_)) => return Err(format!("{} is set, but {} isn't", KEY_VARIABLE, KEY_ID_VARIABLE)),
+        _ => (),
+    }
+    if let Some(ref command) = ::config().credentials.command {
+        return run_credentials_command(command).map(|auth| Some((format!("'{}'", command), auth)));
+    }
+    let path = ::config().credentials_file();
+    Ok(read_credentials_file(&path, interactive)?.map(|auth| (format!("'{}'", path.display()), auth)))
+}
+
+// Runs the command from credentials.command, which prints the auth string on its first line
+// Its errors and prompts, like the one for a gpg passphrase, go to the terminal
+fn run_credentials_command(command: &str) -> Result<String, String> {
+    let mut shell = if cfg!(windows) {
+        let mut c = std::process::Command::new("cmd");
+        c.arg("/C");
+        c
+    } else {
+        let mut c = std::process::Command::new("sh");
+        c.arg("-c");
+        c
+    };
+    let output = match shell.arg(command).stdin(Stdio::inherit()).stderr(Stdio::inherit()).output() {
+        Ok(v) => v,
+        Err(e) => return Err(format!("Can't run '{}': {}", command, e)),
+    };
+    if !output.status.success() {
+        return Err(format!("'{}' failed, {}", command, output.status));
+    }
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    match stdout.lines().next().map(|l| l.trim()) {
+        Some(line) if line.contains(':') => Ok(line.to_owned()),
+        _ => Err(format!("'{}' should print keyId:applicationKey on its first line", command)),
+    }
+}
+
+// Reads the auth string from the credentials file, None if there is no such file
+//
+// The file holds either the auth string as it is, or a SealedSecret with it
+fn read_credentials_file(path: &Path, interactive: bool) -> Result<Option<String>, String> {
+    let contents = match std::fs::read_to_string(path) {
+        Ok(v) => v,
+        Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(format!("Can't read '{}': {}", path.display(), e)),
+    };
+    let sealed: SealedSecret = match serde_json::from_str(&contents) {
+        Ok(v) => v,
+        Err(_e) => {
+            // Anyone who can read the key can empty the bucket
+            if !is_private(path) {
+                return Err(format!("'{}' holds the key unencrypted and can be read by other users, run 'chmod 600 {}' first",
+                                   path.display(), path.display()));
+            }
+            return Ok(Some(contents.trim().to_owned()).filter(|c| !c.is_empty()));
+        },
+    };
+    if !is_private(path) {
+        println!("Warning: '{}' can be read by other users, run 'chmod 600 {}'", path.display(), path.display());
+    }
+    let passphrase = match std::env::var(CREDENTIALS_PASSPHRASE_VARIABLE) {
+        Ok(v) => v,
+        Err(_) if interactive => match rpassword::prompt_password("Credentials passphrase: ") {
+            Ok(v) => v,
+            Err(e) => return Err(format!("Failed to read the passphrase: {}", e)),
+        },
+        Err(_) => return Err(format!("'{}' is encrypted, set {} to its passphrase", path.display(), CREDENTIALS_PASSPHRASE_VARIABLE)),
+    };
+    match sealed.open(&passphrase) {
+        Ok(v) => Ok(Some(v)),
+        Err(e) => Err(format!("Can't decrypt '{}': {}", path.display(), e)),
+    }
+}
+
+// Keeps credentials entered at the prompt, see credentials.store
+fn store_credentials(auth: &str) {
+    let path = ::config().credentials_file();
+    let contents = match ::config().credentials.store {
+        CredentialStore::None => {
+            println!("The credentials are not saved, set credentials.store to keep them");
+            return;
+        },
+        CredentialStore::Plaintext => auth.to_owned(),
+        CredentialStore::Encrypted => {
+            println!("The credentials are saved encrypted, enter a passphrase for them or leave it empty to not save them");
+            let passphrase = match rpassword::prompt_password("Credentials passphrase: ") {
+                Ok(ref v) if v.is_empty() => {
+                    println!("The credentials are not saved");
+                    return;
+                },
+                Ok(v) => v,
+                Err(e) => {
+                    println!("Failed to read the passphrase, the credentials are not saved: {}", e);
+                    return;
+                },
+            };
+            match rpassword::prompt_password("Repeat the passphrase: ") {
+                Ok(ref v) if *v == passphrase => (),
                 _ => {
-                    println!("Successfully authenticated, credentials stored file: '{}' ", ::CREDENTIALS_FILE_NAME);
-                    let mut cred_file = std::fs::File::create(std::path::Path::new(::CREDENTIALS_FILE_NAME)).unwrap();
-                    cred_file.write_all(auth.as_bytes()).unwrap();
+                    println!("The passphrases don't match, the credentials are not saved");
+                    return;
                 },
             }
+            serde_json::to_string(&SealedSecret::seal(&passphrase, auth)).unwrap()
         },
-        None => println!("Successfully authenticated")
+    };
+    match write_private(&path, contents.as_bytes()) {
+        Ok(_) => println!("Credentials stored in file: '{}'", path.display()),
+        Err(e) => println!("Failed to save the credentials in '{}': {}", path.display(), e),
+    }
+    if ::config().credentials.store == CredentialStore::Encrypted {
+        println!("Set {} to its passphrase to use it without being asked, eg. from cron", CREDENTIALS_PASSPHRASE_VARIABLE);
+    }
+}
+
+// Whether only the owner can read the file, always the case where there are no such permissions
+#[cfg(unix)]
+fn is_private(path: &Path) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+    match std::fs::metadata(path) {
+        Ok(m) => m.permissions().mode() & 0o077 == 0,
+        Err(_e) => false,
+    }
+}
+
+#[cfg(not(unix))]
+fn is_private(_path: &Path) -> bool {
+    true
+}
+
+/// Lets only the owner read and write a file, like credentials written by this program
+#[cfg(unix)]
+pub fn restrict_to_owner(path: &Path) -> std::io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))
+}
+
+#[cfg(not(unix))]
+pub fn restrict_to_owner(_path: &Path) -> std::io::Result<()> {
+    Ok(())
+}
+
+// Replaces the file with one only the owner can read, before anything is written to it
+fn write_private(path: &Path, contents: &[u8]) -> std::io::Result<()> {
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+        // The mode only applies to new files
+        if path.exists() {
+            restrict_to_owner(path)?;
+        }
     }
-}
\ No newline at end of file
+    let mut write = options.open(path)?;
+    write.write_all(contents)
+}
@@ -1,25 +1,69 @@
 use std;
 use std::io::{stdout, Write};
-use raze::engine::engine;
-use raze;
+use std::path::{Path, PathBuf};
+use std::collections::BTreeMap;
 use formatting::size_formatter::format_bytes;
-use storage::storage as storage_helper;
+use storage::storage::{self as storage_helper, EntryKind};
+use storage::index::IndexEntry;
+use storage::crypto::Keys;
+use storage::compression;
+use storage::paths;
+use storage::filter::Filter;
+use storage::manifest::{Manifest, ManifestFile};
+use storage::metadata::FileMetadata;
+use storage::plan::{BackupPlan, Change, PlanEntry, PlanFormat};
+use remote::session::Session;
+use remote::files;
 use scoped_pool::Pool;
 use std::sync::{Arc, Mutex};
 use std::time::Duration;
 use progress;
 use time;
+use zstd;
+use procedures::error::ProcedureError;
+use procedures::large_file::{self, UploadTarget};
 
-pub fn perform_backup(raze: &mut engine::Raze ,persistent_data: &mut storage_helper::PersistentData) {
+/// Options for the 'backup' command
+#[derive(Debug, PartialEq, Default)]
+pub struct BackupOptions {
+    /// Decide whether to upload by comparing SHA-1 hashes instead of modification times
+    pub checksum: bool,
+    /// Also record the extended attributes of every file
+    pub xattrs: bool,
+    /// Only work out what would be uploaded, without uploading anything
+    pub dry_run: bool,
+    /// How the dry run plan is written
+    pub format: PlanFormat,
+    /// File to write the dry run plan to instead of printing it
+    pub output: Option<PathBuf>,
+    /// Skip the backup unless the schedule says one is due
+    pub if_due: bool,
+}
+
+pub fn perform_backup(session: &Session, persistent_data: &mut storage_helper::PersistentData, options: BackupOptions) -> Result<(), ProcedureError> {
     // Verify that a bucket is selected
     if persistent_data.active_bucket == "" {
-        println!("Please set a bucket first with the 'set_bucket' command");
-        return
+        return Err(ProcedureError::NoBucketSelected)
     }
-    // Set the active bucket
-    raze.set_active_bucket(persistent_data.active_bucket.clone());
 
+    let start_time = time::get_time().sec;
+    if options.if_due && !::config().backup_due(persistent_data.last_backup, start_time) {
+        println!("No backup is due yet, the schedule is {}", ::config().schedule.as_ref().unwrap());
+        return Ok(())
+    }
     println!("Backup start");
+    // Encrypted buckets get their contents and names encrypted before uploading
+    let keys = ::procedures::repository::open_repository(session, &persistent_data.active_bucket)?;
+    let chunking = ::procedures::repository::read_chunking_config(session, &persistent_data.active_bucket)?;
+    if options.dry_run {
+        return dry_run(session, persistent_data, &options, keys.as_ref(), chunking.is_some());
+    }
+    if let Some(config) = chunking {
+        return ::procedures::chunked::perform_chunked_backup(session, persistent_data, &options, keys.as_ref(), &config);
+    }
+    if options.checksum {
+        println!("Comparing file contents by SHA-1, this reads every previously uploaded file");
+    }
 
     // Notify the user that they are throttling the upload
     if persistent_data.bandwidth_limit > 0 {
@@ -29,17 +73,14 @@ pub fn perform_backup(raze: &mut engine::Raze ,persistent_data: &mut storage_hel
     println!("Constructing file list");
 
     // Get a list of files for uploading
-    let file_list = storage_helper::create_file_list(
-        storage_helper::read_lines_to_vec(
-            std::path::Path::new(::BACKUP_LIST_FILE_NAME)).unwrap());
+    let file_list = backup_file_list(persistent_data.symlinks)?;
     let file_count = file_list.len(); // mut since it may be decreased if duplicates are discovered
     // Get the total size of those files
     let mut list_size = storage_helper::get_total_size(&file_list); // mut since it may be decreased if duplicates are discovered
     println!("Uploading up to {} ({} bytes) across {} files", format_bytes(list_size), list_size, file_count);
 
     if list_size == 0 {
-        println!("It seems like the {} doesn't exist or contains no entries, aborting", ::BACKUP_LIST_FILE_NAME);
-        return
+        return Err(ProcedureError::EmptyBackupList)
     }
 
     // Create a progress bar
@@ -48,115 +89,125 @@ pub fn perform_backup(raze: &mut engine::Raze ,persistent_data: &mut storage_hel
     let bar = progress::Bar::new();
     let bar = Arc::new(Mutex::new(bar));
     let finished_uploads = Arc::new(Mutex::new(0));
+    let failed_uploads = Arc::new(Mutex::new(0));
 
-    // Before we start uploading, we should check if the file is also on the server
-    // To do this, we retrieve a list of all files on the server,
-    // check if one of them has the same path+name as the one we're uploading and
+    // Before we start uploading, we should check if the file is already on the server
+    // To do this, we look it up in the local index of uploaded files, which is built
+    // from a listing of the bucket the first time around
     println!("Synchronizing changes, this may take a bit...");
-    let mut stored_file_list = raze.list_all_file_names(&persistent_data.active_bucket, 1000).unwrap();
-    // Sort it so we can use binary search for finding elements
-    stored_file_list.sort();
+    let index = ::procedures::index::load_index(session, &persistent_data.active_bucket)?;
+    let index = Arc::new(Mutex::new(index));
     let revised_file_count = Arc::new(Mutex::new(file_count));
+    // Large files that didn't finish uploading last time continue where they stopped
+    let pending = Mutex::new(large_file::load_pending(&persistent_data.active_bucket));
+    let large_file_turn = Mutex::new(());
+    let target = UploadTarget {
+        session,
+        bucket_id: &persistent_data.active_bucket,
+        pending: &pending,
+        bandwidth: persistent_data.bandwidth_limit/::config().transfer.upload_threads,
+        large_file_turn: &large_file_turn,
+    };
+    let target = &target;
 
     // Create a scoped pool and queue each file in the list for uploading
-    let pool = Pool::new(::UPLOAD_THREADS);
+    let pool = Pool::new(::config().transfer.upload_threads);
     pool.scoped(|scope| {
         for i in 0..file_count {
-            // First, convert the Path to a prefix and a filename
-            // The prefix will be everything between eg. C:\ and the filename, eg. Users\Kongou
-            let entry_parent = file_list[i].parent();
-            let mut prefix = "";
-            if entry_parent.is_some() {
-                let entry_str = entry_parent.unwrap().to_str().unwrap();
-                let fwd = entry_str.find("/");
-                let bwd = entry_str.find("\\");
-                if fwd.is_some() {
-                    prefix = &entry_str[fwd.unwrap() + 1..];
-                } else if bwd.is_some() {
-                    prefix = &entry_str[bwd.unwrap() + 1..];
-                }
-            }
-
-            // Construct a StoredFile with the intended name so we can search for it
-            let sf = raze::api::files::structs::StoredFile {
-                file_name: format!("{}/{}", prefix, file_list[i].file_name().unwrap().to_str().unwrap()).replace("\\", "/"),
-                file_id: "".to_owned(),
-                account_id: None,
-                bucket_id: None,
-                content_length: 0,
-                content_sha1: "".to_owned(),
-                content_type: "".to_owned(),
-                action: "".to_owned(),
-                upload_timestamp: 0
+            let file_name = match keys {
+                Some(ref k) => k.encrypt_name(&file_list[i].name),
+                None => file_list[i].name.clone(),
             };
-            // If it's found, skip to the next file, if not, queue it for uploading
-            let do_upload: bool;
-            match stored_file_list.binary_search(&sf) {
-                Ok(v) => { // A file with the same path+name exists
-                    // Check if the local file was modified since it was last uploaded
-                    let metadata = std::fs::metadata(&file_list[i]).unwrap();
-                    let modified_time = match metadata.modified().unwrap().duration_since(std::time::UNIX_EPOCH) {
-                        Ok(v) => v.as_secs()*1000, // Convert seconds to milliseconds
-                        Err(_e) => 0u64
-                    };
-                    //println!("{} vs {} for {:?}",modified_time,stored_file_list[i].upload_timestamp,&file_list[i]);
-                    if modified_time > stored_file_list[v].upload_timestamp {
-                        do_upload = true;
-                    }else{
-                        do_upload = false;
-                        let mut data = revised_file_count.clone();
-                        *data.lock().unwrap() -= 1;
-                        list_size -= metadata.len();
-                    }
+
+            // Deleted or made unreadable since the list was made, that counts as a failed upload
+            let (size, modified_time) = match entry_stats(&file_list[i]) {
+                Ok(v) => v,
+                Err(e) => {
+                    println!();
+                    println!("Failed to read {}: {}", file_list[i].path.display(), e);
+                    *failed_uploads.lock().unwrap() += 1;
+                    *revised_file_count.lock().unwrap() -= 1;
+                    continue;
                 },
-                Err(_e) => { // No matching path+name exists
-                    do_upload = true;
-                }
+            };
+            // Encrypted names are about a third longer, so paths well below the limit can already go over it
+            // B2 would refuse the upload on every attempt
+            if file_name.len() > files::MAX_FILE_NAME_LEN {
+                println!();
+                println!("Skipping {}, its name would take {} bytes in the bucket and B2 allows {}",
+                         file_list[i].path.display(), file_name.len(), files::MAX_FILE_NAME_LEN);
+                *failed_uploads.lock().unwrap() += 1;
+                *revised_file_count.lock().unwrap() -= 1;
+                list_size -= size;
+                continue;
             }
-            if !do_upload {
+            // If it's found and unchanged, skip to the next file, if not, queue it for uploading
+            let change = compare_entry(&file_list[i], size, modified_time, index.lock().unwrap().files.get(&file_name), options.checksum);
+            if change == Change::Unchanged {
+                let data = revised_file_count.clone();
+                *data.lock().unwrap() -= 1;
+                list_size -= size;
                 continue;
             }
             // Clone all the data we pass to the thread
-            let entry = file_list[i].clone();
-            let mut r = raze.clone();
+            let entry = file_list[i].path.clone();
+            let kind = &file_list[i].kind;
+            let display_name = &file_list[i].name;
             let fin_uploads = finished_uploads.clone();
-            let bandwidth_limit = persistent_data.bandwidth_limit.clone();
+            let failed = failed_uploads.clone();
+            let idx = index.clone();
+            let keys = &keys;
+            let xattrs = options.xattrs;
 
             // Queue the upload tasks
-            // Every file gets a maximum of 5 attempts in case they fail for any reason
+            // Every file gets a few attempts, see transfer.retries, in case they fail for any reason
             // This loop will first decide which upload time to use, then call that upload
             // If the upload fails, it'll sleep and retry
             scope.execute(move || {
-                for attempts in 0..5 {
+                for attempts in 0..::config().transfer.retries {
                     // We need to decide which upload type to use
-                    // First of all, we check whether or not we're throttling our uploads
-                    let result = match bandwidth_limit {
-                        // If we're not throttling:
-                        // Decide which upload type to use, based on the value of STREAM_UPLOAD_THRESHOLD
-                        0 => {
-                            match entry.metadata().unwrap().len() {
-                                x if x < ::STREAM_UPLOAD_THRESHOLD => r.upload_file(entry.as_ref(), prefix),
-                                _ => {
-                                    r.upload_file_streaming(entry.as_ref(), prefix)
-                                },
-                            }
+                    // Permissions, ownership and the exact modification time go along with every type
+                    let result = match *kind {
+                        EntryKind::Symlink(ref link) => upload_symlink(target, keys.as_ref(), link, &file_name)
+                            .map(|v| IndexEntry::from_remote_file(&v, modified_time)),
+                        EntryKind::Directory => upload_empty(target, keys.as_ref(), &file_name, metadata_info(keys.as_ref(), &entry, xattrs))
+                            .map(|v| IndexEntry::from_remote_file(&v, modified_time)),
+                        EntryKind::File => match entry.metadata() {
+                            Err(e) => Err(format!("Failed to read {}: {}", entry.display(), e)),
+                            Ok(metadata) => {
+                                let len = metadata.len();
+                                let info = metadata_info(keys.as_ref(), &entry, xattrs);
+                                match compression::choose_codec(&entry, len) {
+                                    Err(e) => Err(format!("Failed to read {}: {}", entry.display(), e)),
+                                    // Encrypted and compressed files are written to a temporary file first
+                                    Ok(codec) if keys.is_some() || codec.is_some() =>
+                                        upload_transformed(target, keys.as_ref(), codec, &entry, &file_name, modified_time, info),
+                                    // Large files are uploaded in parts
+                                    Ok(_) if len > ::config().transfer.large_file_threshold =>
+                                        large_file::upload_large_file(target, &file_name, &entry, &entry, &info)
+                                            .map(|v| IndexEntry::from_remote_file(&v, modified_time)),
+                                    // Everything else is uploaded as is, in one go
+                                    Ok(_) => upload_plain(target, &entry, &file_name, len, &info)
+                                        .map(|v| IndexEntry::from_remote_file(&v, modified_time)),
+                                }
+                            },
                         },
-                        // If we are, use throttled upload.
-                        // Each thread gets the same bandwidth, equal to bandwidth/num_threads
-                        _ => r.upload_file_throttled(entry.as_ref(), prefix, bandwidth_limit/::UPLOAD_THREADS),
                     };
 
                     match result {
-                        Ok(_v) => break,
+                        Ok(v) => {
+                            idx.lock().unwrap().record(&file_name, v);
+                            break
+                        },
                         Err(e) => {
-                            if attempts == 4 {
+                            if attempts + 1 == ::config().transfer.retries {
                                 println!();
-                                println!("Failed to upload {} after 5 attempts",
-                                         format!("{}/{}", prefix, entry.file_name().unwrap().to_str().unwrap()).replace("\\", "/"));
-                                println!("{:?}", e);
+                                println!("Failed to upload {} after {} attempts", display_name, ::config().transfer.retries);
+                                println!("{}", e);
+                                *failed.lock().unwrap() += 1;
                             }else{
                                 // Sleep for a bit before retrying
-                                std::thread::sleep(Duration::from_millis(5000));
+                                std::thread::sleep(Duration::from_secs(::config().transfer.retry_delay));
                             }
                         },
                     }
@@ -176,10 +227,21 @@ pub fn perform_backup(raze: &mut engine::Raze ,persistent_data: &mut storage_hel
         }
 
         // Start the loop that prints the progress bar and checks if we're done yet
+        // The index is saved every now and then, so an interrupted backup doesn't forget what it uploaded
         let progress_bar = bar.clone();
         progress_bar.lock().unwrap().set_job_title("Upload in progress");
+        let mut ticks = 0;
         loop {
             std::thread::sleep(Duration::from_millis(1000));
+            ticks += 1;
+            if ticks % 60 == 0 {
+                // Not fatal, the save at the end reports it if it keeps failing
+                let saved = index.lock().unwrap().save_to_file(&::config().index_file());
+                if let Err(e) = saved {
+                    println!();
+                    println!("Can't save {}: {}", ::config().index_file().display(), e);
+                }
+            }
             let data2_clone = finished_uploads.clone();
             let data2 = data2_clone.lock().unwrap();
             progress_bar.lock().unwrap().set_job_title(&format!("Upload in progress ({}/{})",*data2, *data));
@@ -190,7 +252,342 @@ pub fn perform_backup(raze: &mut engine::Raze ,persistent_data: &mut storage_hel
         }
     });
     println!();
+    let saved = index.lock().unwrap().save_to_file(&::config().index_file());
+    if let Err(e) = saved {
+        return Err(ProcedureError::save_failed(&::config().index_file(), e))
+    }
+
+    // Record what every file in the backup list looks like in the bucket now, so it can be restored later
+    // even after newer versions are uploaded
+    let mut snapshot = Manifest::new(start_time);
+    for (name, entry) in file_list.iter().filter_map(|file| {
+        let file_name = match keys {
+            Some(ref k) => k.encrypt_name(&file.name),
+            None => file.name.clone(),
+        };
+        index.lock().unwrap().files.get(&file_name).cloned().map(|entry| (file.name.clone(), entry))
+    }) {
+        snapshot.files.insert(name, ManifestFile {
+            size: entry.size,
+            modified: entry.modified,
+            sha1: entry.sha1,
+            chunks: Vec::new(),
+            file_id: Some(entry.file_id),
+            // Stored with the uploaded file already
+            posix: None,
+            xattrs: None,
+            symlink: None,
+        });
+    }
+    // Only count the backup as successful if every file made it
+    // A snapshot would list the older versions of the files that failed, as if they were current
+    let failed = *failed_uploads.lock().unwrap();
+    if failed > 0 {
+        println!("Backup completed, but {} files failed to upload, no snapshot was saved", failed);
+        return Err(ProcedureError::Incomplete(failed))
+    }
+    ::procedures::repository::save_manifest(session, &persistent_data.active_bucket, keys.as_ref(), &snapshot)?;
+    println!("Saved snapshot {} with {} files", snapshot.id(), snapshot.files.len());
     println!("Backup successfully completed");
     persistent_data.last_backup = time::get_time().sec;
-    persistent_data.save_to_file(&std::path::Path::new(::PERSISTENT_DATA_FILE_NAME));
-}
\ No newline at end of file
+    persistent_data.last_backup_start = start_time;
+    persistent_data.save_to_file(&::config().data_file()).map_err(ProcedureError::SaveFailed)?;
+    Ok(())
+}
+
+/// Local size and modification time in milliseconds of an entry in the backup list
+///
+/// Links and empty directories are stored as empty files, so their size is 0. \
+/// Stored links are compared by the link itself, not what it points to. \
+/// Fails if the entry was deleted or can't be read anymore since the list was made
+pub fn entry_stats(file: &storage_helper::BackupFile) -> std::io::Result<(u64, u64)> {
+    let (metadata, size) = match file.kind {
+        EntryKind::File => {
+            let metadata = std::fs::metadata(&file.path)?;
+            let size = metadata.len();
+            (metadata, size)
+        },
+        EntryKind::Symlink(_) => (std::fs::symlink_metadata(&file.path)?, 0),
+        EntryKind::Directory => (std::fs::metadata(&file.path)?, 0),
+    };
+    let modified_time = match metadata.modified()?.duration_since(std::time::UNIX_EPOCH) {
+        Ok(v) => v.as_secs()*1000, // Convert seconds to milliseconds
+        Err(_e) => 0u64
+    };
+    Ok((size, modified_time))
+}
+
+/// Compares an entry in the backup list to what was uploaded for it, if anything
+///
+/// With 'checksum' the contents are compared by SHA-1, otherwise by size and modification time
+pub fn compare_entry(file: &storage_helper::BackupFile, size: u64, modified: u64, stored: Option<&IndexEntry>, checksum: bool) -> Change {
+    let stored = match stored {
+        Some(v) => v,
+        None => return Change::New,
+    };
+    // B2 has no whole-file hash for large files ("none"), those fall back to modification time
+    let changed = if checksum && stored.sha1 != "none" && file.kind == EntryKind::File {
+        // Different sizes can't have the same content, so skip hashing those
+        size != stored.size || match storage_helper::sha1_file(&file.path) {
+            Ok(hash) => hash != stored.sha1,
+            Err(_e) => true,
+        }
+    } else {
+        // Check if the local file was modified since it was last uploaded
+        size != stored.size || modified > stored.modified
+    };
+    if changed { Change::Modified } else { Change::Unchanged }
+}
+
+// Scans the backup list and compares it to the bucket like a backup would, then writes out the plan
+//
+// Nothing is uploaded, and the time of the last backup is left alone
+fn dry_run(session: &Session, persistent_data: &storage_helper::PersistentData, options: &BackupOptions,
+           keys: Option<&Keys>, chunked: bool) -> Result<(), ProcedureError> {
+    println!("Dry run, nothing will be uploaded");
+    println!("Constructing file list");
+    let file_list = backup_file_list(persistent_data.symlinks)?;
+
+    println!("Synchronizing changes, this may take a bit...");
+    // Chunked buckets describe every file in their latest manifest, others in the index
+    let stored: BTreeMap<String, IndexEntry> = if chunked {
+        let previous = ::procedures::repository::latest_manifest(session, &persistent_data.active_bucket, keys)?.unwrap_or_default();
+        previous.files.into_iter().map(|(name, file)| (name, IndexEntry {
+            file_id: String::new(),
+            size: file.size,
+            modified: file.modified,
+            sha1: file.sha1,
+        })).collect()
+    } else {
+        ::procedures::index::load_index(session, &persistent_data.active_bucket)?.files
+    };
+
+    let mut plan = BackupPlan::default();
+    for file in &file_list {
+        // The index is keyed by the names in the bucket, which are encrypted outside chunked mode
+        let stored_name = match keys {
+            Some(k) if !chunked => k.encrypt_name(&file.name),
+            _ => file.name.clone(),
+        };
+        let (size, modified) = match entry_stats(file) {
+            Ok(v) => v,
+            Err(e) => {
+                println!("Skipping {}, it can't be read: {}", file.path.display(), e);
+                continue;
+            },
+        };
+        plan.entries.push(PlanEntry {
+            change: compare_entry(file, size, modified, stored.get(&stored_name), options.checksum),
+            name: file.name.clone(),
+            path: file.path.to_string_lossy().into_owned(),
+            size,
+        });
+    }
+
+    match options.output {
+        Some(ref path) => {
+            if let Err(e) = plan.save_to_file(path, options.format) {
+                return Err(ProcedureError::LocalFile(format!("Failed to write the plan to {}: {}", path.display(), e)))
+            }
+            println!("Wrote the plan for {} files to {}", plan.entries.len(), path.display());
+        },
+        None => match options.format {
+            PlanFormat::Json => println!("{}", plan.to_json()),
+            PlanFormat::Csv => print!("{}", plan.to_csv()),
+            PlanFormat::Text => {
+                for entry in plan.entries.iter().filter(|e| e.change != Change::Unchanged) {
+                    println!("{:<10} {:>10}  {}", entry.change.as_str(), format_bytes(entry.size), entry.name);
+                }
+            },
+        },
+    }
+    let (new, new_size) = plan.total(Change::New);
+    let (modified, modified_size) = plan.total(Change::Modified);
+    let (unchanged, unchanged_size) = plan.total(Change::Unchanged);
+    println!("{} new files ({}), {} modified files ({}), {} unchanged files ({}) would be skipped",
+             new, format_bytes(new_size), modified, format_bytes(modified_size), unchanged, format_bytes(unchanged_size));
+    println!("A backup would upload {} ({} bytes) across {} files", format_bytes(new_size + modified_size),
+             new_size + modified_size, new + modified);
+    Ok(())
+}
+
+/// The file info recording the permissions, ownership and modification time of a file
+///
+/// In encrypted buckets the values are encrypted like names, since they say a fair bit about a file
+pub fn metadata_info(keys: Option<&Keys>, path: &Path, xattrs: bool) -> BTreeMap<String, String> {
+    let mut info = BTreeMap::new();
+    let metadata = match FileMetadata::read(path, xattrs) {
+        Ok(v) => v,
+        Err(_e) => return info,
+    };
+    info.insert(files::INFO_POSIX.to_owned(), seal(keys, metadata.encode()));
+    if let Some(encoded) = metadata.encode_xattrs() {
+        info.insert(files::INFO_XATTRS.to_owned(), seal(keys, encoded));
+    }
+    info
+}
+
+// Encrypts a file info value like a name if the bucket is encrypted
+fn seal(keys: Option<&Keys>, value: String) -> String {
+    match keys {
+        Some(k) => k.encrypt_name(&value),
+        None => value,
+    }
+}
+
+/// The backup roots from the configuration or the backup list, failing if the list can't be read
+pub fn backup_roots() -> Result<Vec<storage_helper::BackupRoot>, ProcedureError> {
+    match ::config().backup_roots() {
+        Ok(v) => Ok(v),
+        Err(e) => Err(ProcedureError::LocalFile(format!("Can't read {}: {}", ::config().backup_list_file().display(), e))),
+    }
+}
+
+/// The patterns excluded from every root, failing if the exclude list can't be read
+pub fn exclude_filter() -> Result<Filter, ProcedureError> {
+    match ::config().exclude_filter() {
+        Ok(v) => Ok(v),
+        Err(e) => Err(ProcedureError::LocalFile(format!("Can't read {}: {}", ::config().exclude_list_file().display(), e))),
+    }
+}
+
+/// Reads the backup list and lists every file to back up, treating links according to 'symlinks'
+///
+/// Fails if files would be stored under the same name, which would have them overwrite each other in the bucket
+pub fn backup_file_list(symlinks: storage_helper::SymlinkPolicy) -> Result<Vec<storage_helper::BackupFile>, ProcedureError> {
+    let roots = backup_roots()?;
+    let problems = paths::check_roots(&roots);
+    for problem in &problems {
+        println!("{}", problem);
+    }
+    let file_list = storage_helper::create_file_list(&roots,
+        &exclude_filter()?, symlinks);
+    let collisions = paths::find_collisions(&file_list);
+    for (name, local) in &collisions {
+        println!("These files would all be stored as '{}':", name);
+        for path in local {
+            println!("    {}", path.display());
+        }
+    }
+    if !problems.is_empty() || !collisions.is_empty() {
+        println!("Give the entries in '{}' distinct names, eg. 'c-data = C:\\data'", ::config().backup_list_file().display());
+        return Err(ProcedureError::NameCollision(problems.len() + collisions.len()))
+    }
+    Ok(file_list)
+}
+
+// Compresses with 'codec' and/or encrypts a file and uploads the result
+//
+// The upload needs the length and SHA-1 of what is sent up front, so the result is written to
+// a temporary file first. Whether to compress is decided beforehand, see compression::choose_codec
+// Large results are uploaded in parts, keeping the temporary file until that's done
+fn upload_transformed(target: &UploadTarget, keys: Option<&Keys>, codec: Option<&str>, path: &Path, remote_name: &str, modified: u64,
+                      mut info: BTreeMap<String, String>) -> Result<IndexEntry, String> {
+    // An interrupted upload kept what it was uploading, so continue with that instead of starting over
+    if let Some(upload) = large_file::resumable(target, remote_name) {
+        if upload.source == path.to_string_lossy() && upload.content != upload.source {
+            let v = large_file::upload_large_file(target, remote_name, path, Path::new(&upload.content), &upload.info)?;
+            return Ok(IndexEntry {
+                file_id: v.file_id,
+                size: upload.source_size,
+                modified,
+                sha1: upload.info.get(files::INFO_SHA1).cloned().unwrap_or_else(|| "none".to_owned()),
+            })
+        }
+    }
+
+    let temp = storage_helper::TempFile::new();
+    let transformed = transform(keys, codec, path, &temp.path).map_err(|e| e.to_string())?;
+
+    // Remember what the original looked like, so the index can be rebuilt and restores verified
+    if keys.is_some() {
+        info.insert(files::INFO_ENCRYPTED.to_owned(), "1".to_owned());
+    }
+    if let Some(c) = codec {
+        info.insert(files::INFO_CODEC.to_owned(), c.to_owned());
+    }
+    info.insert(files::INFO_SIZE.to_owned(), transformed.source_len.to_string());
+    info.insert(files::INFO_SHA1.to_owned(), transformed.source_sha1.clone());
+
+    let v = if transformed.len > ::config().transfer.large_file_threshold {
+        let content = temp.keep();
+        large_file::upload_large_file(target, remote_name, path, &content, &info)?
+    } else {
+        let upload_auth = files::get_upload_url(target.session, target.bucket_id).map_err(|e| format!("{:?}", e))?;
+        let read = std::fs::File::open(&temp.path).map_err(|e| e.to_string())?;
+        match target.bandwidth {
+            0 => files::upload_file(target.session, &upload_auth, remote_name, read, transformed.len, &transformed.sha1, &info),
+            _ => files::upload_file(target.session, &upload_auth, remote_name, files::ThrottledRead::new(read, target.bandwidth),
+                                    transformed.len, &transformed.sha1, &info),
+        }.map_err(|e| format!("{:?}", e))?
+    };
+    Ok(IndexEntry {
+        file_id: v.file_id,
+        size: transformed.source_len,
+        modified,
+        sha1: transformed.source_sha1,
+    })
+}
+
+// Uploads a file as is, sending its SHA-1 after the content so it's only read once
+fn upload_plain(target: &UploadTarget, path: &Path, remote_name: &str, len: u64, info: &BTreeMap<String, String>) -> Result<files::RemoteFile, String> {
+    let upload_auth = files::get_upload_url(target.session, target.bucket_id).map_err(|e| format!("{:?}", e))?;
+    let read = files::AppendSha1::new(std::fs::File::open(path).map_err(|e| e.to_string())?);
+    let len = len + files::SHA1_AT_END_LEN;
+    match target.bandwidth {
+        0 => files::upload_file(target.session, &upload_auth, remote_name, read, len, files::SHA1_AT_END, info),
+        _ => files::upload_file(target.session, &upload_auth, remote_name, files::ThrottledRead::new(read, target.bandwidth),
+                                len, files::SHA1_AT_END, info),
+    }.map_err(|e| format!("{:?}", e))
+}
+
+// Stores a symbolic link as an empty file, with where it points in the file info
+fn upload_symlink(target: &UploadTarget, keys: Option<&Keys>, link: &Path, remote_name: &str) -> Result<files::RemoteFile, String> {
+    let mut info = BTreeMap::new();
+    info.insert(files::INFO_SYMLINK.to_owned(), seal(keys, link.to_string_lossy().into_owned()));
+    upload_empty(target, keys, remote_name, info)
+}
+
+// Uploads an empty file that only carries file info, like the marker of an empty directory
+fn upload_empty(target: &UploadTarget, keys: Option<&Keys>, remote_name: &str, mut info: BTreeMap<String, String>) -> Result<files::RemoteFile, String> {
+    // Restore needs to know the info is encrypted
+    if keys.is_some() {
+        info.insert(files::INFO_ENCRYPTED.to_owned(), "1".to_owned());
+    }
+    let upload_auth = files::get_upload_url(target.session, target.bucket_id).map_err(|e| format!("{:?}", e))?;
+    files::upload_file(target.session, &upload_auth, remote_name, std::io::empty(), 0, storage_helper::EMPTY_SHA1, &info)
+        .map_err(|e| format!("{:?}", e))
+}
+
+// Size and SHA-1 of a file before and after transform
+struct Transformed {
+    source_len: u64,
+    source_sha1: String,
+    len: u64,
+    sha1: String,
+}
+
+// Writes the file at 'path' to 'output', compressed with 'codec' and then encrypted with 'keys' if given
+fn transform(keys: Option<&Keys>, codec: Option<&str>, path: &Path, output: &Path) -> std::io::Result<Transformed> {
+    let mut source = storage_helper::HashingReader::new(std::fs::File::open(path)?);
+    let mut write = storage_helper::HashingWriter::new(std::fs::File::create(output)?);
+    {
+        let mut read: Box<dyn std::io::Read> = match codec {
+            Some(_) => Box::new(zstd::stream::read::Encoder::new(&mut source, ::config().transfer.compression_level)?),
+            None => Box::new(&mut source),
+        };
+        match keys {
+            Some(k) => k.encrypt_stream(read, &mut write)?,
+            None => {
+                std::io::copy(&mut read, &mut write)?;
+            },
+        }
+    }
+    write.flush()?;
+    Ok(Transformed {
+        source_len: source.len,
+        source_sha1: source.digest(),
+        len: write.len,
+        sha1: write.digest(),
+    })
+}
@@ -0,0 +1,301 @@
+use std;
+use std::io::Read;
+use std::path::Path;
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use scoped_pool::Pool;
+use progress;
+use time;
+use formatting::size_formatter::format_bytes;
+use storage::storage::{self as storage_helper, EntryKind};
+use storage::crypto::Keys;
+use storage::compression;
+use storage::chunks::{self, ChunkingConfig, Pack};
+use storage::manifest::{Manifest, ManifestFile, ChunkLocation};
+use storage::metadata::FileMetadata;
+use remote::session::Session;
+use remote::files;
+use procedures::backup::BackupOptions;
+use procedures::error::ProcedureError;
+
+/// Backup for buckets in chunked mode, called by perform_backup
+///
+/// Files are read one at a time and split into chunks. Chunks that aren't stored yet are
+/// collected into packs, which are uploaded in the background while the next files are read. \
+/// Once every pack is uploaded, a new manifest describing all files is stored
+pub fn perform_chunked_backup(session: &Session, persistent_data: &mut storage_helper::PersistentData, options: &BackupOptions,
+                              keys: Option<&Keys>, config: &ChunkingConfig) -> Result<(), ProcedureError> {
+    println!("The bucket is in chunked mode, only changed chunks are uploaded");
+    let start_time = time::get_time().sec;
+
+    println!("Constructing file list");
+    let file_list = ::procedures::backup::backup_file_list(persistent_data.symlinks)?;
+    let list_size = storage_helper::get_total_size(&file_list);
+    if list_size == 0 {
+        return Err(ProcedureError::EmptyBackupList)
+    }
+    println!("Reading {} ({} bytes) across {} files", format_bytes(list_size), list_size, file_list.len());
+
+    println!("Retrieving the previous manifest...");
+    let previous = ::procedures::repository::latest_manifest(session, &persistent_data.active_bucket, keys)?.unwrap_or_default();
+
+    // Every chunk known to be stored, new ones refer to their pack by name until it's uploaded
+    let mut known = previous.chunks.clone();
+    let mut pack_names = previous.packs.clone();
+    // Names of uploaded packs mapped to their file ids
+    let uploaded: Arc<Mutex<BTreeMap<String, String>>> = Arc::new(Mutex::new(BTreeMap::new()));
+    let failed_packs = Arc::new(Mutex::new(0));
+    let in_flight = Arc::new(Mutex::new(0));
+    let mut entries: Vec<(String, ManifestFile)> = Vec::new();
+    let mut failed_files = 0;
+    let mut new_bytes = 0u64;
+
+    let bar = progress::Bar::new();
+    let bar = Arc::new(Mutex::new(bar));
+    let bucket_id = &persistent_data.active_bucket;
+    let bandwidth = persistent_data.bandwidth_limit/::config().transfer.upload_threads;
+
+    let pool = Pool::new(::config().transfer.upload_threads);
+    pool.scoped(|scope| {
+        // Uploads a full pack in the background, waiting first if enough are queued already
+        // so packs don't pile up in memory
+        let queue_pack = |pack: Pack| {
+            while *in_flight.lock().unwrap() >= ::config().transfer.upload_threads * 2 {
+                std::thread::sleep(Duration::from_millis(100));
+            }
+            *in_flight.lock().unwrap() += 1;
+            let uploaded = uploaded.clone();
+            let failed = failed_packs.clone();
+            let in_flight = in_flight.clone();
+            scope.execute(move || {
+                for attempts in 0..::config().transfer.retries {
+                    match upload_pack(session, bucket_id, &pack, bandwidth) {
+                        Ok(file_id) => {
+                            uploaded.lock().unwrap().insert(pack.name.clone(), file_id);
+                            break
+                        },
+                        Err(e) => {
+                            if attempts + 1 == ::config().transfer.retries {
+                                println!();
+                                println!("Failed to upload {} after {} attempts", pack.name, ::config().transfer.retries);
+                                println!("{}", e);
+                                *failed.lock().unwrap() += 1;
+                            }else{
+                                // Sleep for a bit before retrying
+                                std::thread::sleep(Duration::from_secs(::config().transfer.retry_delay));
+                            }
+                        },
+                    }
+                }
+                *in_flight.lock().unwrap() -= 1;
+            });
+        };
+
+        bar.lock().unwrap().set_job_title("Backup in progress");
+        let mut pack = Pack::new();
+        let mut bytes_done = 0u64;
+        for (i, file) in file_list.iter().enumerate() {
+            let (path, name) = (&file.path, file.name.clone());
+            // Stored links only need to know where they point, the manifest has room for that
+            if let EntryKind::Symlink(ref link) = file.kind {
+                let modified_time = match std::fs::symlink_metadata(path).and_then(|m| m.modified()).map(|m| m.duration_since(std::time::UNIX_EPOCH)) {
+                    Ok(Ok(v)) => v.as_secs()*1000,
+                    _ => 0u64,
+                };
+                entries.push((name, ManifestFile {
+                    size: 0,
+                    modified: modified_time,
+                    sha1: storage_helper::EMPTY_SHA1.to_owned(),
+                    chunks: Vec::new(),
+                    file_id: None,
+                    posix: None,
+                    xattrs: None,
+                    symlink: Some(link.to_string_lossy().into_owned()),
+                }));
+                continue;
+            }
+            // Deleted or made unreadable since the list was made
+            let (size, modified_time) = match ::procedures::backup::entry_stats(file) {
+                Ok(v) => v,
+                Err(e) => {
+                    println!();
+                    println!("Failed to read {}: {}", path.display(), e);
+                    failed_files += 1;
+                    continue;
+                },
+            };
+            // Empty directories only have their metadata
+            if file.kind == EntryKind::Directory {
+                let (posix, xattrs) = read_metadata(path, options.xattrs);
+                entries.push((name, ManifestFile {
+                    size: 0,
+                    modified: modified_time,
+                    sha1: storage_helper::EMPTY_SHA1.to_owned(),
+                    chunks: Vec::new(),
+                    file_id: None,
+                    posix,
+                    xattrs,
+                    symlink: None,
+                }));
+                continue;
+            }
+            bytes_done += size;
+
+            // Unchanged files keep the chunks they had, as long as those are still around
+            if let Some(prev) = previous.files.get(&name) {
+                let unchanged = size == prev.size && prev.chunks.iter().all(|c| known.contains_key(c)) && if options.checksum {
+                    match storage_helper::sha1_file(path) {
+                        Ok(hash) => hash == prev.sha1,
+                        Err(_e) => false,
+                    }
+                } else {
+                    modified_time <= prev.modified
+                };
+                if unchanged {
+                    // Permissions can change without touching the content
+                    let (posix, xattrs) = read_metadata(path, options.xattrs);
+                    entries.push((name, ManifestFile { posix, xattrs, ..prev.clone() }));
+                    continue;
+                }
+            }
+
+            let compress = compression::should_compress(path, size);
+            let mut chunk_ids = Vec::new();
+            let mut source = match std::fs::File::open(path) {
+                Ok(f) => storage_helper::HashingReader::new(f),
+                Err(e) => {
+                    println!();
+                    println!("Failed to read {}: {}", path.display(), e);
+                    failed_files += 1;
+                    continue;
+                },
+            };
+            let result = config.for_each_chunk(&mut source, |data| {
+                let id = chunks::chunk_id(keys, data);
+                if !known.contains_key(&id) {
+                    let (encoded, codec) = chunks::encode_blob(keys, data, compress).map_err(|e| e.to_string())?;
+                    known.insert(id.clone(), pack.add(&encoded, codec, keys.is_some()));
+                    new_bytes += data.len() as u64;
+                    if pack.data.len() >= config.pack_size {
+                        queue_pack(std::mem::replace(&mut pack, Pack::new()));
+                    }
+                }
+                chunk_ids.push(id);
+                Ok(())
+            });
+            match result {
+                Ok(_) => {
+                    let (posix, xattrs) = read_metadata(path, options.xattrs);
+                    entries.push((name, ManifestFile {
+                        size: source.len,
+                        modified: modified_time,
+                        sha1: source.digest(),
+                        chunks: chunk_ids,
+                        file_id: None,
+                        posix,
+                        xattrs,
+                        symlink: None,
+                    }))
+                },
+                Err(e) => {
+                    println!();
+                    println!("Failed to read {}: {}", path.display(), e);
+                    failed_files += 1;
+                    // Keep what the last backup had rather than losing the file entirely
+                    if let Some(prev) = previous.files.get(&name) {
+                        entries.push((name, prev.clone()));
+                    }
+                },
+            }
+            bar.lock().unwrap().set_job_title(&format!("Backup in progress ({}/{})", i + 1, file_list.len()));
+            bar.lock().unwrap().reach_percent((((bytes_done as f64) / (list_size as f64)) * 100.) as i32);
+        }
+        if !pack.data.is_empty() {
+            queue_pack(pack);
+        }
+        bar.lock().unwrap().set_job_title("Uploading the remaining packs");
+        bar.lock().unwrap().reach_percent(100);
+    });
+    println!();
+
+    let failed = *failed_packs.lock().unwrap();
+    if failed > 0 {
+        // Without those packs the new manifest would refer to chunks that don't exist
+        println!("Backup failed, {} packs could not be uploaded", failed);
+        return Err(ProcedureError::Incomplete(failed))
+    }
+
+    // Now that every pack has a file id, put together the manifest
+    for (name, file_id) in uploaded.lock().unwrap().iter() {
+        pack_names.insert(file_id.clone(), name.clone());
+    }
+    let uploaded = uploaded.lock().unwrap();
+    let mut manifest = Manifest::new(start_time);
+    for (name, file) in entries {
+        for id in &file.chunks {
+            let mut location: ChunkLocation = known[id].clone();
+            if let Some(file_id) = uploaded.get(&location.pack) {
+                location.pack = file_id.clone();
+            }
+            if let Some(pack_name) = pack_names.get(&location.pack) {
+                manifest.packs.insert(location.pack.clone(), pack_name.clone());
+            }
+            manifest.chunks.insert(id.clone(), location);
+        }
+        manifest.files.insert(name, file);
+    }
+    println!("Uploaded {} of new chunks in {} packs", format_bytes(new_bytes), uploaded.len());
+    ::procedures::repository::save_manifest(session, &persistent_data.active_bucket, keys, &manifest)?;
+
+    if failed_files > 0 {
+        println!("Backup completed, but {} files could not be read", failed_files);
+        return Err(ProcedureError::Incomplete(failed_files))
+    }
+    println!("Backup successfully completed");
+    persistent_data.last_backup = time::get_time().sec;
+    persistent_data.last_backup_start = start_time;
+    persistent_data.save_to_file(&::config().data_file()).map_err(ProcedureError::SaveFailed)?;
+    Ok(())
+}
+
+// The encoded metadata and extended attributes of a file, for its manifest entry
+// The manifest is encrypted as a whole, so these don't need to be
+fn read_metadata(path: &Path, xattrs: bool) -> (Option<String>, Option<String>) {
+    match FileMetadata::read(path, xattrs) {
+        Ok(metadata) => (Some(metadata.encode()), metadata.encode_xattrs()),
+        Err(_e) => (None, None),
+    }
+}
+
+// Uploads a pack, returning its file id
+fn upload_pack(session: &Session, bucket_id: &str, pack: &Pack, bandwidth: usize) -> Result<String, String> {
+    let sha1 = ::sha1::Sha1::from(&pack.data).digest().to_string();
+    let len = pack.data.len() as u64;
+    let upload_auth = files::get_upload_url(session, bucket_id).map_err(|e| format!("{:?}", e))?;
+    let read = std::io::Cursor::new(pack.data.clone());
+    let result = match bandwidth {
+        0 => files::upload_file(session, &upload_auth, &pack.name, read, len, &sha1, &BTreeMap::new()),
+        _ => files::upload_file(session, &upload_auth, &pack.name, files::ThrottledRead::new(read, bandwidth), len, &sha1, &BTreeMap::new()),
+    };
+    result.map(|v| v.file_id).map_err(|e| format!("{:?}", e))
+}
+
+/// Writes a file stored in chunked mode to 'write', downloading its chunks one at a time
+pub fn restore_chunks<W: std::io::Write>(session: &Session, keys: Option<&Keys>, locations: &[ChunkLocation], mut write: W) -> std::io::Result<()> {
+    for location in locations {
+        let mut resp = match files::download_file_range(session, &location.pack, location.offset, location.length) {
+            Ok(v) => v,
+            Err(e) => return Err(std::io::Error::other(format!("{:?}", e))),
+        };
+        let mut encoded = Vec::new();
+        resp.read_to_end(&mut encoded)?;
+        let keys = match (location.encrypted, keys) {
+            (true, None) => return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "The chunk is encrypted")),
+            (true, k) => k,
+            (false, _) => None,
+        };
+        write.write_all(&chunks::decode_blob(keys, location.codec.as_ref().map(|c| c.as_ref()), &encoded)?)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,523 @@
+use std;
+use std::path::PathBuf;
+use raze::engine::engine::Raze;
+use storage::storage::{PersistentData, SymlinkPolicy};
+use procedures::error::ProcedureError;
+use formatting::size_formatter::parse_bytes;
+use formatting::time_formatter::parse_timestamp;
+use remote::session::Session;
+use storage::plan::PlanFormat;
+use procedures::backup::BackupOptions;
+use procedures::restore::RestoreOptions;
+use procedures::purge::PurgeOptions;
+use procedures::prune::{PruneOptions, DeleteHiddenOptions};
+use procedures::unhide::UnhideOptions;
+use procedures::verify::VerifyOptions;
+use storage::retention::RetentionPolicy;
+
+// Exit codes used when running a single command from the command line
+pub const EXIT_SUCCESS: i32 = 0;
+// The command ran, but failed or only partially completed
+pub const EXIT_FAILURE: i32 = 1;
+// The command line itself was invalid
+pub const EXIT_USAGE: i32 = 2;
+
+/// A single command, either given as program arguments or typed into the prompt
+#[derive(Debug, PartialEq)]
+pub enum Command {
+    Backup(BackupOptions),
+    Purge(PurgeOptions),
+    Prune(PruneOptions),
+    DeleteHidden(DeleteHiddenOptions),
+    Unhide(UnhideOptions),
+    Verify(VerifyOptions),
+    Restore(RestoreOptions),
+    RebuildIndex,
+    InitEncryption,
+    InitChunking,
+    CleanupUploads,
+    Snapshots,
+    Profiles,
+    /// Name of the bucket to use. If None, the prompt will list buckets and ask for one
+    SetBucket(Option<String>),
+    /// Maximum bytes/sec. If None, the prompt will ask for it
+    Throttle(Option<usize>),
+    /// Percentage of stored files purge may hide. If None, the prompt will ask for it
+    PurgeThreshold(Option<u8>),
+    /// How to treat symbolic links. If None, the prompt will ask for it
+    Symlinks(Option<SymlinkPolicy>),
+    Help,
+    Usage,
+    Quit,
+}
+
+/// Parses a command and its arguments
+///
+/// When 'interactive' is false, commands must be given all their arguments up front,
+/// since there is nobody around to answer a prompt
+pub fn parse_command(args: &[String], interactive: bool) -> Result<Command, String> {
+    let (name, rest) = match args.split_first() {
+        Some(v) => v,
+        None => return Err("No command given".to_owned()),
+    };
+    let mut args = Arguments::new(rest);
+    if args.flag("-h") || args.flag("--help") {
+        return Ok(Command::Help);
+    }
+    let command = match name.to_lowercase().as_ref() {
+        "backup" => Command::Backup(BackupOptions {
+            checksum: args.flag("--checksum"),
+            xattrs: args.flag("--xattrs"),
+            dry_run: args.flag("--dry-run"),
+            format: match args.value("--format")? {
+                Some(f) => match PlanFormat::parse(&f) {
+                    Some(v) => v,
+                    None => return Err(format!("Invalid format '{}', expected text, json or csv", f)),
+                },
+                None => PlanFormat::Text,
+            },
+            output: args.value("--output")?.map(|o| o.into()),
+            if_due: args.flag("--if-due"),
+        }),
+        "purge" => Command::Purge(PurgeOptions {
+            force: args.flag("--force"),
+            dry_run: args.flag("--dry-run"),
+        }),
+        "prune" => {
+            let policy = RetentionPolicy {
+                keep_last: count(&mut args, "--keep-last")?,
+                keep_daily: count(&mut args, "--keep-daily")?,
+                keep_weekly: count(&mut args, "--keep-weekly")?,
+                keep_monthly: count(&mut args, "--keep-monthly")?,
+            };
+            if policy.is_empty() {
+                return Err("'prune' needs at least one of --keep-last, --keep-daily, --keep-weekly and --keep-monthly".to_owned());
+            }
+            Command::Prune(PruneOptions {
+                policy,
+                dry_run: args.flag("--dry-run"),
+            })
+        },
+        "delete-hidden" | "delete_hidden" => Command::DeleteHidden(DeleteHiddenOptions {
+            older_than_days: match args.value("--older-than")? {
+                Some(days) => match days.parse() {
+                    Ok(n) => n,
+                    Err(_e) => return Err(format!("'--older-than' expects a number of days, not '{}'", days)),
+                },
+                None => ::HIDDEN_GRACE_DAYS,
+            },
+            dry_run: args.flag("--dry-run"),
+        }),
+        "unhide" | "undelete" => Command::Unhide(UnhideOptions {
+            since: timestamp(&mut args, "--since")?,
+            until: timestamp(&mut args, "--until")?,
+            last_purge: args.flag("--last-purge"),
+            dry_run: args.flag("--dry-run"),
+        }),
+        "verify" => Command::Verify(VerifyOptions {
+            sample: count(&mut args, "--sample")?,
+            all: args.flag("--all"),
+        }),
+        "purge-threshold" | "purge_threshold" => match args.positional(name, !interactive)? {
+            Some(percent) => match parse_percent(&percent) {
+                Some(p) => Command::PurgeThreshold(Some(p)),
+                None => return Err(format!("Invalid percentage '{}', expected a number from 0 to 100", percent)),
+            },
+            None => Command::PurgeThreshold(None),
+        },
+        "restore" => Command::Restore(RestoreOptions {
+            target: args.value("--to")?.map(|t| t.into()),
+            path: args.value("--path")?,
+            overwrite: args.flag("--overwrite"),
+            snapshot: args.value("--snapshot")?,
+            no_owner: args.flag("--no-owner"),
+            no_xattrs: args.flag("--no-xattrs"),
+        }),
+        "set-bucket" | "set_bucket" => Command::SetBucket(args.positional(name, !interactive)?),
+        "throttle" => match args.positional(name, !interactive)? {
+            Some(rate) => match parse_bytes(&rate) {
+                Some(n) => Command::Throttle(Some(n as usize)),
+                None => return Err(format!("Invalid rate '{}', expected eg. 500K or 2M", rate)),
+            },
+            None => Command::Throttle(None),
+        },
+        "symlinks" => match args.positional(name, !interactive)? {
+            Some(policy) => match SymlinkPolicy::parse(&policy) {
+                Some(p) => Command::Symlinks(Some(p)),
+                None => return Err(format!("Invalid policy '{}', expected skip, follow or store", policy)),
+            },
+            None => Command::Symlinks(None),
+        },
+        "rebuild-index" | "rebuild_index" => Command::RebuildIndex,
+        "init-encryption" | "init_encryption" => Command::InitEncryption,
+        "init-chunking" | "init_chunking" => Command::InitChunking,
+        "cleanup-uploads" | "cleanup_uploads" => Command::CleanupUploads,
+        "snapshots" => Command::Snapshots,
+        "profiles" => Command::Profiles,
+        "help" | "-h" | "--help" => Command::Help,
+        "usage" => Command::Usage,
+        "quit" | "exit" | "goodbye" if interactive => Command::Quit,
+        _ => return Err(format!("Unknown command '{}'", name)),
+    };
+    args.finish(name)?;
+    Ok(command)
+}
+
+/// The arguments following a command name
+///
+/// Commands take out the flags and values they understand, anything left over is an error
+struct Arguments {
+    remaining: Vec<String>,
+}
+
+impl Arguments {
+    fn new(args: &[String]) -> Arguments {
+        Arguments {
+            remaining: args.to_vec(),
+        }
+    }
+
+    // Removes a flag like '--checksum', returning whether it was present
+    fn flag(&mut self, flag: &str) -> bool {
+        match self.remaining.iter().position(|a| a == flag) {
+            Some(i) => {
+                self.remaining.remove(i);
+                true
+            },
+            None => false,
+        }
+    }
+
+    // Removes an option like '--format json' or '--format=json', returning its value
+    // A value starting with '--' is more likely a forgotten value followed by the next option,
+    // so it's only taken in the '--format=--odd' form
+    fn value(&mut self, option: &str) -> Result<Option<String>, String> {
+        let prefix = format!("{}=", option);
+        if let Some(i) = self.remaining.iter().position(|a| a.starts_with(&prefix)) {
+            return Ok(Some(self.remaining.remove(i)[prefix.len()..].to_owned()));
+        }
+        match self.remaining.iter().position(|a| a == option) {
+            Some(i) if i + 1 < self.remaining.len() && !self.remaining[i + 1].starts_with("--") => {
+                self.remaining.remove(i);
+                Ok(Some(self.remaining.remove(i)))
+            },
+            Some(_) => Err(format!("'{}' requires a value", option)),
+            None => Ok(None),
+        }
+    }
+
+    // Removes the next positional argument, should be called after all flags are taken out
+    fn positional(&mut self, name: &str, required: bool) -> Result<Option<String>, String> {
+        match self.remaining.iter().position(|a| !a.starts_with("--")) {
+            Some(i) => Ok(Some(self.remaining.remove(i))),
+            None if required => Err(format!("'{}' is missing an argument", name)),
+            None => Ok(None),
+        }
+    }
+
+    // Fails if anything wasn't used by the command
+    fn finish(&self, name: &str) -> Result<(), String> {
+        match self.remaining.first() {
+            Some(a) if a.starts_with("--") => Err(format!("Unknown option '{}' for '{}'", a, name)),
+            Some(a) => Err(format!("Unexpected argument '{}' for '{}'", a, name)),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Options given before the command, which apply to the whole run
+#[derive(Debug, PartialEq, Default)]
+pub struct GlobalOptions {
+    /// Read the configuration from this file instead of CONFIG_FILE_NAME
+    pub config: Option<PathBuf>,
+    /// Keep every file in this directory instead of the usual configuration and state directories
+    pub config_dir: Option<PathBuf>,
+    /// Settings overriding the configuration file, eg. 'transfer.upload_threads=8'
+    pub overrides: Vec<String>,
+    /// Use this profile from the configuration file
+    pub profile: Option<String>,
+}
+
+/// Takes the options for the whole run off the front of the arguments, returning them and the command
+///
+/// '--config <file>' reads another configuration file, '--set key=value' overrides a setting in it
+/// and '--profile <name>' picks one of its profiles. '--config-dir <dir>' keeps every file in one directory
+pub fn parse_global_options(args: &[String]) -> Result<(GlobalOptions, Vec<String>), String> {
+    let mut options = GlobalOptions::default();
+    let mut rest = args;
+    loop {
+        let (option, value, used) = match rest.first() {
+            Some(a) if a == "--config" || a == "--config-dir" || a == "--set" || a == "--profile" => match rest.get(1) {
+                Some(v) => (a.as_str(), v.clone(), 2),
+                None => return Err(format!("'{}' requires a value", a)),
+            },
+            Some(a) if a.starts_with("--config=") || a.starts_with("--config-dir=") || a.starts_with("--set=") || a.starts_with("--profile=") => {
+                let i = a.find('=').unwrap();
+                (&a[..i], a[i+1..].to_owned(), 1)
+            },
+            _ => break,
+        };
+        match option {
+            "--config" => options.config = Some(value.into()),
+            "--config-dir" => options.config_dir = Some(value.into()),
+            "--profile" => options.profile = Some(value),
+            _ => options.overrides.push(value),
+        }
+        rest = &rest[used..];
+    }
+    Ok((options, rest.to_vec()))
+}
+
+/// Runs a parsed command that doesn't need any further input
+///
+/// Help, usage, quit and commands with missing arguments are handled by the prompt instead
+pub fn run_command(raze: &mut Raze, session: &Session, persistent_data: &mut PersistentData, command: Command) -> Result<(), ProcedureError> {
+    match command {
+        Command::Backup(options) => ::procedures::backup::perform_backup(session, persistent_data, options),
+        Command::Purge(options) => ::procedures::purge::purge_files(raze, session, persistent_data, options),
+        Command::Prune(options) => ::procedures::prune::prune_versions(session, persistent_data, options),
+        Command::DeleteHidden(options) => ::procedures::prune::delete_hidden(session, persistent_data, options),
+        Command::Unhide(options) => ::procedures::unhide::unhide_files(session, persistent_data, options),
+        Command::Verify(options) => ::procedures::verify::verify_backup(session, persistent_data, options),
+        Command::Restore(options) => ::procedures::restore::restore_files(session, persistent_data, options),
+        Command::RebuildIndex => ::procedures::index::rebuild_index(session, persistent_data),
+        Command::InitEncryption => ::procedures::repository::init_encryption(session, persistent_data),
+        Command::InitChunking => ::procedures::repository::init_chunking(session, persistent_data),
+        Command::CleanupUploads => ::procedures::large_file::cleanup_uploads(session, persistent_data),
+        Command::Snapshots => ::procedures::snapshots::list_snapshots(session, persistent_data),
+        Command::Profiles => ::procedures::profiles::list_profiles(),
+        Command::SetBucket(Some(name)) => ::procedures::settings::set_bucket(raze, persistent_data, &name),
+        Command::Throttle(Some(amount)) => ::procedures::settings::set_throttle(persistent_data, amount),
+        Command::PurgeThreshold(Some(percent)) => ::procedures::settings::set_purge_threshold(persistent_data, percent),
+        Command::Symlinks(Some(policy)) => ::procedures::settings::set_symlinks(persistent_data, policy),
+        Command::Help => {
+            print_help();
+            Ok(())
+        },
+        Command::Usage => {
+            print_usage();
+            Ok(())
+        },
+        _ => Ok(()),
+    }
+}
+
+// Takes out an option like '--keep-last 5', 0 if it isn't given
+fn count(args: &mut Arguments, option: &str) -> Result<usize, String> {
+    match args.value(option)? {
+        Some(v) => v.parse().map_err(|_e| format!("'{}' expects a number, not '{}'", option, v)),
+        None => Ok(0),
+    }
+}
+
+// Reads an option holding a date and time, see parse_timestamp
+fn timestamp(args: &mut Arguments, option: &str) -> Result<Option<i64>, String> {
+    match args.value(option)? {
+        Some(v) => match parse_timestamp(&v) {
+            Some(t) => Ok(Some(t)),
+            None => Err(format!("'{}' expects a date like \"2018-06-02 14:05\", not '{}'", option, v)),
+        },
+        None => Ok(None),
+    }
+}
+
+/// Parses a percentage like '25' or '25%'
+pub fn parse_percent(s: &str) -> Option<u8> {
+    match s.trim().trim_end_matches('%').parse::<u8>() {
+        Ok(n) if n <= 100 => Some(n),
+        _ => None,
+    }
+}
+
+/// Maps the result of a command to the exit code of the program
+pub fn exit_code(result: &Result<(), ProcedureError>) -> i32 {
+    match *result {
+        Ok(_) => EXIT_SUCCESS,
+        Err(_) => EXIT_FAILURE,
+    }
+}
+
+pub fn print_help() {
+    println!("Command List");
+    // Aligning like a pro coder
+    println!("'quit' \t\t\t- Exits this program");
+    println!("'backup' \t\t- Starts a new backup");
+    println!("    --checksum \t- Compare SHA-1 hashes instead of modification times to find changes");
+    println!("    --xattrs \t- Also record extended attributes");
+    println!("    --dry-run \t- Only show which files would be uploaded, without uploading");
+    println!("    --format <fmt> \t- Write the dry run plan as text, json or csv");
+    println!("    --output <file> \t- Write the dry run plan to a file instead");
+    println!("    --if-due \t- Only back up if the schedule of the profile says a backup is due");
+    println!("'throttle [rate]' \t- Sets the maximum bytes sent per second, eg. 500K. 0 disables it");
+    println!("'set_bucket [name]' \t- Lists available buckets and asks which one to use for backups");
+    println!("'symlinks [policy]' \t- Sets how backups treat symbolic links: skip, follow or store");
+    println!("'purge' \t\t- Delete files in bucket no longer found on the system");
+    println!("    --dry-run \t- Only list the files that would be hidden");
+    println!("    --force \t\t- Hide files even if folders are missing or more would go than 'purge_threshold' allows");
+    println!("'purge_threshold [pct]' - Sets the largest percentage of files purge hides without '--force'");
+    println!("'prune' \t\t- Deletes old file versions and snapshots outside the retention policy");
+    println!("    --keep-last <n> \t- Keep the newest n versions");
+    println!("    --keep-daily <n> \t- Keep the newest version of each of the last n days");
+    println!("    --keep-weekly <n> \t- Keep the newest version of each of the last n weeks");
+    println!("    --keep-monthly <n> - Keep the newest version of each of the last n months");
+    println!("    --dry-run \t- Only list what would be deleted");
+    println!("'delete_hidden' \t- Permanently deletes every version of files hidden by purge a while ago");
+    println!("    --older-than <days> - Only files hidden at least this long ago, {} days by default", ::HIDDEN_GRACE_DAYS);
+    println!("    --dry-run \t- Only list what would be deleted");
+    println!("'unhide' \t\t- Brings back files hidden by purge, also 'undelete'");
+    println!("    --last-purge \t- Only files hidden by the last purge");
+    println!("    --since <time> \t- Only files hidden at or after this time, eg. \"2018-06-02 14:05\"");
+    println!("    --until <time> \t- Only files hidden at or before this time");
+    println!("    --dry-run \t- Only list the files that would be brought back");
+    println!("'restore' \t\t- Downloads backed up files to where they came from");
+    println!("    --to <dir> \t- Restore below this directory instead");
+    println!("    --path <path> \t- Only restore files at or below this path");
+    println!("    --overwrite \t- Replace files that already exist");
+    println!("    --snapshot <id> \t- Restore the files as they were in this snapshot");
+    println!("    --no-owner \t- Don't change the owner of restored files, for restoring without root");
+    println!("    --no-xattrs \t- Don't put back extended attributes");
+    println!("'verify' \t\t- Checks every file in the backup list against the bucket by size and SHA-1");
+    println!("    --sample <n> \t- Also download n random files and check their content");
+    println!("    --all \t\t- Also download every file and check its content");
+    println!("'snapshots' \t\t- Lists the snapshots made by every backup");
+    println!("'profiles' \t\t- Lists the profiles in the configuration and when they last backed up");
+    println!("'rebuild_index' \t- Re-reads the bucket into the local index of uploaded files");
+    println!("'init_encryption' \t- Encrypts everything uploaded to the bucket from now on with a passphrase");
+    println!("'init_chunking' \t- Switches an empty bucket to only uploading the changed parts of files");
+    println!("'cleanup_uploads' \t- Cancels unfinished large file uploads that can't be resumed anymore");
+    println!("'usage' \t\t- Explains how to use this program");
+    println!();
+    println!("All commands can also be run directly, eg. 'raze-cli backup' or 'raze-cli set-bucket my-bucket'");
+    println!("Run without a command to get this prompt");
+    std::io::Write::flush(&mut std::io::stdout()).unwrap();
+}
+
+pub fn print_usage() {
+    println!("Raze User Guide");
+    println!("Before you can run a backup, you must use the 'set_bucket' command");
+    println!("This will be the bucket your files will be stored in");
+    println!("You may want to edit the bucket's settings via the web interface");
+    println!();
+    println!("Running the 'backup' command will start the backup process");
+    println!("Edit the '{}' file to specify files/folders for backup", ::config().backup_list_file().display());
+    println!("All sub-folders will be included when selecting a folder!");
+    println!("Name a folder with 'name = /path/to/folder' to store its files as name/... in the bucket");
+    println!("Patterns like 'target/' or '*.tmp' in '{}' exclude matching files and folders everywhere", ::config().exclude_list_file().display());
+    println!("Patterns indented below a folder in '{}' only apply to that folder, '!pattern' includes again", ::config().backup_list_file().display());
+    println!("Purge treats excluded files like deleted ones and hides them in the bucket");
+    println!("It lists what it will hide first, and refuses to run if a folder in '{}' is missing or empty,", ::config().backup_list_file().display());
+    println!("or if more than {}% of the files would be hidden. 'purge --force' overrides that", ::DEFAULT_PURGE_THRESHOLD);
+    println!("Symbolic links are followed by default, 'symlinks store' backs up the links themselves and 'symlinks skip' leaves them out");
+    println!("Files that compress well are compressed before uploading, restore decompresses them again");
+    println!();
+    println!("'backup --dry-run' lists new and modified files and what a backup would upload, without uploading anything");
+    println!("Add '--format json' or '--format csv' with '--output <file>' to export the plan");
+    println!();
+    println!("The upload speed can be limited by using the 'throttle' command");
+    println!();
+    println!("The backup process can be stopped at any time and will continue from where it left off");
+    println!("Large files are uploaded in parts, an interrupted upload only sends the missing parts next time");
+    println!("Uploads of files that changed or were removed since are left unfinished, 'cleanup_uploads' removes them");
+    println!("Uploaded files are tracked in '{}' instead of listing the bucket every time", ::config().index_file().display());
+    println!("If files were changed in the bucket by something else, run 'rebuild_index'");
+    println!("Files can be retrieved with the 'restore' command, by default to their original location");
+    println!("Existing files are left alone unless '--overwrite' is given");
+    println!("Permissions, owner and modification time are recorded by backup and put back by restore");
+    println!("Empty folders are stored as a '{}' file inside them and recreated by restore", ::storage::paths::DIRECTORY_MARKER);
+    println!("Use 'backup --xattrs' to record extended attributes as well, and 'restore --no-owner' when not running as root");
+    println!("'verify' checks that the backup is intact, 'verify --sample 20' also downloads a few files to be sure");
+    println!("Every backup saves a snapshot of all files, list them with 'snapshots'");
+    println!("'restore --snapshot <id>' restores the files as they were when that snapshot was made");
+    println!("Old versions and snapshots pile up until removed, 'prune --keep-daily 7 --keep-monthly 12' keeps only those");
+    println!("If purge hid files by mistake, 'unhide --last-purge' brings them back");
+    println!("Files hidden by purge still take up space, 'delete_hidden' deletes them for good once they've been hidden for a while");
+    println!();
+    println!("Run 'init_encryption' once to encrypt file contents and names before they are uploaded");
+    println!("The passphrase is asked for by every command that uploads or downloads, or read from RAZE_PASSPHRASE");
+    println!();
+    println!("Run 'init_chunking' on an empty bucket to split files into chunks and store every chunk once");
+    println!("Backups then only upload chunks that changed, which helps with large files like disk images");
+    println!();
+    println!("Settings like the number of upload threads are read from '{}', if it exists", ::config().dirs.config.join(::CONFIG_FILE_NAME).display());
+    println!("The program's state is kept in '{}', 'raze-cli --config-dir <dir>' keeps everything in one folder instead", ::config().dirs.state.display());
+    println!("Options before the command change them for one run, eg. 'raze-cli --set transfer.upload_threads=8 backup'");
+    println!("or 'raze-cli --config other.toml backup' to read another configuration file");
+    println!("Profiles back up their own folders to their own bucket, pick one with eg. 'raze-cli --profile photos backup'");
+    println!("List them with 'profiles'. 'backup --if-due' only runs when the schedule of the profile says so");
+    println!();
+    println!("To run from a script or cron job, pass the command as arguments, eg. 'raze-cli backup'");
+    println!("Credentials are read from B2_APPLICATION_KEY_ID and B2_APPLICATION_KEY, credentials.command or '{}'", ::config().credentials_file().display());
+    println!("The exit code is {} on success, {} if the command failed and {} for invalid arguments",
+             EXIT_SUCCESS, EXIT_FAILURE, EXIT_USAGE);
+    std::io::Write::flush(&mut std::io::stdout()).unwrap();
+}
+
+#[test]
+fn test_parse_command() {
+    let args = |s: &str| s.split_whitespace().map(|a| a.to_owned()).collect::<Vec<String>>();
+    assert_eq!(parse_global_options(&args("--config=a.toml --set transfer.retries=3 --profile photos backup --dry-run")), Ok((GlobalOptions {
+        config: Some(PathBuf::from("a.toml")),
+        config_dir: None,
+        overrides: vec!["transfer.retries=3".to_owned()],
+        profile: Some("photos".to_owned()),
+    }, args("backup --dry-run"))));
+    assert_eq!(parse_global_options(&args("--config-dir /srv/raze backup")).unwrap().0.config_dir, Some(PathBuf::from("/srv/raze")));
+    assert!(parse_global_options(&args("--set")).is_err());
+    assert_eq!(parse_command(&args("backup"), false), Ok(Command::Backup(BackupOptions::default())));
+    assert_eq!(parse_command(&args("backup --checksum --xattrs --if-due"), false), Ok(Command::Backup(BackupOptions {
+        checksum: true,
+        xattrs: true,
+        if_due: true,
+        ..Default::default()
+    })));
+    assert_eq!(parse_command(&args("backup --dry-run --format=csv --output plan.csv"), false), Ok(Command::Backup(BackupOptions {
+        dry_run: true,
+        format: PlanFormat::Csv,
+        output: Some("plan.csv".into()),
+        ..Default::default()
+    })));
+    assert!(parse_command(&args("backup --dry-run --format xml"), false).is_err());
+    assert_eq!(parse_command(&args("set-bucket photos"), false), Ok(Command::SetBucket(Some("photos".to_owned()))));
+    assert_eq!(parse_command(&args("throttle 2M"), false), Ok(Command::Throttle(Some(2_000_000))));
+    assert_eq!(parse_command(&args("set_bucket"), true), Ok(Command::SetBucket(None)));
+    assert_eq!(parse_command(&args("symlinks store"), false), Ok(Command::Symlinks(Some(SymlinkPolicy::Store))));
+    assert!(parse_command(&args("symlinks sometimes"), false).is_err());
+    assert_eq!(parse_command(&args("purge --dry-run"), false), Ok(Command::Purge(PurgeOptions { force: false, dry_run: true })));
+    assert_eq!(parse_command(&args("purge-threshold 40%"), false), Ok(Command::PurgeThreshold(Some(40))));
+    assert!(parse_command(&args("purge-threshold 140"), false).is_err());
+    assert_eq!(parse_command(&args("prune --keep-last 3 --keep-monthly=6 --dry-run"), false), Ok(Command::Prune(PruneOptions {
+        policy: RetentionPolicy { keep_last: 3, keep_monthly: 6, ..Default::default() },
+        dry_run: true,
+    })));
+    assert!(parse_command(&args("prune"), false).is_err());
+    assert_eq!(parse_command(&args("undelete --since 1528000000 --last-purge"), false), Ok(Command::Unhide(UnhideOptions {
+        since: Some(1528000000),
+        last_purge: true,
+        ..Default::default()
+    })));
+    assert!(parse_command(&args("unhide --until yesterday"), false).is_err());
+    assert_eq!(parse_command(&args("verify --sample=20"), false), Ok(Command::Verify(VerifyOptions {
+        sample: 20,
+        all: false,
+    })));
+    assert_eq!(parse_command(&args("delete-hidden --older-than 90"), false), Ok(Command::DeleteHidden(DeleteHiddenOptions {
+        older_than_days: 90,
+        dry_run: false,
+    })));
+    assert!(parse_command(&args("set-bucket"), false).is_err());
+    assert!(parse_command(&args("quit"), false).is_err());
+    assert!(parse_command(&args("backup --bogus"), false).is_err());
+    assert!(parse_command(&args("restore --to --overwrite"), false).is_err());
+    assert_eq!(parse_command(&args("restore --to=--odd"), false).map(|c| match c {
+        Command::Restore(options) => options.target,
+        _ => None,
+    }), Ok(Some("--odd".into())));
+    assert_eq!(parse_command(&args("restore --to /tmp/r --overwrite --snapshot 1528000000"), false), Ok(Command::Restore(RestoreOptions {
+        target: Some("/tmp/r".into()),
+        path: None,
+        overwrite: true,
+        snapshot: Some("1528000000".to_owned()),
+        no_owner: false,
+        no_xattrs: false,
+    })));
+}
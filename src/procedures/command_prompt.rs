@@ -2,49 +2,64 @@ use std;
 use std::io::{stdout, Write};
 use raze::engine::engine::Raze;
 use storage::storage::PersistentData;
+use remote::session::Session;
+use procedures::command_line::{self, Command};
 
-pub fn command_prompt(raze: &mut Raze, persistent_data: &mut PersistentData){
+pub fn command_prompt(raze: &mut Raze, session: &Session, persistent_data: &mut PersistentData){
     print!("Raze>");
     stdout().flush().unwrap();
     let input: String = read!("{}\n");
-    match input.to_lowercase().trim_right() {
-        "help" => {
-            println!("Command List");
-            // Aligning like a pro coder
-            println!("'quit' \t\t\t- Exits this program");
-            println!("'backup' \t\t- Starts a new backup");
-            println!("'throttle' \t\t- Allows you to set the maximum bytes sent per second");
-            println!("'set_bucket' \t- Lists available buckets and asks which one to use for backups");
-            println!("'purge' \t\t- Delete files in bucket no longer found on the system");
-            println!("'usage' \t\t- Explains how to use this program")
-        }
-        "quit" => std::process::exit(0),
-        "exit" => std::process::exit(0),
-        "goodbye" => std::process::exit(0),
-        "backup" => {
-            ::procedures::backup::perform_backup(raze, persistent_data);
+    let args: Vec<String> = input.split_whitespace().map(|a| a.to_owned()).collect();
+    if args.is_empty() {
+        return;
+    }
+    let command = match command_line::parse_command(&args, true) {
+        Ok(v) => v,
+        Err(e) => {
+            println!("{} - Type 'help' for help", e);
+            return;
         }
-        "throttle" => {
+    };
+    let result = match command {
+        Command::Quit => std::process::exit(command_line::EXIT_SUCCESS),
+        Command::Throttle(None) => {
             print!("Enter maximum bytes/sec sent during upload: ");
             stdout().flush().unwrap();
             let read: String = read!("{}\n");
-            let amount = match read.parse::<usize>() {
-                Ok(n) => match n {
-                    n if n > ::UPLOAD_THREADS || n == 0 => n,
-                    _ => {
-                        println!("Input too low -- defaulting to minimum");
-                        ::UPLOAD_THREADS
-                    }
-                },
-                Err(_e) => {
+            let amount = match ::formatting::size_formatter::parse_bytes(&read) {
+                Some(n) => n as usize,
+                None => {
                     println!("Invalid input -- defaulting to no throttling");
                     0
                 },
             };
-            persistent_data.bandwidth_limit = amount;
-            persistent_data.save_to_file(&std::path::Path::new(::PERSISTENT_DATA_FILE_NAME));
+            command_line::run_command(raze, session, persistent_data, Command::Throttle(Some(amount)))
         }
-        "set_bucket" => {
+        Command::PurgeThreshold(None) => {
+            print!("Enter the largest percentage of files purge may hide, currently {}%: ", persistent_data.purge_threshold);
+            stdout().flush().unwrap();
+            let read: String = read!("{}\n");
+            match command_line::parse_percent(&read) {
+                Some(percent) => command_line::run_command(raze, session, persistent_data, Command::PurgeThreshold(Some(percent))),
+                None => {
+                    println!("Invalid input -- leaving it at {}%", persistent_data.purge_threshold);
+                    Ok(())
+                },
+            }
+        },
+        Command::Symlinks(None) => {
+            print!("Enter how to treat symbolic links (skip, follow or store), currently {:?}: ", persistent_data.symlinks);
+            stdout().flush().unwrap();
+            let read: String = read!("{}\n");
+            match ::storage::storage::SymlinkPolicy::parse(read.trim()) {
+                Some(policy) => command_line::run_command(raze, session, persistent_data, Command::Symlinks(Some(policy))),
+                None => {
+                    println!("Invalid input -- leaving it at {:?}", persistent_data.symlinks);
+                    Ok(())
+                },
+            }
+        },
+        Command::SetBucket(None) => {
             println!("Available buckets");
             let buckets = raze.list_buckets().unwrap();
             for bucket in &buckets {
@@ -53,32 +68,11 @@ pub fn command_prompt(raze: &mut Raze, persistent_data: &mut PersistentData){
             print!("Enter bucket name: ");
             stdout().flush().unwrap();
             let name: String = read!("{}\n");
-            for bucket in &buckets {
-                if name == bucket.bucket_name {
-                    persistent_data.active_bucket = bucket.bucket_id.clone();
-                    persistent_data.save_to_file(&std::path::Path::new(::PERSISTENT_DATA_FILE_NAME));
-                }
-            }
-        },
-        "purge" => {
-            ::procedures::purge::purge_files(raze, persistent_data);
+            command_line::run_command(raze, session, persistent_data, Command::SetBucket(Some(name)))
         },
-        "usage" => {
-            println!("Raze User Guide");
-            println!("Before you can run a backup, you must use the 'set_bucket' command");
-            println!("This will be the bucket your files will be stored in");
-            println!("You may want to edit the bucket's settings via the web interface");
-            println!();
-            println!("Running the 'backup' command will start the backup process");
-            println!("Edit the '{}' file to specify files/folders for backup", ::BACKUP_LIST_FILE_NAME);
-            println!("All sub-folders will be included when selecting a folder!");
-            println!();
-            println!("The upload speed can be limited by using the 'throttle' command");
-            println!();
-            println!("The backup process can be stopped at any time and will continue from where it left off");
-            println!("Files can be retrieved via the B2 web interface");
-            stdout().flush().unwrap();
-        }
-        _ => println!("Unknown command - Type 'help' for help")
+        command => command_line::run_command(raze, session, persistent_data, command),
+    };
+    if let Err(e) = result {
+        println!("{}", e);
     }
-}
\ No newline at end of file
+}
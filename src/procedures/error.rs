@@ -0,0 +1,62 @@
+use std;
+use std::fmt;
+use raze;
+use storage::storage::StorageError;
+
+/// The reasons a procedure can fail
+///
+/// Procedures still print their own progress and diagnostics, this is only used to
+/// decide what to report at the end of a command and which exit code to use
+#[derive(Debug)]
+pub enum ProcedureError {
+    /// No bucket has been selected with 'set_bucket' yet
+    NoBucketSelected,
+    /// The backup list is missing or doesn't contain any files
+    EmptyBackupList,
+    /// The requested bucket name doesn't exist on the account
+    UnknownBucket(String),
+    /// A request to B2 failed and the procedure could not continue
+    B2Error(raze::B2Error),
+    /// The procedure finished, but this many files could not be processed
+    Incomplete(usize),
+    /// The repository couldn't be unlocked or set up
+    Encryption(String),
+    /// Something the program keeps in the bucket, like a manifest, is missing or damaged
+    Repository(String),
+    /// This many names in the bucket would be used by more than one file or backup list entry
+    NameCollision(usize),
+    /// A local file couldn't be read or written
+    LocalFile(String),
+    /// The procedure looked likely to do damage, so it didn't run. It can be forced
+    Refused(String),
+    /// Verify found this many files missing, different or damaged in the bucket
+    VerifyFailed(usize),
+    /// The persistent data couldn't be saved, see the data file setting
+    SaveFailed(StorageError),
+}
+
+impl ProcedureError {
+    /// A local state file other than the persistent data couldn't be saved
+    pub fn save_failed(file: &std::path::Path, e: StorageError) -> ProcedureError {
+        ProcedureError::LocalFile(format!("Can't save {}: {}", file.display(), e))
+    }
+}
+
+impl fmt::Display for ProcedureError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ProcedureError::NoBucketSelected => write!(f, "No bucket selected, set one with 'set_bucket' first"),
+            ProcedureError::EmptyBackupList => write!(f, "The {} doesn't exist or contains no entries", ::config().backup_list_file().display()),
+            ProcedureError::UnknownBucket(ref name) => write!(f, "No bucket named '{}' was found", name),
+            ProcedureError::B2Error(ref e) => write!(f, "B2 request failed: {:?}", e),
+            ProcedureError::Incomplete(n) => write!(f, "{} file(s) could not be processed", n),
+            ProcedureError::Encryption(ref reason) => write!(f, "{}", reason),
+            ProcedureError::Repository(ref reason) => write!(f, "{}", reason),
+            ProcedureError::NameCollision(n) => write!(f, "{} name(s) in the bucket would be shared, nothing was uploaded", n),
+            ProcedureError::LocalFile(ref reason) => write!(f, "{}", reason),
+            ProcedureError::Refused(ref reason) => write!(f, "{}", reason),
+            ProcedureError::VerifyFailed(n) => write!(f, "Verify found {} problem(s), see the report above", n),
+            ProcedureError::SaveFailed(ref e) => write!(f, "Can't save {}: {}", ::config().data_file().display(), e),
+        }
+    }
+}
@@ -0,0 +1,49 @@
+use storage::storage as storage_helper;
+use storage::index::UploadIndex;
+use remote::session::Session;
+use remote::files;
+use procedures::error::ProcedureError;
+
+/// Loads the upload index for the given bucket
+///
+/// If there is no index yet, or it belongs to another bucket, a new one is built by listing the bucket
+pub fn load_index(session: &Session, bucket_id: &str) -> Result<UploadIndex, ProcedureError> {
+    match UploadIndex::from_file(&::config().index_file()) {
+        Ok(index) => {
+            if index.bucket_id == bucket_id {
+                return Ok(index);
+            }
+            println!("The local index belongs to another bucket, rebuilding it");
+        },
+        Err(_e) => println!("No local index found, building one from the bucket"),
+    }
+    build_index(session, bucket_id)
+}
+
+/// The 'rebuild-index' command, replaces the local index with the current contents of the bucket
+pub fn rebuild_index(session: &Session, persistent_data: &mut storage_helper::PersistentData) -> Result<(), ProcedureError> {
+    // Verify that a bucket is selected
+    if persistent_data.active_bucket.is_empty() {
+        return Err(ProcedureError::NoBucketSelected)
+    }
+    if ::procedures::repository::read_chunking_config(session, &persistent_data.active_bucket)?.is_some() {
+        println!("The bucket is in chunked mode, which keeps track of files with manifests instead of the index");
+        return Ok(())
+    }
+    build_index(session, &persistent_data.active_bucket).map(|_| ())
+}
+
+// Lists the bucket and saves the result as the new index
+fn build_index(session: &Session, bucket_id: &str) -> Result<UploadIndex, ProcedureError> {
+    println!("Listing all files in the bucket, this may take a bit...");
+    let remote_file_list = match files::list_all_file_names(session, bucket_id, "", 1000) {
+        Ok(v) => v,
+        Err(e) => return Err(ProcedureError::B2Error(e)),
+    };
+    let index = UploadIndex::from_remote_files(bucket_id, remote_file_list);
+    if let Err(e) = index.save_to_file(&::config().index_file()) {
+        return Err(ProcedureError::save_failed(&::config().index_file(), e))
+    }
+    println!("Index now contains {} files", index.files.len());
+    Ok(index)
+}
@@ -0,0 +1,295 @@
+use std;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::Path;
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+use std::time::Duration;
+use scoped_pool::Pool;
+use storage::storage::{self as storage_helper, StorageError};
+use storage::uploads::{PendingUploads, PendingUpload};
+use remote::session::Session;
+use remote::files::{self, RemoteFile};
+use remote::large_files;
+use procedures::error::ProcedureError;
+
+/// Where an upload goes, shared by every upload in a backup
+pub struct UploadTarget<'a> {
+    pub session: &'a Session,
+    pub bucket_id: &'a str,
+    /// Large file uploads that haven't finished yet, saved whenever a part is done
+    pub pending: &'a Mutex<PendingUploads>,
+    /// Bytes per second for a single file, 0 for no limit
+    pub bandwidth: usize,
+    /// Held while a large file is uploading, see upload_large_file
+    pub large_file_turn: &'a Mutex<()>,
+}
+
+/// Loads the unfinished large file uploads for the given bucket
+///
+/// Uploads recorded for another bucket are forgotten, 'cleanup-uploads' takes care of those
+pub fn load_pending(bucket_id: &str) -> PendingUploads {
+    match PendingUploads::from_file(&::config().pending_uploads_file()) {
+        Ok(pending) if pending.bucket_id == bucket_id => pending,
+        _ => PendingUploads {
+            bucket_id: bucket_id.to_owned(),
+            uploads: BTreeMap::new(),
+        },
+    }
+}
+
+/// Returns the unfinished upload for 'remote_name' if it can be continued where it left off
+pub fn resumable(target: &UploadTarget, remote_name: &str) -> Option<PendingUpload> {
+    match target.pending.lock().unwrap().uploads.get(remote_name) {
+        Some(upload) if upload.is_resumable() => Some(upload.clone()),
+        _ => None,
+    }
+}
+
+/// Uploads 'content' as a large file in parts of the recommended size, several at a time
+///
+/// 'source' is the local file being backed up, which is also 'content' unless it was encrypted or compressed. \
+/// Every finished part is recorded, if the upload is interrupted the next attempt only uploads the missing parts.
+/// That only works while the source is unchanged, otherwise the old upload is cancelled and a new one started
+///
+/// Once finished, 'content' is removed if it isn't the source
+///
+/// Large files take turns, each sending transfer.upload_threads parts at once. The other upload threads keep
+/// going in the meantime, so there are at most twice as many uploads running as there are threads
+pub fn upload_large_file(target: &UploadTarget, remote_name: &str, source: &Path, content: &Path, info: &BTreeMap<String, String>) -> Result<RemoteFile, String> {
+    let _turn = target.large_file_turn.lock().unwrap();
+    let len = std::fs::metadata(content).map_err(|e| e.to_string())?.len();
+    let previous = target.pending.lock().unwrap().uploads.get(remote_name).cloned();
+    let upload = match previous {
+        Some(ref p) if p.is_resumable() && p.source == source.to_string_lossy() && p.content == content.to_string_lossy() => {
+            // B2 knows best which parts made it, our record may be a part behind
+            match large_files::list_parts(target.session, &p.file_id) {
+                Ok(parts) => {
+                    let mut upload = p.clone();
+                    upload.parts = parts.into_iter()
+                        .filter(|part| part.content_length == part_length(upload.part_size, len, part.part_number))
+                        .map(|part| (part.part_number, part.content_sha1))
+                        .collect();
+                    println!();
+                    println!("Resuming the upload of {}, {} parts were done already", remote_name, upload.parts.len());
+                    upload
+                },
+                // The upload is gone, most likely cancelled from elsewhere
+                Err(_e) => start_upload(target, remote_name, source, content, len, info)?,
+            }
+        },
+        Some(p) => {
+            // The file changed since, so the parts uploaded so far are no use
+            let _ = large_files::cancel_large_file(target.session, &p.file_id);
+            remove_content(&p);
+            start_upload(target, remote_name, source, content, len, info)?
+        },
+        None => start_upload(target, remote_name, source, content, len, info)?,
+    };
+    save_pending(target, remote_name, Some(&upload));
+
+    let part_count = len.div_ceil(upload.part_size) as u32;
+    let failed = Mutex::new(0);
+    let pool = Pool::new(::config().transfer.upload_threads);
+    pool.scoped(|scope| {
+        for number in (1..part_count + 1).filter(|n| !upload.parts.contains_key(n)) {
+            let failed = &failed;
+            let upload = &upload;
+            scope.execute(move || {
+                for attempts in 0..::config().transfer.retries {
+                    match upload_part(target, upload, content, number, len) {
+                        Ok(sha1) => {
+                            let mut pending = target.pending.lock().unwrap();
+                            if let Some(u) = pending.uploads.get_mut(remote_name) {
+                                u.parts.insert(number, sha1);
+                            }
+                            report_save(pending.save_to_file(&::config().pending_uploads_file()));
+                            break
+                        },
+                        Err(e) => {
+                            if attempts + 1 == ::config().transfer.retries {
+                                println!();
+                                println!("Failed to upload part {} of {} after {} attempts", number, remote_name, ::config().transfer.retries);
+                                println!("{}", e);
+                                *failed.lock().unwrap() += 1;
+                            }else{
+                                // Sleep for a bit before retrying
+                                std::thread::sleep(Duration::from_secs(::config().transfer.retry_delay));
+                            }
+                        },
+                    }
+                }
+            });
+        }
+    });
+    let failed = *failed.lock().unwrap();
+    if failed > 0 {
+        return Err(format!("{} of {} parts failed, the upload continues from there next time", failed, part_count))
+    }
+
+    let parts = match target.pending.lock().unwrap().uploads.get(remote_name) {
+        Some(u) => u.parts.clone(),
+        None => return Err("The upload was removed while it was running".to_owned()),
+    };
+    let sha1s: Vec<String> = parts.values().cloned().collect();
+    let finished = large_files::finish_large_file(target.session, &upload.file_id, &sha1s).map_err(|e| format!("{:?}", e))?;
+    save_pending(target, remote_name, None);
+    remove_content(&upload);
+    Ok(finished)
+}
+
+/// The 'cleanup-uploads' command, cancels unfinished large files that can't be resumed anymore
+///
+/// Those are uploads this computer doesn't know about, and uploads of files that changed or were removed
+/// since they started. Their parts are deleted, along with any temporary files kept for them
+pub fn cleanup_uploads(session: &Session, persistent_data: &mut storage_helper::PersistentData) -> Result<(), ProcedureError> {
+    // Verify that a bucket is selected
+    if persistent_data.active_bucket.is_empty() {
+        return Err(ProcedureError::NoBucketSelected)
+    }
+    let mut pending = load_pending(&persistent_data.active_bucket);
+    println!("Looking for unfinished large files...");
+    let unfinished = match large_files::list_unfinished_large_files(session, &persistent_data.active_bucket) {
+        Ok(v) => v,
+        Err(e) => return Err(ProcedureError::B2Error(e)),
+    };
+
+    let mut cancelled = 0;
+    let mut failed = 0;
+    for file in &unfinished {
+        let keep = pending.uploads.get(&file.file_name)
+            .map(|u| u.file_id == file.file_id && u.is_resumable())
+            .unwrap_or(false);
+        if keep {
+            continue;
+        }
+        match large_files::cancel_large_file(session, &file.file_id) {
+            Ok(_) => cancelled += 1,
+            Err(e) => {
+                println!("Failed to cancel {}: {:?}", file.file_name, e);
+                failed += 1;
+            },
+        }
+    }
+
+    // Forget about uploads that are cancelled or can't continue
+    let kept: Vec<&str> = unfinished.iter().map(|f| f.file_id.as_ref()).collect();
+    let (resumable, stale): (BTreeMap<String, PendingUpload>, BTreeMap<String, PendingUpload>) = pending.uploads.into_iter()
+        .partition(|(_, u)| u.is_resumable() && kept.contains(&u.file_id.as_ref()));
+    for upload in stale.values() {
+        remove_content(upload);
+    }
+    pending.uploads = resumable;
+    if let Err(e) = pending.save_to_file(&::config().pending_uploads_file()) {
+        return Err(ProcedureError::save_failed(&::config().pending_uploads_file(), e))
+    }
+
+    println!("Cancelled {} of {} unfinished large files, {} can still be resumed by the next backup",
+             cancelled, unfinished.len(), pending.uploads.len());
+    if failed > 0 {
+        return Err(ProcedureError::Incomplete(failed))
+    }
+    Ok(())
+}
+
+// Starts a new large file and records it as pending
+fn start_upload(target: &UploadTarget, remote_name: &str, source: &Path, content: &Path, len: u64, info: &BTreeMap<String, String>) -> Result<PendingUpload, String> {
+    let metadata = std::fs::metadata(source).map_err(|e| e.to_string())?;
+    let modified = match metadata.modified().map(|m| m.duration_since(std::time::UNIX_EPOCH)) {
+        Ok(Ok(v)) => v.as_secs()*1000, // Convert seconds to milliseconds
+        _ => 0u64,
+    };
+    // B2 has no hash of the whole file for large files, so restore and verify need it recorded
+    // Files that were transformed first already have the hash of the original
+    let mut info = info.clone();
+    if !info.contains_key(files::INFO_SHA1) {
+        let sha1 = storage_helper::sha1_file(content).map_err(|e| e.to_string())?;
+        info.insert(files::INFO_LARGE_FILE_SHA1.to_owned(), sha1.clone());
+        info.insert(files::INFO_SHA1.to_owned(), sha1);
+    }
+    let started = large_files::start_large_file(target.session, target.bucket_id, remote_name, &info).map_err(|e| format!("{:?}", e))?;
+    Ok(PendingUpload {
+        file_id: started.file_id,
+        source: source.to_string_lossy().into_owned(),
+        source_size: metadata.len(),
+        source_modified: modified,
+        content: content.to_string_lossy().into_owned(),
+        part_size: part_size(target.session, len),
+        info,
+        parts: BTreeMap::new(),
+    })
+}
+
+// Picks the part size for a file of 'len' bytes
+// Usually the recommended size, but there must be at least 2 parts and at most 10000
+fn part_size(session: &Session, len: u64) -> u64 {
+    let part_size = std::cmp::min(session.auth().recommended_part_size as u64, len.div_ceil(2));
+    let part_size = std::cmp::max(part_size, len.div_ceil(10000));
+    std::cmp::max(part_size, session.auth().absolute_minimum_part_size as u64)
+}
+
+// The length of part 'number' when splitting 'len' bytes into parts of 'part_size'
+fn part_length(part_size: u64, len: u64, number: u32) -> u64 {
+    let offset = (number as u64 - 1) * part_size;
+    std::cmp::min(part_size, len.saturating_sub(offset))
+}
+
+// Uploads a single part, returning its SHA-1
+// The part is read twice, once for the hash and once for the upload, so it never has to be in memory
+fn upload_part(target: &UploadTarget, upload: &PendingUpload, content: &Path, number: u32, len: u64) -> Result<String, String> {
+    let offset = (number as u64 - 1) * upload.part_size;
+    let part_len = part_length(upload.part_size, len, number);
+    let open_part = || -> std::io::Result<std::io::Take<std::fs::File>> {
+        let mut read = std::fs::File::open(content)?;
+        read.seek(SeekFrom::Start(offset))?;
+        Ok(read.take(part_len))
+    };
+    let mut hashing = storage_helper::HashingReader::new(open_part().map_err(|e| e.to_string())?);
+    std::io::copy(&mut hashing, &mut std::io::sink()).map_err(|e| e.to_string())?;
+    let sha1 = hashing.digest();
+
+    let part_auth = large_files::get_upload_part_url(target.session, &upload.file_id).map_err(|e| format!("{:?}", e))?;
+    let read = open_part().map_err(|e| e.to_string())?;
+    // The parts of a file are uploaded side by side, so they share the bandwidth of the one thread the file has
+    // At least a byte a second each, a share of 0 wouldn't limit anything
+    let result = match target.bandwidth {
+        0 => large_files::upload_part(target.session, &part_auth, number, read, part_len, &sha1),
+        _ => {
+            let share = std::cmp::max(target.bandwidth/::config().transfer.upload_threads, 1);
+            large_files::upload_part(target.session, &part_auth, number, files::ThrottledRead::new(read, share), part_len, &sha1)
+        },
+    };
+    result.map(|_| sha1).map_err(|e| format!("{:?}", e))
+}
+
+// Updates or removes the pending upload for 'remote_name' and saves the list
+fn save_pending(target: &UploadTarget, remote_name: &str, upload: Option<&PendingUpload>) {
+    let mut pending = target.pending.lock().unwrap();
+    match upload {
+        Some(u) => pending.uploads.insert(remote_name.to_owned(), u.clone()),
+        None => pending.uploads.remove(remote_name),
+    };
+    report_save(pending.save_to_file(&::config().pending_uploads_file()));
+}
+
+// Reports a failure to save the pending uploads without stopping the upload
+// B2 keeps track of the parts as well, so a resumed upload only has to redo the ones it can't match
+fn report_save(result: Result<(), StorageError>) {
+    if let Err(e) = result {
+        println!();
+        println!("Can't save {}: {}", ::config().pending_uploads_file().display(), e);
+    }
+}
+
+// Removes the uploaded content if it's a temporary file
+fn remove_content(upload: &PendingUpload) {
+    if upload.content != upload.source {
+        let _ = std::fs::remove_file(&upload.content);
+    }
+}
+
+#[test]
+fn test_part_length() {
+    assert_eq!(part_length(100, 250, 1), 100);
+    assert_eq!(part_length(100, 250, 3), 50);
+    assert_eq!(part_length(100, 200, 2), 100);
+}
@@ -4,4 +4,30 @@ pub mod command_prompt;
 
 pub mod backup;
 
-pub mod purge;
\ No newline at end of file
+pub mod purge;
+
+pub mod restore;
+
+pub mod command_line;
+
+pub mod error;
+
+pub mod settings;
+
+pub mod index;
+
+pub mod repository;
+
+pub mod chunked;
+
+pub mod large_file;
+
+pub mod snapshots;
+
+pub mod prune;
+
+pub mod unhide;
+
+pub mod verify;
+
+pub mod profiles;
\ No newline at end of file
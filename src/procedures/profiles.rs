@@ -0,0 +1,44 @@
+use time;
+use formatting::time_formatter::format_timestamp;
+use storage::storage::PersistentData;
+use storage::config;
+use procedures::error::ProcedureError;
+
+/// The 'profiles' command, lists the profiles in the configuration and when each of them last backed up
+///
+/// The profile in use is marked with a '*'
+pub fn list_profiles() -> Result<(), ProcedureError> {
+    let config = ::config();
+    if config.profiles.is_empty() {
+        println!("There are no profiles, add them to '{}' as [profiles.<name>]", config.dirs.config.join(::CONFIG_FILE_NAME).display());
+        return Ok(())
+    }
+    let now = time::get_time().sec;
+    for name in config.profiles.keys() {
+        let settings = config.profile_settings(name).unwrap();
+        // Every profile keeps its own state, which records when it last backed up
+        let last_backup = PersistentData::from_file(&config.profile_data_file(Some(name)))
+            .map(|d| d.last_backup)
+            .unwrap_or(0);
+        let current = if config.profile.as_ref() == Some(name) { "*" } else { " " };
+        let bucket = match settings.bucket {
+            Some(ref b) => format!("bucket '{}'", b),
+            None => "bucket set with 'set_bucket'".to_owned(),
+        };
+        let roots = match settings.roots {
+            Some(ref r) => format!("{} roots", r.len()),
+            None => format!("roots from '{}'", config.backup_list_file().display()),
+        };
+        let last = match last_backup {
+            0 => "never backed up".to_owned(),
+            t => format!("last backup {}", format_timestamp(t)),
+        };
+        let schedule = match settings.schedule {
+            Some(ref s) if config::backup_due(&settings.schedule, last_backup, now) => format!(", {}, due", s),
+            Some(ref s) => format!(", {}", s),
+            None => String::new(),
+        };
+        println!("{} {}: {}, {}, {}{}", current, name, bucket, roots, last, schedule);
+    }
+    Ok(())
+}
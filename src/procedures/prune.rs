@@ -0,0 +1,222 @@
+use std;
+use std::collections::BTreeSet;
+use std::sync::Mutex;
+use std::time::Duration;
+use scoped_pool::Pool;
+use time;
+use formatting::size_formatter::format_bytes;
+use formatting::time_formatter::format_timestamp;
+use storage::storage as storage_helper;
+use storage::crypto::Keys;
+use storage::retention::RetentionPolicy;
+use storage::chunks::PACK_PREFIX;
+use remote::session::Session;
+use remote::files::{self, RemoteFile};
+use procedures::error::ProcedureError;
+
+// How many of the versions to delete are listed before deleting them, a dry run lists all of them
+const SHOWN_VERSIONS: usize = 20;
+
+/// Options for the 'prune' command
+#[derive(Debug, PartialEq, Default)]
+pub struct PruneOptions {
+    pub policy: RetentionPolicy,
+    /// Only list what would be deleted
+    pub dry_run: bool,
+}
+
+/// The 'prune' command, deletes old file versions and snapshots that fall outside the retention policy
+///
+/// Snapshots are pruned first, then the versions of every file. Versions used by a remaining snapshot are kept,
+/// so every snapshot stays restorable. The newest version of a file is never deleted, even if it's hidden
+///
+/// In chunked mode the files are in packs, so packs no remaining snapshot uses are deleted instead. \
+/// Only packs older than the newest snapshot are, the ones after it may belong to a backup that is still running
+pub fn prune_versions(session: &Session, persistent_data: &mut storage_helper::PersistentData, options: PruneOptions) -> Result<(), ProcedureError> {
+    // Verify that a bucket is selected
+    if persistent_data.active_bucket.is_empty() {
+        return Err(ProcedureError::NoBucketSelected)
+    }
+    let keys = ::procedures::repository::open_repository(session, &persistent_data.active_bucket)?;
+
+    println!("Retrieving snapshots...");
+    let manifests = ::procedures::repository::list_manifests(session, &persistent_data.active_bucket)?;
+    // Listed oldest first, the policy wants the newest first
+    let timestamps: Vec<u64> = manifests.iter().rev().map(|m| m.upload_timestamp).collect();
+    let mut kept = options.policy.keep(&timestamps);
+    if let Some(k) = kept.first_mut() {
+        *k = true;
+    }
+    let mut to_delete: Vec<RemoteFile> = Vec::new();
+    let mut protected = BTreeSet::new();
+    let mut used_packs = BTreeSet::new();
+    for (manifest, keep) in manifests.iter().rev().zip(kept) {
+        if keep {
+            let snapshot = ::procedures::repository::read_manifest(session, manifest, keys.as_ref())?;
+            used_packs.extend(snapshot.chunks.into_values().map(|c| c.pack));
+            protected.extend(snapshot.files.into_values().filter_map(|f| f.file_id));
+        } else {
+            to_delete.push(manifest.clone());
+        }
+    }
+    let snapshot_count = to_delete.len();
+
+    println!("Retrieving every file version, this may take a bit...");
+    let versions = match files::list_all_file_versions(session, &persistent_data.active_bucket, "", 1000) {
+        Ok(v) => v,
+        Err(e) => return Err(ProcedureError::B2Error(e)),
+    };
+    // Versions of the same name are listed together, newest first
+    // Hide markers are left alone, removing one would bring back a file purge hid
+    let uploads: Vec<&RemoteFile> = versions.iter()
+        .filter(|v| v.action == "upload" && !v.file_name.starts_with(::REPOSITORY_PREFIX))
+        .collect();
+    for group in uploads.chunk_by(|a, b| a.file_name == b.file_name) {
+        let timestamps: Vec<u64> = group.iter().map(|v| v.upload_timestamp).collect();
+        let kept = options.policy.keep(&timestamps);
+        // The newest version is what restore and 'unhide' go back to, so it always stays
+        for (version, keep) in group.iter().zip(kept).skip(1) {
+            if !keep && !protected.contains(&version.file_id) {
+                to_delete.push((*version).clone());
+            }
+        }
+    }
+    let version_count = to_delete.len() - snapshot_count;
+
+    // Packs only hold chunks, they're in use as long as a snapshot that is kept refers to them
+    if let Some(newest) = manifests.last() {
+        to_delete.extend(versions.iter()
+            .filter(|v| v.action == "upload" && v.file_name.starts_with(PACK_PREFIX))
+            .filter(|v| v.upload_timestamp < newest.upload_timestamp && !used_packs.contains(&v.file_id))
+            .cloned());
+    }
+    let pack_count = to_delete.len() - snapshot_count - version_count;
+
+    let size = to_delete.iter().fold(0, |acc, v| acc + v.content_length);
+    list_versions(keys.as_ref(), &to_delete, options.dry_run);
+    println!("{} snapshots, {} file versions and {} unused packs fall outside the policy, {} in total",
+             snapshot_count, version_count, pack_count, format_bytes(size));
+    if options.dry_run {
+        println!("Dry run, nothing was deleted");
+        return Ok(())
+    }
+    if to_delete.is_empty() {
+        return Ok(())
+    }
+
+    println!("Deleting...");
+    let failed = delete_versions(session, &to_delete);
+    if failed > 0 {
+        println!("Prune completed, but {} versions could not be deleted", failed);
+        return Err(ProcedureError::Incomplete(failed))
+    }
+    println!("Prune successfully completed, reclaimed {}", format_bytes(size));
+    Ok(())
+}
+
+/// Options for the 'delete-hidden' command
+#[derive(Debug, PartialEq, Default)]
+pub struct DeleteHiddenOptions {
+    /// Only files hidden at least this many days ago are deleted
+    pub older_than_days: u64,
+    /// Only list what would be deleted
+    pub dry_run: bool,
+}
+
+/// The 'delete-hidden' command, permanently deletes files that were hidden more than a number of days ago
+///
+/// Every version of such a file is deleted, along with the hide marker itself. This can't be undone,
+/// and snapshots made before the file was hidden won't be able to restore it anymore
+pub fn delete_hidden(session: &Session, persistent_data: &mut storage_helper::PersistentData, options: DeleteHiddenOptions) -> Result<(), ProcedureError> {
+    // Verify that a bucket is selected
+    if persistent_data.active_bucket.is_empty() {
+        return Err(ProcedureError::NoBucketSelected)
+    }
+    let keys = ::procedures::repository::open_repository(session, &persistent_data.active_bucket)?;
+    let cutoff = (time::get_time().sec as u64).saturating_sub(options.older_than_days*24*60*60) * 1000;
+
+    println!("Retrieving every file version, this may take a bit...");
+    let versions = match files::list_all_file_versions(session, &persistent_data.active_bucket, "", 1000) {
+        Ok(v) => v,
+        Err(e) => return Err(ProcedureError::B2Error(e)),
+    };
+    // A file is hidden when its newest version is a hide marker, versions of the same name are listed together
+    let mut to_delete: Vec<RemoteFile> = Vec::new();
+    let mut file_count = 0;
+    for group in versions.chunk_by(|a, b| a.file_name == b.file_name) {
+        if group[0].action == "hide" && group[0].upload_timestamp < cutoff && !group[0].file_name.starts_with(::REPOSITORY_PREFIX) {
+            file_count += 1;
+            to_delete.extend(group.iter().cloned());
+        }
+    }
+
+    let size = to_delete.iter().fold(0, |acc, v| acc + v.content_length);
+    list_versions(keys.as_ref(), &to_delete, options.dry_run);
+    println!("{} files were hidden more than {} days ago, with {} versions taking up {}",
+             file_count, options.older_than_days, to_delete.len(), format_bytes(size));
+    if options.dry_run {
+        println!("Dry run, nothing was deleted");
+        return Ok(())
+    }
+    if to_delete.is_empty() {
+        return Ok(())
+    }
+
+    println!("Deleting...");
+    let failed = delete_versions(session, &to_delete);
+    if failed > 0 {
+        println!("Deleted hidden files, but {} versions could not be deleted", failed);
+        return Err(ProcedureError::Incomplete(failed))
+    }
+    println!("Deleted {} hidden files for good, reclaimed {}", file_count, format_bytes(size));
+    Ok(())
+}
+
+/// Prints the versions about to be deleted with their upload time and size
+///
+/// Only the first few are listed unless 'all' is set. Encrypted names are shown decrypted if possible
+pub fn list_versions(keys: Option<&Keys>, versions: &[RemoteFile], all: bool) {
+    let shown = if all { versions.len() } else { SHOWN_VERSIONS };
+    for version in versions.iter().take(shown) {
+        let name = match keys {
+            Some(k) if version.file_info.contains_key(files::INFO_ENCRYPTED) || version.action == "hide" =>
+                k.decrypt_name(&version.file_name).unwrap_or_else(|| version.file_name.clone()),
+            _ => version.file_name.clone(),
+        };
+        println!("    {}  {} ({})", format_timestamp((version.upload_timestamp / 1000) as i64), name, format_bytes(version.content_length));
+    }
+    if versions.len() > shown {
+        println!("    ... and {} more, add '--dry-run' to list them all", versions.len() - shown);
+    }
+}
+
+/// Deletes file versions, several at a time, returning how many couldn't be deleted
+pub fn delete_versions(session: &Session, versions: &[RemoteFile]) -> usize {
+    let failed = Mutex::new(0);
+    let pool = Pool::new(::config().transfer.delete_threads);
+    pool.scoped(|scope| {
+        for version in versions {
+            let failed = &failed;
+            // Every version gets a few attempts, see transfer.retries, in case they fail for any reason
+            scope.execute(move || {
+                for attempts in 0..::config().transfer.retries {
+                    match files::delete_file_version(session, &version.file_name, &version.file_id) {
+                        Ok(_) => break,
+                        Err(e) => {
+                            if attempts + 1 == ::config().transfer.retries {
+                                println!();
+                                println!("Failed to delete a version of {} after {} attempts", version.file_name, ::config().transfer.retries);
+                                println!("{:?}", e);
+                                *failed.lock().unwrap() += 1;
+                            }else{
+                                // Sleep for a bit before retrying
+                                std::thread::sleep(Duration::from_secs(::config().transfer.retry_delay));
+                            }
+                        },
+                    }
+                }
+            });
+        }
+    });
+    failed.into_inner().unwrap()
+}
@@ -7,114 +7,162 @@ use std::sync::{Arc, Mutex};
 use std::time::Duration;
 use progress;
 use time;
+use formatting::size_formatter::format_bytes;
+use remote::session::Session;
+use procedures::error::ProcedureError;
 
-pub fn purge_files(raze: &mut engine::Raze ,persistent_data: &mut storage_helper::PersistentData) {
+// How many of the files to hide are listed before hiding them, a dry run lists all of them
+const SHOWN_FILES: usize = 20;
+
+/// Options for the 'purge' command
+#[derive(Debug, PartialEq, Default)]
+pub struct PurgeOptions {
+    /// Hide files even if backup list entries are missing or more files would be hidden than the threshold allows
+    pub force: bool,
+    /// Only list the files that would be hidden
+    pub dry_run: bool,
+}
+
+pub fn purge_files(raze: &mut engine::Raze, session: &Session, persistent_data: &mut storage_helper::PersistentData,
+                   options: PurgeOptions) -> Result<(), ProcedureError> {
     // Verify that a bucket is selected
     if persistent_data.active_bucket == "" {
-        println!("Please set a bucket first with the 'set_bucket' command");
-        return
+        return Err(ProcedureError::NoBucketSelected)
     }
     // Set the active bucket
     raze.set_active_bucket(persistent_data.active_bucket.clone());
     println!("Purging start");
+    if ::procedures::repository::read_chunking_config(session, &persistent_data.active_bucket)?.is_some() {
+        println!("The bucket is in chunked mode, every backup only contains the files that exist at the time");
+        println!("Files deleted locally are left out of the next backup, there is nothing to purge");
+        return Ok(())
+    }
+    // Encrypted buckets store files under their encrypted names, so those are what we compare against
+    let keys = ::procedures::repository::open_repository(session, &persistent_data.active_bucket)?;
     println!("Note: this will only hide the files in the cloud");
-    println!("You can (probably should) configure BackBlaze to delete hidden files after a while");
+    println!("Run 'delete_hidden' once in a while to delete files that have been hidden for {}+ days", ::HIDDEN_GRACE_DAYS);
+    println!("Until then, 'unhide --last-purge' brings back everything this purge hides");
     println!("Constructing file list");
 
+    // An entry in the backup list that's gone, or an empty folder like the mount point of an unplugged drive,
+    // would have everything backed up from it hidden
+    let roots = ::procedures::backup::backup_roots()?;
+    let missing: Vec<&str> = roots.iter().map(|r| r.path.as_ref()).filter(|p| is_missing(p)).collect();
+    for path in &missing {
+        println!("{} is missing or empty", path);
+    }
+    // A dry run hides nothing, so it goes on to show what those entries would take with them
+    if !missing.is_empty() && !options.force && !options.dry_run {
+        println!("Everything backed up from there would be hidden, check that drives are mounted and '{}' is correct", ::config().backup_list_file().display());
+        println!("Run 'purge --force' if those files really should be hidden");
+        return Err(ProcedureError::Refused(format!("{} backup list entries are missing, nothing was hidden", missing.len())))
+    }
+
     // Get a list of files for potential upload
     // Note that we do not intend to upload them, this is a delete function :-)
-    let mut file_list = storage_helper::create_file_list(
-        storage_helper::read_lines_to_vec(
-            std::path::Path::new(::BACKUP_LIST_FILE_NAME)).unwrap());
-    for i in 0..file_list.len() {
-        let fl = file_list[i].clone();
-        let entry_parent = fl.parent();
-        let mut prefix = "";
-        if entry_parent.is_some() {
-            let entry_str = entry_parent.unwrap().to_str().unwrap();
-            let fwd = entry_str.find("/");
-            let bwd = entry_str.find("\\");
-            if fwd.is_some() {
-                prefix = &entry_str[fwd.unwrap() + 1..];
-            } else if bwd.is_some() {
-                prefix = &entry_str[bwd.unwrap() + 1..];
-            }
-        }
-        file_list[i] = std::path::PathBuf::from(format!("{}/{}", prefix, file_list[i].file_name().unwrap().to_str().unwrap()).replace("\\", "/"));
-    }
+    // Only the names they are stored under matter here
+    // Empty folders are listed by their marker, which gets hidden once the folder is gone or has files in it
+    let mut file_list: Vec<String> = storage_helper::create_file_list(&roots,
+        &::procedures::backup::exclude_filter()?,
+        persistent_data.symlinks)
+        .into_iter()
+        .map(|file| match keys {
+            Some(ref k) => k.encrypt_name(&file.name),
+            None => file.name,
+        })
+        .collect();
 
-    if file_list.len() == 0 {
-        println!("It seems like the {} doesn't exist or contains no entries, aborting", ::BACKUP_LIST_FILE_NAME);
-        return
+    if file_list.is_empty() {
+        return Err(ProcedureError::EmptyBackupList)
     }
 
     // Sort it so we can use binary search for finding elements
     file_list.sort();
 
+    // Find every file we have uploaded that no longer exists locally
+    // To do this, we go through the local index of uploaded files and
+    // check if each of them is still in the list of local files
+    println!("Discovering deletable files...");
+    let index = ::procedures::index::load_index(session, &persistent_data.active_bucket)?;
+    let stored_file_count = index.files.len();
+    let to_hide: Vec<(String, u64)> = index.files.iter()
+        .filter(|(name, _)| file_list.binary_search(name).is_err())
+        .map(|(name, entry)| (name.clone(), entry.size))
+        .collect();
+    let hide_size = to_hide.iter().fold(0, |acc, f| acc + f.1);
+
+    // Show what is about to happen before doing anything
+    let shown = if options.dry_run { to_hide.len() } else { SHOWN_FILES };
+    for (name, size) in to_hide.iter().take(shown) {
+        let display_name = match keys {
+            Some(ref k) => k.decrypt_name(name).unwrap_or_else(|| name.clone()),
+            None => name.clone(),
+        };
+        println!("    {} ({})", display_name, format_bytes(*size));
+    }
+    if to_hide.len() > shown {
+        println!("    ... and {} more, 'purge --dry-run' lists them all", to_hide.len() - shown);
+    }
+    println!("{} of {} files would be hidden, {} in total", to_hide.len(), stored_file_count, format_bytes(hide_size));
+    if options.dry_run {
+        if !missing.is_empty() {
+            println!("{} backup list entries are missing, a purge without '--force' would refuse to run", missing.len());
+        }
+        println!("Dry run, nothing was hidden");
+        return Ok(())
+    }
+    // Hiding a large part of the backup at once is more likely a mistake than a cleanup
+    if to_hide.len() * 100 > persistent_data.purge_threshold as usize * stored_file_count && !options.force {
+        println!("That's more than the {}% allowed by 'purge_threshold', which usually means files are missing rather than deleted",
+                 persistent_data.purge_threshold);
+        println!("Run 'purge --force' if those files really should be hidden");
+        return Err(ProcedureError::Refused(format!("{} files would be hidden, nothing was hidden", to_hide.len())))
+    }
+
+    let started = time::get_time().sec;
+
     // Create a progress bar
     // Wrap progress bar and finished_uploads in an Arc(Mutex)
     // This is needed so each thread can redraw a correct progress bar
     let bar = progress::Bar::new();
     let bar = Arc::new(Mutex::new(bar));
-    let delete_amount = Arc::new(Mutex::new(0));
+    let delete_amount = Arc::new(Mutex::new(to_hide.len()));
     let finished_deletes = Arc::new(Mutex::new(0));
-    let saved_space = Arc::new(Mutex::new(0));
-
-    // Before we start uploading, we should check if the file is also on the server
-    // To do this, we retrieve a list of all files on the server,
-    // check if one of them has the same path+name as the one we're uploading and
-    println!("Discovering deletable files...");
-    let stored_file_list = raze.list_all_file_names(&persistent_data.active_bucket, 1000).unwrap();
-    let stored_file_count = stored_file_list.len();
+    let saved_space = Arc::new(Mutex::new(hide_size));
+    let failed_deletes = Arc::new(Mutex::new(0));
+    let index = Arc::new(Mutex::new(index));
 
-    // Create a scoped pool and queue each file in the list for uploading
-    let pool = Pool::new(::DELETE_THREADS);
+    // Create a scoped pool and queue each file in the list for hiding
+    let pool = Pool::new(::config().transfer.delete_threads);
     pool.scoped(|scope| {
-        for i in 0..stored_file_count {
-
-            // Create a PathBuf from the StoredFile
-            let pb = std::path::PathBuf::from(stored_file_list[i].file_name.clone());
-
-            // If it's found, skip to the next file, if not, queue it for uploading
-            let should_delete: bool;
-            match file_list.binary_search(&pb) {
-                Ok(_) => { // A file with the same path+name exists
-                    should_delete = false;
-                },
-                Err(_e) => { // No matching path+name exists
-                    should_delete = true;
-                    let mut data = delete_amount.clone();
-                    *data.lock().unwrap() += 1;
-                    let mut data2 = saved_space.clone();
-                    *data2.lock().unwrap() += stored_file_list[i].content_length;
-                }
-            }
-            if !should_delete {
-                continue;
-            }
+        for (file_name, _) in &to_hide {
             // Clone all the data we pass to the thread
-            let entry = stored_file_list[i].clone();
+            let file_name = file_name.clone();
             let mut r = raze.clone();
             let fin_deletes = finished_deletes.clone();
+            let failed = failed_deletes.clone();
+            let idx = index.clone();
 
             // Queue the delete request
-            // Every file gets a maximum of 5 attempts in case they fail for any reason
+            // Every file gets a few attempts, see transfer.retries, in case they fail for any reason
             // If the request fails, it'll sleep and retry
             scope.execute(move || {
-                for attempts in 0..5 {
-                    let res = r.hide_file(entry.file_name.clone());
+                for attempts in 0..::config().transfer.retries {
+                    let res = r.hide_file(file_name.clone());
                     match res {
-                        Some(_) => {
+                        None => {
+                            idx.lock().unwrap().files.remove(&file_name);
                             break
                         },
-                        None => {
-                            if attempts == 4 {
+                        Some(_) => {
+                            if attempts + 1 == ::config().transfer.retries {
                                 println!();
-                                println!("Failed to delete {} after 5 attempts",
-                                         format!("{}", entry.file_name));
+                                println!("Failed to delete {} after {} attempts",
+                                         format!("{}", file_name), ::config().transfer.retries);
+                                *failed.lock().unwrap() += 1;
                             }else{
                                 // Sleep for a bit before retrying
-                                std::thread::sleep(Duration::from_millis(5000));
+                                std::thread::sleep(Duration::from_secs(::config().transfer.retry_delay));
                             }
                         }
                     }
@@ -154,7 +202,33 @@ pub fn purge_files(raze: &mut engine::Raze ,persistent_data: &mut storage_helper
         }
     });
     println!();
-    println!("Backup successfully completed");
-    persistent_data.last_backup = time::get_time().sec;
-    persistent_data.save_to_file(&std::path::Path::new(::PERSISTENT_DATA_FILE_NAME));
-}
\ No newline at end of file
+    let saved = index.lock().unwrap().save_to_file(&::config().index_file());
+    if let Err(e) = saved {
+        return Err(ProcedureError::save_failed(&::config().index_file(), e))
+    }
+    // Every hide marker this run made falls in this span, which is what 'unhide --last-purge' looks for
+    if !to_hide.is_empty() {
+        persistent_data.last_purge = Some(storage_helper::PurgeRun {
+            start: started,
+            end: time::get_time().sec,
+        });
+        persistent_data.save_to_file(&::config().data_file()).map_err(ProcedureError::SaveFailed)?;
+    }
+    let failed = *failed_deletes.lock().unwrap();
+    if failed > 0 {
+        println!("Purge completed, but {} files could not be hidden", failed);
+        return Err(ProcedureError::Incomplete(failed))
+    }
+    // Purging isn't a backup, 'last_backup' is what 'backup --if-due' goes by
+    println!("Purge successfully completed");
+    Ok(())
+}
+
+// Whether a backup list entry is gone, or is a folder with nothing in it
+fn is_missing(path: &str) -> bool {
+    let path = std::path::Path::new(path);
+    match std::fs::read_dir(path) {
+        Ok(mut entries) => entries.next().is_none(),
+        Err(_e) => !path.is_file(),
+    }
+}
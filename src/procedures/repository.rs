@@ -0,0 +1,233 @@
+use std;
+use std::io::Read;
+use std::collections::BTreeMap;
+use serde_json;
+use rpassword;
+use sha1;
+use storage::storage as storage_helper;
+use storage::crypto::{Keys, RepositoryHeader};
+use storage::chunks::{self, ChunkingConfig};
+use storage::manifest::{self, Manifest};
+use remote::session::Session;
+use remote::files;
+use procedures::error::ProcedureError;
+
+// Name of the repository header in the bucket, only present if encryption was set up
+pub const REPOSITORY_HEADER_NAME: &str = ".raze/repository.json";
+// If set, the passphrase is read from this environment variable instead of asking for it
+const PASSPHRASE_VARIABLE: &str = "RAZE_PASSPHRASE";
+
+/// Looks for a repository header in the bucket and unlocks its keys with the passphrase
+///
+/// Returns None if the bucket doesn't use encryption, files are then uploaded as they are
+pub fn open_repository(session: &Session, bucket_id: &str) -> Result<Option<Keys>, ProcedureError> {
+    let header = match read_header(session, bucket_id)? {
+        Some(v) => v,
+        None => return Ok(None),
+    };
+    println!("The bucket is encrypted");
+    let passphrase = read_passphrase("Passphrase: ")?;
+    match Keys::open(&passphrase, &header) {
+        Ok(keys) => Ok(Some(keys)),
+        Err(e) => Err(ProcedureError::Encryption(e.to_string())),
+    }
+}
+
+/// The 'init-encryption' command, sets up encryption for the active bucket
+///
+/// Stores a new repository header, everything uploaded from then on is encrypted
+pub fn init_encryption(session: &Session, persistent_data: &mut storage_helper::PersistentData) -> Result<(), ProcedureError> {
+    // Verify that a bucket is selected
+    if persistent_data.active_bucket.is_empty() {
+        return Err(ProcedureError::NoBucketSelected)
+    }
+    if read_header(session, &persistent_data.active_bucket)?.is_some() {
+        return Err(ProcedureError::Encryption("Encryption is already set up for this bucket".to_owned()))
+    }
+    println!("Choose a passphrase. Nothing in the bucket can be restored without it, and it can't be recovered");
+    let passphrase = read_passphrase("New passphrase: ")?;
+    if passphrase.is_empty() {
+        return Err(ProcedureError::Encryption("The passphrase can't be empty".to_owned()))
+    }
+    if std::env::var(PASSPHRASE_VARIABLE).is_err() && read_passphrase("Repeat passphrase: ")? != passphrase {
+        return Err(ProcedureError::Encryption("The passphrases don't match".to_owned()))
+    }
+    println!("Deriving keys...");
+    let (header, _keys) = Keys::create(&passphrase);
+
+    let json = serde_json::to_vec(&header).unwrap();
+    upload_object(session, &persistent_data.active_bucket, REPOSITORY_HEADER_NAME, json, BTreeMap::new())?;
+    println!("Encryption is set up, files are encrypted from the next backup on");
+    println!("Files uploaded before stay unencrypted until the next purge hides them");
+    Ok(())
+}
+
+/// Reads the chunking settings of the bucket, None if it isn't in chunked mode
+pub fn read_chunking_config(session: &Session, bucket_id: &str) -> Result<Option<ChunkingConfig>, ProcedureError> {
+    let contents = match read_object(session, bucket_id, chunks::CHUNKING_CONFIG_NAME)? {
+        Some(v) => v.0,
+        None => return Ok(None),
+    };
+    let config: ChunkingConfig = match serde_json::from_slice(&contents) {
+        Ok(v) => v,
+        Err(e) => return Err(ProcedureError::Repository(format!("Invalid chunking settings: {}", e))),
+    };
+    config.validate().map_err(ProcedureError::Repository)?;
+    Ok(Some(config))
+}
+
+/// The 'init-chunking' command, switches an empty bucket to chunked mode
+///
+/// Chunked mode splits files into chunks and only uploads the ones not stored yet,
+/// see storage::chunks. It can't be mixed with files uploaded as a whole
+pub fn init_chunking(session: &Session, persistent_data: &mut storage_helper::PersistentData) -> Result<(), ProcedureError> {
+    // Verify that a bucket is selected
+    if persistent_data.active_bucket.is_empty() {
+        return Err(ProcedureError::NoBucketSelected)
+    }
+    if read_chunking_config(session, &persistent_data.active_bucket)?.is_some() {
+        return Err(ProcedureError::Repository("The bucket is already in chunked mode".to_owned()))
+    }
+    // Everything of our own is below REPOSITORY_PREFIX, so look before and after it for anything else
+    // Names starting with the prefix with its '/' replaced by '0' sort right after all of those
+    let first = files::list_file_names(session, &persistent_data.active_bucket, "", "", 1).map_err(ProcedureError::B2Error)?;
+    let after = files::list_file_names(session, &persistent_data.active_bucket,
+                                       &format!("{}0", ::REPOSITORY_PREFIX.trim_end_matches('/')), "", 1).map_err(ProcedureError::B2Error)?;
+    if first.files.iter().chain(after.files.iter()).any(|f| !f.file_name.starts_with(::REPOSITORY_PREFIX)) {
+        return Err(ProcedureError::Repository("Chunked mode can only be set up for a bucket without backed up files".to_owned()))
+    }
+    let json = serde_json::to_vec(&ChunkingConfig::default()).unwrap();
+    upload_object(session, &persistent_data.active_bucket, chunks::CHUNKING_CONFIG_NAME, json, BTreeMap::new())?;
+    println!("Chunked mode is set up, backups now only upload the parts of files that changed");
+    Ok(())
+}
+
+/// Lists every manifest in the bucket, oldest first
+pub fn list_manifests(session: &Session, bucket_id: &str) -> Result<Vec<files::RemoteFile>, ProcedureError> {
+    files::list_all_file_names(session, bucket_id, manifest::MANIFEST_PREFIX, 1000).map_err(ProcedureError::B2Error)
+}
+
+/// Downloads the most recent manifest, None if no backup stored one yet
+pub fn latest_manifest(session: &Session, bucket_id: &str, keys: Option<&Keys>) -> Result<Option<Manifest>, ProcedureError> {
+    let list = list_manifests(session, bucket_id)?;
+    match list.last() {
+        Some(f) => read_manifest(session, f, keys).map(Some),
+        None => Ok(None),
+    }
+}
+
+/// Downloads the manifest of a snapshot, given its id as listed by 'snapshots' or "latest"
+///
+/// The time on its own is enough, unless several backups were started in that second
+pub fn find_manifest(session: &Session, bucket_id: &str, keys: Option<&Keys>, snapshot: &str) -> Result<Manifest, ProcedureError> {
+    if snapshot == "latest" {
+        return match latest_manifest(session, bucket_id, keys)? {
+            Some(v) => Ok(v),
+            None => Err(ProcedureError::Repository("There are no snapshots in this bucket yet".to_owned())),
+        }
+    }
+    let (time, suffix) = match snapshot.find('-') {
+        Some(i) => (&snapshot[..i], Some(&snapshot[i + 1..])),
+        None => (snapshot, None),
+    };
+    let prefix = match time.parse::<i64>() {
+        Ok(time) => format!("{}{:012}", manifest::MANIFEST_PREFIX, time),
+        Err(_e) => return Err(ProcedureError::Repository(format!("Invalid snapshot '{}', use the id listed by 'snapshots'", snapshot))),
+    };
+    let list = files::list_all_file_names(session, bucket_id, &prefix, 1000).map_err(ProcedureError::B2Error)?;
+    let found: Vec<&files::RemoteFile> = list.iter().filter(|f| {
+        let rest = &f.file_name[prefix.len()..];
+        match suffix {
+            Some(s) => rest.len() == s.len() + 1 && rest.ends_with(s) && rest.starts_with('-'),
+            None => rest.is_empty() || rest.starts_with('-'),
+        }
+    }).collect();
+    match found.len() {
+        0 => Err(ProcedureError::Repository(format!("There is no snapshot '{}'", snapshot))),
+        1 => read_manifest(session, found[0], keys),
+        _ => {
+            let ids: Vec<String> = found.iter().map(|f| f.file_name[manifest::MANIFEST_PREFIX.len()..].trim_start_matches('0').to_owned()).collect();
+            Err(ProcedureError::Repository(format!("Several backups were started at {}, pick one of {}", time, ids.join(", "))))
+        },
+    }
+}
+
+/// Downloads and decodes a manifest
+pub fn read_manifest(session: &Session, remote_file: &files::RemoteFile, keys: Option<&Keys>) -> Result<Manifest, ProcedureError> {
+    let mut resp = files::download_file_by_id(session, &remote_file.file_id).map_err(ProcedureError::B2Error)?;
+    let mut contents = Vec::new();
+    if let Err(e) = resp.read_to_end(&mut contents) {
+        return Err(ProcedureError::Repository(format!("Failed to read manifest {}: {}", remote_file.file_name, e)))
+    }
+    let keys = if remote_file.file_info.contains_key(files::INFO_ENCRYPTED) { keys } else { None };
+    match Manifest::decode(keys, remote_file.file_info.get(files::INFO_CODEC).map(|c| c.as_ref()), &contents) {
+        Ok(v) => Ok(v),
+        Err(e) => Err(ProcedureError::Repository(format!("Invalid manifest {}: {}", remote_file.file_name, e))),
+    }
+}
+
+/// Encodes and uploads a manifest
+pub fn save_manifest(session: &Session, bucket_id: &str, keys: Option<&Keys>, manifest: &Manifest) -> Result<(), ProcedureError> {
+    let (data, codec) = match manifest.encode(keys) {
+        Ok(v) => v,
+        Err(e) => return Err(ProcedureError::Repository(format!("Failed to encode the manifest: {}", e))),
+    };
+    let mut info = BTreeMap::new();
+    if keys.is_some() {
+        info.insert(files::INFO_ENCRYPTED.to_owned(), "1".to_owned());
+    }
+    if let Some(c) = codec {
+        info.insert(files::INFO_CODEC.to_owned(), c);
+    }
+    upload_object(session, bucket_id, &manifest.object_name(), data, info).map(|_| ())
+}
+
+/// Uploads a small object kept in memory, like a header or manifest
+pub fn upload_object(session: &Session, bucket_id: &str, name: &str, data: Vec<u8>, info: BTreeMap<String, String>) -> Result<files::RemoteFile, ProcedureError> {
+    let sha1 = sha1::Sha1::from(&data).digest().to_string();
+    let len = data.len() as u64;
+    let upload_auth = files::get_upload_url(session, bucket_id).map_err(ProcedureError::B2Error)?;
+    files::upload_file(session, &upload_auth, name, std::io::Cursor::new(data), len, &sha1, &info).map_err(ProcedureError::B2Error)
+}
+
+// Downloads the object with exactly this name, None if it doesn't exist
+fn read_object(session: &Session, bucket_id: &str, name: &str) -> Result<Option<(Vec<u8>, files::RemoteFile)>, ProcedureError> {
+    // Listing from the object's name returns it first, if it exists
+    let list = files::list_file_names(session, bucket_id, name, "", 1).map_err(ProcedureError::B2Error)?;
+    let remote_file = match list.files.into_iter().next() {
+        Some(f) => f,
+        None => return Ok(None),
+    };
+    if remote_file.file_name != name {
+        return Ok(None);
+    }
+    let mut resp = files::download_file_by_id(session, &remote_file.file_id).map_err(ProcedureError::B2Error)?;
+    let mut contents = Vec::new();
+    if let Err(e) = resp.read_to_end(&mut contents) {
+        return Err(ProcedureError::Repository(format!("Failed to read {}: {}", name, e)))
+    }
+    Ok(Some((contents, remote_file)))
+}
+
+// Downloads the repository header, None if the bucket doesn't have one
+fn read_header(session: &Session, bucket_id: &str) -> Result<Option<RepositoryHeader>, ProcedureError> {
+    let contents = match read_object(session, bucket_id, REPOSITORY_HEADER_NAME)? {
+        Some(v) => v.0,
+        None => return Ok(None),
+    };
+    match serde_json::from_slice(&contents) {
+        Ok(v) => Ok(Some(v)),
+        Err(e) => Err(ProcedureError::Encryption(format!("Invalid repository header: {}", e))),
+    }
+}
+
+// Asks for the passphrase without echoing it, unless it was given in the environment
+fn read_passphrase(prompt: &str) -> Result<String, ProcedureError> {
+    if let Ok(v) = std::env::var(PASSPHRASE_VARIABLE) {
+        return Ok(v);
+    }
+    match rpassword::prompt_password(prompt) {
+        Ok(v) => Ok(v),
+        Err(e) => Err(ProcedureError::Encryption(format!("Failed to read the passphrase: {}", e))),
+    }
+}
@@ -0,0 +1,411 @@
+use std;
+use std::io::{stdout, Read, Write};
+use std::path::{Path, PathBuf};
+use std::collections::BTreeMap;
+use formatting::size_formatter::format_bytes;
+use formatting::time_formatter::format_timestamp;
+use storage::storage as storage_helper;
+use storage::crypto::Keys;
+use storage::paths;
+use storage::metadata::FileMetadata;
+use remote::session::Session;
+use storage::manifest::{Manifest, ManifestFile, ChunkLocation};
+use remote::files::{self, RemoteFile};
+use procedures::error::ProcedureError;
+use scoped_pool::Pool;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use progress;
+use zstd;
+
+/// Options for the 'restore' command
+#[derive(Debug, PartialEq, Default)]
+pub struct RestoreOptions {
+    /// Restore into this directory instead of the original location, keeping the full remote path below it
+    pub target: Option<PathBuf>,
+    /// Only restore files at or below this local path
+    pub path: Option<String>,
+    /// Replace files that already exist locally
+    pub overwrite: bool,
+    /// Restore the files as they were in this snapshot instead of their latest version
+    pub snapshot: Option<String>,
+    /// Leave the owner of restored files alone, changing it usually takes root
+    pub no_owner: bool,
+    /// Don't put back extended attributes
+    pub no_xattrs: bool,
+}
+
+pub fn restore_files(session: &Session, persistent_data: &mut storage_helper::PersistentData, options: RestoreOptions) -> Result<(), ProcedureError> {
+    // Verify that a bucket is selected
+    if persistent_data.active_bucket.is_empty() {
+        return Err(ProcedureError::NoBucketSelected)
+    }
+    println!("Restore start");
+    let keys = ::procedures::repository::open_repository(session, &persistent_data.active_bucket)?;
+
+    // The backup list is needed to figure out where each file came from
+    let roots = ::procedures::backup::backup_roots()?;
+    let filter = options.path.as_ref().map(|p| paths::remote_prefix(&roots, p));
+
+    println!("Retrieving list of backed up files...");
+    let mut skipped = 0;
+    let chunked = ::procedures::repository::read_chunking_config(session, &persistent_data.active_bucket)?.is_some();
+    let backed_up = match options.snapshot {
+        Some(ref snapshot) => {
+            let manifest = ::procedures::repository::find_manifest(session, &persistent_data.active_bucket, keys.as_ref(), snapshot)?;
+            println!("Restoring snapshot {} from {}", manifest.id(), format_timestamp(manifest.time));
+            snapshot_files(session, &persistent_data.active_bucket, manifest, &mut skipped)?
+        },
+        // Without a snapshot, the latest version of every file is restored
+        None if chunked => match ::procedures::repository::latest_manifest(session, &persistent_data.active_bucket, keys.as_ref())? {
+            Some(manifest) => snapshot_files(session, &persistent_data.active_bucket, manifest, &mut skipped)?,
+            None => Vec::new(),
+        },
+        None => whole_files(session, &persistent_data.active_bucket, keys.as_ref(), &mut skipped)?,
+    };
+
+    // Decide where every file goes, skipping the ones we shouldn't touch
+    let mut restore_list: Vec<(Source, PathBuf, bool)> = Vec::new();
+    for (file_name, source) in backed_up {
+        if let Some(ref f) = filter {
+            if !f.is_empty() && file_name != *f && !file_name.starts_with(&format!("{}/", f)) {
+                continue;
+            }
+        }
+        // Empty directories are stored as a marker inside them
+        let directory = paths::is_marker(&file_name);
+        let local = match paths::local_path(&file_name, &roots, &options.target) {
+            Some(v) if directory => v.parent().unwrap().to_owned(),
+            Some(v) => v,
+            None => {
+                println!("Don't know where '{}' came from, use --to to restore it", file_name);
+                skipped += 1;
+                continue;
+            }
+        };
+        // A link counts as existing even if what it points to doesn't
+        if local.symlink_metadata().is_ok() && !options.overwrite {
+            skipped += 1;
+            continue;
+        }
+        restore_list.push((source, local, directory));
+    }
+    if skipped > 0 {
+        println!("Skipping {} files that already exist or have no known location, use --overwrite to replace existing files", skipped);
+    }
+
+    let file_count = restore_list.len();
+    let list_size = restore_list.iter().fold(0, |acc, f| acc + f.0.size());
+    println!("Downloading {} ({} bytes) across {} files", format_bytes(list_size), list_size, file_count);
+    stdout().flush().unwrap();
+    if file_count == 0 {
+        return Ok(())
+    }
+
+    // Create a progress bar
+    // Wrap progress bar and finished_downloads in an Arc(Mutex)
+    // This is needed so each thread can redraw a correct progress bar
+    let bar = progress::Bar::new();
+    let bar = Arc::new(Mutex::new(bar));
+    let finished_downloads = Arc::new(Mutex::new(0));
+    let failed_downloads = Arc::new(Mutex::new(0));
+
+    // Create a scoped pool and queue each file in the list for downloading
+    let pool = Pool::new(::config().transfer.download_threads);
+    pool.scoped(|scope| {
+        for (source, local, directory) in &restore_list {
+            let fin_downloads = finished_downloads.clone();
+            let failed = failed_downloads.clone();
+            let keys = &keys;
+            let options = &options;
+
+            // Queue the download tasks
+            // Every file gets a few attempts, see transfer.retries, in case they fail for any reason
+            scope.execute(move || {
+                // Stored links have nothing to download
+                let link = source.symlink(keys.as_ref());
+                for attempts in 0..::config().transfer.retries {
+                    let result = match link {
+                        Some(ref l) => restore_symlink(l, local).map_err(|e| e.to_string()),
+                        None if *directory => std::fs::create_dir_all(local).map_err(|e| e.to_string()),
+                        None => download_to(session, keys.as_ref(), source, local),
+                    };
+                    match result {
+                        Ok(_) if link.is_some() => break,
+                        Ok(_) => {
+                            // The content is what matters, so failing to put back the metadata is only a warning
+                            if let Some(metadata) = source.metadata(keys.as_ref()) {
+                                if let Err(e) = metadata.apply(local, !options.no_owner, !options.no_xattrs) {
+                                    println!();
+                                    println!("Restored {}, but not all of its metadata: {}", local.display(), e);
+                                }
+                            }
+                            break
+                        },
+                        Err(e) => {
+                            if attempts + 1 == ::config().transfer.retries {
+                                println!();
+                                println!("Failed to restore {} after {} attempts", local.display(), ::config().transfer.retries);
+                                println!("{}", e);
+                                *failed.lock().unwrap() += 1;
+                            }else{
+                                // Sleep for a bit before retrying
+                                std::thread::sleep(Duration::from_secs(::config().transfer.retry_delay));
+                            }
+                        },
+                    }
+                }
+                let mut data = fin_downloads.lock().unwrap();
+                *data += 1;
+            });
+        }
+
+        // Start the loop that prints the progress bar and checks if we're done yet
+        let progress_bar = bar.clone();
+        progress_bar.lock().unwrap().set_job_title("Restore in progress");
+        loop {
+            std::thread::sleep(Duration::from_millis(1000));
+            let data = *finished_downloads.lock().unwrap();
+            progress_bar.lock().unwrap().set_job_title(&format!("Restore in progress ({}/{})", data, file_count));
+            progress_bar.lock().unwrap().reach_percent((((data as f64) / (file_count as f64)) * 100.) as i32);
+            if data == file_count {
+                break;
+            }
+        }
+    });
+    println!();
+    let failed = *failed_downloads.lock().unwrap();
+    if failed > 0 {
+        println!("Restore completed, but {} files could not be downloaded", failed);
+        return Err(ProcedureError::Incomplete(failed))
+    }
+    println!("Restore successfully completed");
+    Ok(())
+}
+
+/// Where the content of a backed up file comes from
+pub enum Source {
+    /// Uploaded as a whole
+    File(RemoteFile),
+    /// Split into these chunks, in chunked mode
+    Chunks(ManifestFile, Vec<ChunkLocation>),
+}
+
+impl Source {
+    /// Size of the original file
+    pub fn size(&self) -> u64 {
+        match *self {
+            Source::File(ref remote_file) => match remote_file.file_info.get(files::INFO_SIZE).and_then(|s| s.parse().ok()) {
+                Some(v) => v,
+                None => remote_file.content_length,
+            },
+            Source::Chunks(ref file, _) => file.size,
+        }
+    }
+
+    // Permissions, ownership, modification time and extended attributes recorded at backup, if any
+    fn metadata(&self, keys: Option<&Keys>) -> Option<FileMetadata> {
+        let (posix, xattrs) = match *self {
+            Source::File(ref remote_file) => (info_value(keys, remote_file, files::INFO_POSIX),
+                                              info_value(keys, remote_file, files::INFO_XATTRS)),
+            Source::Chunks(ref file, _) => (file.posix.clone(), file.xattrs.clone()),
+        };
+        let mut metadata = FileMetadata::decode(&posix?);
+        if let Some(x) = xattrs {
+            metadata.decode_xattrs(&x);
+        }
+        Some(metadata)
+    }
+
+    /// SHA-1 of the original content, "none" for large files that have no whole-file hash
+    pub fn sha1(&self) -> String {
+        match *self {
+            // Transformed files carry the hash of the original, others are checked against the hash B2 has
+            Source::File(ref remote_file) => match remote_file.file_info.get(files::INFO_SHA1) {
+                Some(v) => v.clone(),
+                None => remote_file.content_sha1.trim_start_matches("unverified:").to_owned(),
+            },
+            Source::Chunks(ref file, _) => file.sha1.clone(),
+        }
+    }
+
+    /// Where the file points if it was stored as a symbolic link
+    pub fn symlink(&self, keys: Option<&Keys>) -> Option<String> {
+        match *self {
+            Source::File(ref remote_file) => info_value(keys, remote_file, files::INFO_SYMLINK),
+            Source::Chunks(ref file, _) => file.symlink.clone(),
+        }
+    }
+}
+
+// Reads a file info value, which is encrypted like the name for encrypted files
+fn info_value(keys: Option<&Keys>, remote_file: &RemoteFile, key: &str) -> Option<String> {
+    let value = remote_file.file_info.get(key)?;
+    match keys {
+        Some(k) if remote_file.file_info.contains_key(files::INFO_ENCRYPTED) => k.decrypt_name(value),
+        _ => Some(value.clone()),
+    }
+}
+
+/// Lists the files uploaded as a whole, along with their original names
+///
+/// Files whose name can't be decrypted are counted in 'skipped'
+pub fn whole_files(session: &Session, bucket_id: &str, keys: Option<&Keys>, skipped: &mut usize) -> Result<Vec<(String, Source)>, ProcedureError> {
+    let remote_file_list = match files::list_all_file_names(session, bucket_id, "", 1000) {
+        Ok(v) => v,
+        Err(e) => return Err(ProcedureError::B2Error(e)),
+    };
+    let mut backed_up = Vec::new();
+    for remote_file in remote_file_list {
+        if remote_file.file_name.starts_with(::REPOSITORY_PREFIX) {
+            continue;
+        }
+        // Encrypted files only reveal their original name with the right keys
+        let file_name = match (remote_file.file_info.contains_key(files::INFO_ENCRYPTED), keys) {
+            (false, _) => remote_file.file_name.clone(),
+            (true, Some(k)) => match k.decrypt_name(&remote_file.file_name) {
+                Some(v) => v,
+                None => {
+                    println!("Can't decrypt the name of '{}', skipping it", remote_file.file_name);
+                    *skipped += 1;
+                    continue;
+                },
+            },
+            (true, None) => {
+                *skipped += 1;
+                continue;
+            },
+        };
+        backed_up.push((file_name, Source::File(remote_file)));
+    }
+    Ok(backed_up)
+}
+
+/// Lists the files in a snapshot, along with where their content is
+///
+/// Files whose version was deleted since are counted in 'skipped'
+pub fn snapshot_files(session: &Session, bucket_id: &str, manifest: Manifest, skipped: &mut usize) -> Result<Vec<(String, Source)>, ProcedureError> {
+    // Decoding a file uploaded as a whole takes the file info of its version, which only a listing gives
+    let versions: BTreeMap<String, RemoteFile> = if manifest.files.values().any(|f| f.file_id.is_some()) {
+        println!("Listing all file versions, this may take a bit...");
+        match files::list_all_file_versions(session, bucket_id, "", 1000) {
+            Ok(v) => v.into_iter().filter(|f| f.action == "upload").map(|f| (f.file_id.clone(), f)).collect(),
+            Err(e) => return Err(ProcedureError::B2Error(e)),
+        }
+    } else {
+        BTreeMap::new()
+    };
+    let mut backed_up = Vec::new();
+    let chunks = manifest.chunks;
+    for (file_name, file) in manifest.files {
+        if let Some(ref file_id) = file.file_id {
+            match versions.get(file_id) {
+                Some(v) => backed_up.push((file_name, Source::File(v.clone()))),
+                None => {
+                    println!("The version of '{}' in this snapshot was deleted, skipping it", file_name);
+                    *skipped += 1;
+                },
+            }
+            continue;
+        }
+        let locations: Option<Vec<ChunkLocation>> = file.chunks.iter().map(|id| chunks.get(id).cloned()).collect();
+        match locations {
+            Some(v) => backed_up.push((file_name, Source::Chunks(file, v))),
+            None => return Err(ProcedureError::Repository(format!("The manifest is missing chunks of '{}'", file_name))),
+        }
+    }
+    Ok(backed_up)
+}
+
+// Downloads a single file, writing it next to its destination first and moving it in place once verified
+//
+// Encrypted and compressed files are decoded on the way and checked against the hash of the original
+fn download_to(session: &Session, keys: Option<&Keys>, source: &Source, local: &Path) -> Result<(), String> {
+    if let Some(parent) = local.parent() {
+        std::fs::create_dir_all(parent).map_err(|e| e.to_string())?;
+    }
+    // Names that aren't valid unicode are kept as they are
+    let mut partial_name = match local.file_name() {
+        Some(v) => v.to_os_string(),
+        None => return Err(format!("{} is not a file name", local.display())),
+    };
+    partial_name.push(".razepart");
+    let partial = local.with_file_name(partial_name);
+    let mut write = storage_helper::HashingWriter::new(std::fs::File::create(&partial).map_err(|e| e.to_string())?);
+    let copied = download_content(session, keys, source, &mut write);
+    let hash = write.digest();
+    drop(write);
+    let expected = match copied {
+        Ok(v) => v,
+        Err(e) => {
+            let _ = std::fs::remove_file(&partial);
+            return Err(e);
+        },
+    };
+    // Large files have no whole-file hash, so there is nothing to compare against
+    match expected {
+        ref v if v != "none" && *v != hash => {
+            let _ = std::fs::remove_file(&partial);
+            Err(format!("Checksum mismatch for {}", local.display()))
+        },
+        _ => std::fs::rename(&partial, local).map_err(|e| e.to_string()),
+    }
+}
+
+/// Downloads the original content of a file into 'write', decoding it on the way
+///
+/// Returns the SHA-1 the content should have, "none" for large files that have no whole-file hash
+pub fn download_content<W: Write>(session: &Session, keys: Option<&Keys>, source: &Source, mut write: W) -> Result<String, String> {
+    match *source {
+        Source::File(ref remote_file) => {
+            let mut resp = match files::download_file_by_id(session, &remote_file.file_id) {
+                Ok(v) => v,
+                Err(e) => return Err(format!("{:?}", e)),
+            };
+            decode_content(keys, remote_file, &mut resp, &mut write).map_err(|e| e.to_string())?;
+        },
+        Source::Chunks(_, ref locations) => {
+            ::procedures::chunked::restore_chunks(session, keys, locations, &mut write).map_err(|e| e.to_string())?;
+        },
+    }
+    Ok(source.sha1())
+}
+
+// Recreates a symbolic link, replacing a file or link in its place
+// A directory there is left alone, it may hold files that were never backed up
+fn restore_symlink(link: &str, local: &Path) -> std::io::Result<()> {
+    if let Some(parent) = local.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    match local.symlink_metadata() {
+        Ok(ref m) if m.is_dir() => {
+            return Err(std::io::Error::new(std::io::ErrorKind::AlreadyExists,
+                format!("{} is a directory now, move it away to restore the link to {}", local.display(), link)));
+        },
+        Ok(_) => std::fs::remove_file(local)?,
+        Err(_e) => (),
+    }
+    #[cfg(unix)]
+    return std::os::unix::fs::symlink(link, local);
+    #[cfg(windows)]
+    return std::os::windows::fs::symlink_file(link, local);
+}
+
+/// Writes the original content of a downloaded file, undoing encryption and compression
+pub fn decode_content<R: Read, W: Write>(keys: Option<&Keys>, remote_file: &RemoteFile, mut read: R, write: W) -> std::io::Result<()> {
+    let mut write: Box<dyn Write> = match remote_file.file_info.get(files::INFO_CODEC) {
+        Some(c) if c == ::storage::compression::CODEC_ZSTD => Box::new(zstd::stream::write::Decoder::new(write)?),
+        Some(c) => return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, format!("Unknown codec '{}'", c))),
+        None => Box::new(write),
+    };
+    match keys {
+        Some(k) if remote_file.file_info.contains_key(files::INFO_ENCRYPTED) => k.decrypt_stream(read, &mut write)?,
+        None if remote_file.file_info.contains_key(files::INFO_ENCRYPTED) => {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "The file is encrypted"));
+        },
+        _ => {
+            std::io::copy(&mut read, &mut write)?;
+        },
+    }
+    write.flush()
+}
@@ -0,0 +1,73 @@
+use raze::engine::engine::Raze;
+use storage::storage::{PersistentData, SymlinkPolicy};
+use procedures::error::ProcedureError;
+use formatting::size_formatter::format_bytes;
+
+/// Makes the bucket with the given name the one used for backups
+///
+/// Buckets are looked up by name, but the id is what gets stored
+pub fn set_bucket(raze: &mut Raze, persistent_data: &mut PersistentData, name: &str) -> Result<(), ProcedureError> {
+    persistent_data.set_bucket(find_bucket(raze, name)?);
+    persistent_data.save_to_file(&::config().data_file()).map_err(ProcedureError::SaveFailed)?;
+    println!("Now backing up to '{}'", name);
+    if ::config().bucket.is_some() {
+        println!("The configuration sets a bucket as well, which is used again the next time the program starts");
+    }
+    Ok(())
+}
+
+/// Looks up the id of the bucket with the given name
+pub fn find_bucket(raze: &mut Raze, name: &str) -> Result<String, ProcedureError> {
+    let buckets = match raze.list_buckets() {
+        Ok(v) => v,
+        Err(e) => return Err(ProcedureError::B2Error(e)),
+    };
+    match buckets.into_iter().find(|b| b.bucket_name == name) {
+        Some(bucket) => Ok(bucket.bucket_id),
+        None => Err(ProcedureError::UnknownBucket(name.to_owned())),
+    }
+}
+
+/// Sets the maximum amount of bytes per second sent while uploading
+///
+/// 0 disables throttling. Since the bandwidth is split between the upload threads,
+/// anything lower than transfer.upload_threads is raised to that
+pub fn set_throttle(persistent_data: &mut PersistentData, amount: usize) -> Result<(), ProcedureError> {
+    let amount = match amount {
+        n if n > ::config().transfer.upload_threads || n == 0 => n,
+        _ => {
+            println!("Input too low -- defaulting to minimum");
+            ::config().transfer.upload_threads
+        }
+    };
+    match amount {
+        0 => println!("Uploads will not be throttled"),
+        n => println!("Uploads will be throttled to {}/sec", format_bytes(n as u64)),
+    }
+    persistent_data.set_bandwidth_limit(amount);
+    persistent_data.save_to_file(&::config().data_file()).map_err(ProcedureError::SaveFailed)?;
+    Ok(())
+}
+
+/// Sets the largest percentage of the stored files purge hides without being forced
+pub fn set_purge_threshold(persistent_data: &mut PersistentData, percent: u8) -> Result<(), ProcedureError> {
+    match percent {
+        100 => println!("Purge will hide any number of files"),
+        n => println!("Purge will refuse to hide more than {}% of the stored files without '--force'", n),
+    }
+    persistent_data.purge_threshold = percent;
+    persistent_data.save_to_file(&::config().data_file()).map_err(ProcedureError::SaveFailed)?;
+    Ok(())
+}
+
+/// Sets what backups do with symbolic links found while walking the backup list
+pub fn set_symlinks(persistent_data: &mut PersistentData, policy: SymlinkPolicy) -> Result<(), ProcedureError> {
+    match policy {
+        SymlinkPolicy::Skip => println!("Symbolic links will be left out of backups"),
+        SymlinkPolicy::Follow => println!("Symbolic links will be followed, except those pointing back to a folder containing them"),
+        SymlinkPolicy::Store => println!("Symbolic links will be backed up as links, restore recreates them"),
+    }
+    persistent_data.symlinks = policy;
+    persistent_data.save_to_file(&::config().data_file()).map_err(ProcedureError::SaveFailed)?;
+    Ok(())
+}
@@ -0,0 +1,29 @@
+use formatting::size_formatter::format_bytes;
+use formatting::time_formatter::format_timestamp;
+use storage::storage as storage_helper;
+use remote::session::Session;
+use procedures::error::ProcedureError;
+
+/// The 'snapshots' command, lists the snapshot made by every backup along with its files and their size
+pub fn list_snapshots(session: &Session, persistent_data: &mut storage_helper::PersistentData) -> Result<(), ProcedureError> {
+    // Verify that a bucket is selected
+    if persistent_data.active_bucket.is_empty() {
+        return Err(ProcedureError::NoBucketSelected)
+    }
+    let keys = ::procedures::repository::open_repository(session, &persistent_data.active_bucket)?;
+    let list = ::procedures::repository::list_manifests(session, &persistent_data.active_bucket)?;
+    if list.is_empty() {
+        println!("There are no snapshots in this bucket yet, every backup makes one");
+        return Ok(())
+    }
+
+    println!("{:<24}{:<22}{:>10}{:>14}", "Snapshot", "Time", "Files", "Size");
+    for remote_file in &list {
+        let manifest = ::procedures::repository::read_manifest(session, remote_file, keys.as_ref())?;
+        println!("{:<24}{:<22}{:>10}{:>14}", manifest.id(), format_timestamp(manifest.time),
+                 manifest.files.len(), format_bytes(manifest.total_size()));
+    }
+    println!();
+    println!("Use 'restore --snapshot <snapshot>' to restore the files as they were at that time");
+    Ok(())
+}
@@ -0,0 +1,101 @@
+use storage::storage as storage_helper;
+use storage::index::UploadIndex;
+use formatting::size_formatter::format_bytes;
+use formatting::time_formatter::format_timestamp;
+use remote::session::Session;
+use remote::files::{self, RemoteFile};
+use procedures::error::ProcedureError;
+
+/// Options for the 'unhide' command
+#[derive(Debug, PartialEq, Default)]
+pub struct UnhideOptions {
+    /// Only files hidden at or after this time, in seconds since the epoch
+    pub since: Option<i64>,
+    /// Only files hidden at or before this time, in seconds since the epoch
+    pub until: Option<i64>,
+    /// Only files hidden by the last purge
+    pub last_purge: bool,
+    /// Only list the files that would be brought back
+    pub dry_run: bool,
+}
+
+/// The 'unhide' command, brings back files hidden by purge
+///
+/// Hiding a file only adds a hide marker as its newest version. Deleting the marker makes the version
+/// before it the current one again, so nothing has to be uploaded. Files that were deleted for good,
+/// eg. by 'delete_hidden', can't be brought back
+pub fn unhide_files(session: &Session, persistent_data: &mut storage_helper::PersistentData, options: UnhideOptions) -> Result<(), ProcedureError> {
+    // Verify that a bucket is selected
+    if persistent_data.active_bucket.is_empty() {
+        return Err(ProcedureError::NoBucketSelected)
+    }
+    let (mut since, mut until) = (options.since, options.until);
+    if options.last_purge {
+        let run = match persistent_data.last_purge {
+            Some(v) => v,
+            None => return Err(ProcedureError::Refused("No purge has hidden anything yet, there is nothing to undo".to_owned())),
+        };
+        println!("The last purge ran from {} to {}", format_timestamp(run.start), format_timestamp(run.end));
+        since = Some(since.map_or(run.start, |s| s.max(run.start)));
+        until = Some(until.map_or(run.end, |u| u.min(run.end)));
+    }
+    let keys = ::procedures::repository::open_repository(session, &persistent_data.active_bucket)?;
+
+    println!("Retrieving every file version, this may take a bit...");
+    let versions = match files::list_all_file_versions(session, &persistent_data.active_bucket, "", 1000) {
+        Ok(v) => v,
+        Err(e) => return Err(ProcedureError::B2Error(e)),
+    };
+    // Versions of the same name are listed together, newest first
+    // A file is hidden when its newest version is a hide marker, hiding it again stacks another marker on top
+    let mut markers: Vec<RemoteFile> = Vec::new();
+    let mut restored: Vec<RemoteFile> = Vec::new();
+    for group in versions.chunk_by(|a, b| a.file_name == b.file_name) {
+        if group[0].action != "hide" || group[0].file_name.starts_with(::REPOSITORY_PREFIX) {
+            continue;
+        }
+        let hidden_at = (group[0].upload_timestamp / 1000) as i64;
+        if since.is_some_and(|s| hidden_at < s) || until.is_some_and(|u| hidden_at > u) {
+            continue;
+        }
+        // Without an earlier upload there is nothing to bring back
+        let previous = match group.iter().find(|v| v.action == "upload") {
+            Some(v) => v,
+            None => continue,
+        };
+        markers.extend(group.iter().take_while(|v| v.action == "hide").cloned());
+        restored.push(previous.clone());
+    }
+
+    let size = restored.iter().fold(0, |acc, v| acc + v.content_length);
+    ::procedures::prune::list_versions(keys.as_ref(), &restored, options.dry_run);
+    println!("{} hidden files would be brought back, {} in total", restored.len(), format_bytes(size));
+    if options.dry_run {
+        println!("Dry run, nothing was brought back");
+        return Ok(())
+    }
+    if markers.is_empty() {
+        return Ok(())
+    }
+
+    println!("Removing hide markers...");
+    let failed = ::procedures::prune::delete_versions(session, &markers);
+
+    // The index only tracks current files, so the restored ones go back in
+    // Without a usable index there is nothing to update, the next backup builds one from the bucket
+    if let Ok(mut index) = UploadIndex::from_file(&::config().index_file()) {
+        if index.bucket_id == persistent_data.active_bucket {
+            let current = UploadIndex::from_remote_files(&persistent_data.active_bucket, restored);
+            index.files.extend(current.files);
+            if let Err(e) = index.save_to_file(&::config().index_file()) {
+                println!("Can't save {}: {}, run 'rebuild_index' to bring the index back in sync", ::config().index_file().display(), e);
+            }
+        }
+    }
+    if failed > 0 {
+        println!("Unhide completed, but {} hide markers could not be removed, run 'rebuild_index' to bring the index back in sync", failed);
+        return Err(ProcedureError::Incomplete(failed))
+    }
+    println!("Unhide successfully completed");
+    Ok(())
+}
@@ -0,0 +1,223 @@
+use std;
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use scoped_pool::Pool;
+use progress;
+use rand;
+use formatting::size_formatter::format_bytes;
+use storage::storage::{self as storage_helper, EntryKind};
+use remote::session::Session;
+use procedures::restore::Source;
+use procedures::error::ProcedureError;
+
+/// Options for the 'verify' command
+#[derive(Debug, PartialEq, Default)]
+pub struct VerifyOptions {
+    /// Download this many randomly picked files and check their content
+    pub sample: usize,
+    /// Download every file and check its content
+    pub all: bool,
+}
+
+/// The 'verify' command, checks that everything in the backup list is intact in the bucket
+///
+/// Every local file is compared to what is stored for it by size and SHA-1, without downloading anything.
+/// Files changed or added since the last backup are listed, but aren't counted as problems. \
+/// With 'sample' or 'all', stored files are also downloaded and their content checked against the hash
+/// recorded when they were uploaded, which catches damage the listing can't show
+pub fn verify_backup(session: &Session, persistent_data: &mut storage_helper::PersistentData, options: VerifyOptions) -> Result<(), ProcedureError> {
+    // Verify that a bucket is selected
+    if persistent_data.active_bucket.is_empty() {
+        return Err(ProcedureError::NoBucketSelected)
+    }
+    println!("Verify start");
+    let keys = ::procedures::repository::open_repository(session, &persistent_data.active_bucket)?;
+
+    println!("Retrieving list of backed up files...");
+    let mut skipped = 0;
+    let chunked = ::procedures::repository::read_chunking_config(session, &persistent_data.active_bucket)?.is_some();
+    let backed_up = if chunked {
+        match ::procedures::repository::latest_manifest(session, &persistent_data.active_bucket, keys.as_ref())? {
+            Some(manifest) => ::procedures::restore::snapshot_files(session, &persistent_data.active_bucket, manifest, &mut skipped)?,
+            None => Vec::new(),
+        }
+    } else {
+        ::procedures::restore::whole_files(session, &persistent_data.active_bucket, keys.as_ref(), &mut skipped)?
+    };
+    if skipped > 0 {
+        println!("{} stored files can't be read and are left out", skipped);
+    }
+    let mut stored: BTreeMap<String, Source> = backed_up.into_iter().collect();
+
+    println!("Constructing file list");
+    let file_list = ::procedures::backup::backup_file_list(persistent_data.symlinks)?;
+    // Anything modified after the last backup started can't be in the bucket yet
+    // Data saved before the start was recorded only has the end, which is all there is to go by
+    let last_backup = match persistent_data.last_backup_start {
+        0 => persistent_data.last_backup,
+        start => start,
+    } as u64 * 1000;
+
+    println!("Comparing {} files to the bucket...", file_list.len());
+    let mut missing = Vec::new();
+    let mut different = Vec::new();
+    let mut changed = Vec::new();
+    let mut to_download: Vec<(String, Source)> = Vec::new();
+    for file in &file_list {
+        let (size, modified) = match ::procedures::backup::entry_stats(file) {
+            Ok(v) => v,
+            Err(e) => {
+                // Deleted or made unreadable since the list was made, which says nothing about the bucket
+                stored.remove(&file.name);
+                changed.push(format!("{} (can't be read: {})", file.name, e));
+                continue;
+            },
+        };
+        let source = match stored.remove(&file.name) {
+            Some(v) => v,
+            None if modified > last_backup => {
+                changed.push(format!("{} (new)", file.name));
+                continue;
+            },
+            None => {
+                missing.push(file.name.clone());
+                continue;
+            },
+        };
+        let problem = match file.kind {
+            EntryKind::File if source.symlink(keys.as_ref()).is_some() => Some("stored as a link".to_owned()),
+            EntryKind::File if size != source.size() => Some(format!("{} locally, {} stored", size, source.size())),
+            EntryKind::File if source.sha1() != "none" => match storage_helper::sha1_file(&file.path) {
+                Ok(ref hash) if *hash == source.sha1() => None,
+                Ok(_) => Some("content differs".to_owned()),
+                Err(e) => Some(format!("can't be read: {}", e)),
+            },
+            EntryKind::Symlink(ref target) => match source.symlink(keys.as_ref()) {
+                Some(ref link) if *link == target.to_string_lossy() => None,
+                Some(link) => Some(format!("points to {} locally, {} stored", target.display(), link)),
+                None => Some("stored as a file".to_owned()),
+            },
+            _ => None,
+        };
+        // A file changed since it was backed up is expected to differ
+        let backed_up_at = match source {
+            Source::File(ref remote_file) => remote_file.upload_timestamp,
+            Source::Chunks(ref manifest_file, _) => manifest_file.modified,
+        };
+        match problem {
+            Some(_) if modified > backed_up_at => changed.push(format!("{} (modified)", file.name)),
+            Some(p) => different.push(format!("{}: {}", file.name, p)),
+            None => (),
+        }
+        if file.kind == EntryKind::File && source.symlink(keys.as_ref()).is_none() {
+            to_download.push((file.name.clone(), source));
+        }
+    }
+    // Whatever is left is stored, but no longer in the backup list
+    let orphans: Vec<String> = stored.into_keys().collect();
+
+    // Only downloading shows whether what's stored still matches the hash it was uploaded with
+    if !options.all {
+        let count = options.sample.min(to_download.len());
+        let picked: BTreeSet<usize> = rand::seq::index::sample(&mut rand::thread_rng(), to_download.len(), count).into_iter().collect();
+        to_download = to_download.into_iter().enumerate()
+            .filter(|(i, _)| picked.contains(i))
+            .map(|(_, file)| file)
+            .collect();
+    }
+    let damaged = if to_download.is_empty() {
+        Vec::new()
+    } else {
+        download_files(session, keys.as_ref(), &to_download)
+    };
+
+    print_section("Missing from the bucket", &missing);
+    print_section("Different from the bucket", &different);
+    print_section("Damaged in the bucket", &damaged);
+    print_section("Changed or added since the last backup, not a problem", &changed);
+    if !chunked {
+        print_section("Only in the bucket, 'purge' hides these", &orphans);
+    } else {
+        print_section("Only in the last snapshot, deleted locally since", &orphans);
+    }
+    println!("Checked {} files, downloaded {}", file_list.len(), to_download.len());
+    println!("{} missing, {} different, {} damaged, {} changed since the last backup, {} only in the bucket",
+             missing.len(), different.len(), damaged.len(), changed.len(), orphans.len());
+    let problems = missing.len() + different.len() + damaged.len();
+    if problems > 0 {
+        return Err(ProcedureError::VerifyFailed(problems))
+    }
+    println!("Verify successfully completed, the backup is intact");
+    Ok(())
+}
+
+// Downloads files without keeping them, returning the ones whose content doesn't match their hash
+fn download_files(session: &Session, keys: Option<&::storage::crypto::Keys>, files: &[(String, Source)]) -> Vec<String> {
+    let total_size = files.iter().fold(0, |acc, f| acc + f.1.size());
+    println!("Downloading {} across {} files to check their content", format_bytes(total_size), files.len());
+    let bar = Arc::new(Mutex::new(progress::Bar::new()));
+    let finished_downloads = Arc::new(Mutex::new(0));
+    let damaged = Arc::new(Mutex::new(Vec::new()));
+
+    let pool = Pool::new(::config().transfer.download_threads);
+    pool.scoped(|scope| {
+        for (name, source) in files {
+            let fin_downloads = finished_downloads.clone();
+            let damaged = damaged.clone();
+
+            // Every file gets a few attempts, see transfer.retries, in case they fail for any reason
+            scope.execute(move || {
+                for attempts in 0..::config().transfer.retries {
+                    let mut write = storage_helper::HashingWriter::new(std::io::sink());
+                    match ::procedures::restore::download_content(session, keys, source, &mut write) {
+                        Ok(expected) => {
+                            // Large files have no whole-file hash, so there is nothing to compare against
+                            if expected != "none" && expected != write.digest() {
+                                damaged.lock().unwrap().push(format!("{}: content doesn't match its hash", name));
+                            }
+                            break
+                        },
+                        Err(e) => {
+                            if attempts + 1 == ::config().transfer.retries {
+                                damaged.lock().unwrap().push(format!("{}: can't be downloaded, {}", name, e));
+                            }else{
+                                // Sleep for a bit before retrying
+                                std::thread::sleep(Duration::from_secs(::config().transfer.retry_delay));
+                            }
+                        },
+                    }
+                }
+                *fin_downloads.lock().unwrap() += 1;
+            });
+        }
+
+        // Start the loop that prints the progress bar and checks if we're done yet
+        let progress_bar = bar.clone();
+        progress_bar.lock().unwrap().set_job_title("Verify in progress");
+        loop {
+            std::thread::sleep(Duration::from_millis(1000));
+            let data = *finished_downloads.lock().unwrap();
+            progress_bar.lock().unwrap().set_job_title(&format!("Verify in progress ({}/{})", data, files.len()));
+            progress_bar.lock().unwrap().reach_percent((((data as f64) / (files.len() as f64)) * 100.) as i32);
+            if data == files.len() {
+                break;
+            }
+        }
+    });
+    println!();
+    let mut damaged = damaged.lock().unwrap().clone();
+    damaged.sort();
+    damaged
+}
+
+// Prints a part of the report, leaving it out if there is nothing in it
+fn print_section(title: &str, lines: &[String]) {
+    if lines.is_empty() {
+        return;
+    }
+    println!("{} ({}):", title, lines.len());
+    for line in lines {
+        println!("    {}", line);
+    }
+}
@@ -0,0 +1,399 @@
+use std;
+use std::io::Read;
+use std::collections::BTreeMap;
+use reqwest;
+use raze;
+use raze::{B2Error, B2ApiError};
+use raze::api::files::structs::UploadAuth;
+use serde;
+use serde_json;
+use remote::session::Session;
+use storage::storage::HashingReader;
+
+// Custom file info set on files that aren't uploaded as is
+// Whether the content is encrypted, see storage::crypto
+pub const INFO_ENCRYPTED: &str = "raze-encrypted";
+// The compression codec, see storage::compression
+pub const INFO_CODEC: &str = "raze-codec";
+// Size and SHA-1 of the original content
+pub const INFO_SIZE: &str = "raze-size";
+pub const INFO_SHA1: &str = "raze-sha1";
+// The SHA-1 of a large file uploaded as is, named the way B2 recommends so other tools find it as well
+pub const INFO_LARGE_FILE_SHA1: &str = "large_file_sha1";
+// Permissions, ownership and modification time, and extended attributes, see storage::metadata
+// Encrypted like the name in encrypted buckets
+pub const INFO_POSIX: &str = "raze-posix";
+pub const INFO_XATTRS: &str = "raze-xattrs";
+// Where a stored symbolic link points, its content is empty
+// Encrypted like the name in encrypted buckets
+pub const INFO_SYMLINK: &str = "raze-symlink";
+
+// Given as the SHA-1 of an upload when the hash follows the content, see AppendSha1
+pub const SHA1_AT_END: &str = "hex_digits_at_end";
+// The number of bytes that adds to the upload
+pub const SHA1_AT_END_LEN: u64 = 40;
+
+// The longest file name B2 accepts, in bytes of UTF-8
+pub const MAX_FILE_NAME_LEN: usize = 1024;
+
+/// A file version as returned by B2, including the custom file info raze leaves out of StoredFile
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct RemoteFile {
+    pub file_id: String,
+    pub file_name: String,
+    pub content_length: u64,
+    /// "none" when B2 has no hash, like for large files and hide markers
+    #[serde(default, deserialize_with = "null_as_none")]
+    pub content_sha1: String,
+    /// "upload" for regular versions, "hide" for hide markers and "start" for unfinished large files
+    pub action: String,
+    pub upload_timestamp: u64,
+    /// Custom 'X-Bz-Info-*' values given when the file was uploaded
+    #[serde(default)]
+    pub file_info: BTreeMap<String, String>,
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+/// Request body used for the [b2_list_file_names](https://www.backblaze.com/b2/docs/b2_list_file_names.html) call
+struct ListFileNamesBody<'a> {
+    bucket_id: &'a str,
+    start_file_name: &'a str,
+    prefix: &'a str,
+    max_file_count: usize,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+/// Response of the [b2_list_file_names](https://www.backblaze.com/b2/docs/b2_list_file_names.html) call
+pub struct RemoteFileList {
+    pub files: Vec<RemoteFile>,
+    pub next_file_name: Option<String>,
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+/// Request body used for the [b2_list_file_versions](https://www.backblaze.com/b2/docs/b2_list_file_versions.html) call
+struct ListFileVersionsBody<'a> {
+    bucket_id: &'a str,
+    start_file_name: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    start_file_id: Option<&'a str>,
+    prefix: &'a str,
+    max_file_count: usize,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+/// Response of the [b2_list_file_versions](https://www.backblaze.com/b2/docs/b2_list_file_versions.html) call
+pub struct RemoteFileVersionList {
+    pub files: Vec<RemoteFile>,
+    pub next_file_name: Option<String>,
+    pub next_file_id: Option<String>,
+}
+
+/// Lists up to 'max_file_count' files in the bucket, starting at 'start_file_name'
+///
+/// Only files whose names start with 'prefix' are listed, use "" for every file
+///
+/// Official documentation: [b2_list_file_names](https://www.backblaze.com/b2/docs/b2_list_file_names.html)
+pub fn list_file_names(session: &Session, bucket_id: &str, start_file_name: &str, prefix: &str, max_file_count: usize) -> Result<RemoteFileList, B2Error> {
+    let json = match serde_json::to_string(&ListFileNamesBody {
+        bucket_id,
+        start_file_name,
+        prefix,
+        max_file_count,
+    }) {
+        Ok(v) => v,
+        Err(e) => return Err(B2Error::SerdeError(e))
+    };
+    session.call(|auth| {
+        let mut resp = match session.client.post(&format!("{}/b2api/v1/b2_list_file_names", auth.api_url))
+            .header(reqwest::header::Authorization(auth.authorization_token.clone()))
+            .body(reqwest::Body::from(json.clone()))
+            .send() {
+            Ok(v) => v,
+            Err(e) => return Err(B2Error::ReqwestError(e))
+        };
+        if !resp.status().is_success() {
+            return Err(error_from_response(resp))
+        }
+        let response_string = match resp.text() {
+            Ok(v) => v,
+            Err(e) => return Err(B2Error::ReqwestError(e)),
+        };
+        match serde_json::from_str(&response_string) {
+            Ok(v) => Ok(v),
+            Err(e) => Err(B2Error::SerdeError(e)),
+        }
+    })
+}
+
+/// Like [list_file_names](fn.list_file_names.html), but repeats until every file has been listed
+pub fn list_all_file_names(session: &Session, bucket_id: &str, prefix: &str, max_file_count: usize) -> Result<Vec<RemoteFile>, B2Error> {
+    let mut files = Vec::new();
+    let mut start = String::new();
+    loop {
+        let mut list = list_file_names(session, bucket_id, &start, prefix, max_file_count)?;
+        files.append(&mut list.files);
+        match list.next_file_name {
+            Some(v) => start = v,
+            None => return Ok(files),
+        }
+    }
+}
+
+/// Lists up to 'max_file_count' file versions in the bucket, starting at 'start_file_name' and 'start_file_id'
+///
+/// Unlike list_file_names this includes old versions and hide markers, newest version of each name first
+///
+/// Official documentation: [b2_list_file_versions](https://www.backblaze.com/b2/docs/b2_list_file_versions.html)
+pub fn list_file_versions(session: &Session, bucket_id: &str, start_file_name: &str, start_file_id: Option<&str>,
+                          prefix: &str, max_file_count: usize) -> Result<RemoteFileVersionList, B2Error> {
+    let json = match serde_json::to_string(&ListFileVersionsBody {
+        bucket_id,
+        start_file_name,
+        start_file_id,
+        prefix,
+        max_file_count,
+    }) {
+        Ok(v) => v,
+        Err(e) => return Err(B2Error::SerdeError(e))
+    };
+    session.call(|auth| {
+        let mut resp = match session.client.post(&format!("{}/b2api/v1/b2_list_file_versions", auth.api_url))
+            .header(reqwest::header::Authorization(auth.authorization_token.clone()))
+            .body(reqwest::Body::from(json.clone()))
+            .send() {
+            Ok(v) => v,
+            Err(e) => return Err(B2Error::ReqwestError(e))
+        };
+        if !resp.status().is_success() {
+            return Err(error_from_response(resp))
+        }
+        let response_string = match resp.text() {
+            Ok(v) => v,
+            Err(e) => return Err(B2Error::ReqwestError(e)),
+        };
+        match serde_json::from_str(&response_string) {
+            Ok(v) => Ok(v),
+            Err(e) => Err(B2Error::SerdeError(e)),
+        }
+    })
+}
+
+/// Like [list_file_versions](fn.list_file_versions.html), but repeats until every version has been listed
+pub fn list_all_file_versions(session: &Session, bucket_id: &str, prefix: &str, max_file_count: usize) -> Result<Vec<RemoteFile>, B2Error> {
+    let mut files = Vec::new();
+    let mut start_name = String::new();
+    let mut start_id: Option<String> = None;
+    loop {
+        let mut list = list_file_versions(session, bucket_id, &start_name, start_id.as_ref().map(|s| s.as_ref()), prefix, max_file_count)?;
+        files.append(&mut list.files);
+        match list.next_file_name {
+            Some(v) => {
+                start_name = v;
+                start_id = list.next_file_id;
+            },
+            None => return Ok(files),
+        }
+    }
+}
+
+/// Gets an upload url and token for the bucket, see [raze's get_upload_url](../../raze/api/files/misc/fn.get_upload_url.html)
+pub fn get_upload_url(session: &Session, bucket_id: &str) -> Result<UploadAuth, B2Error> {
+    session.call(|auth| raze::api::files::misc::get_upload_url(&session.client, auth, bucket_id))
+}
+
+/// Deletes a single version of a file, see [raze's delete_file_version](../../raze/api/files/misc/fn.delete_file_version.html)
+///
+/// Hide markers are versions too, deleting one makes the version before it current again
+pub fn delete_file_version(session: &Session, file_name: &str, file_id: &str) -> Result<(), B2Error> {
+    session.call(|auth| raze::api::files::misc::delete_file_version(&session.client, auth, file_name.to_owned(), file_id.to_owned()))
+        .map(|_| ())
+}
+
+/// Uploads 'len' bytes from 'read' as a file with exactly the given name
+///
+/// Unlike the engine uploads, the name isn't derived from a local path and the content
+/// doesn't have to be a file on disk. 'info' is stored as the file's custom info \
+/// If 'bandwidth' isn't 0, the upload is limited to that many bytes per second
+///
+/// Official documentation: [b2_upload_file](https://www.backblaze.com/b2/docs/b2_upload_file.html)
+pub fn upload_file<R: Read + Send + 'static>(session: &Session, upload_auth: &UploadAuth, file_name: &str, read: R, len: u64,
+                                             sha1: &str, info: &BTreeMap<String, String>) -> Result<RemoteFile, B2Error> {
+    let mut headers = reqwest::header::Headers::new();
+    headers.set(reqwest::header::Authorization(upload_auth.authorization_token.clone()));
+    headers.set_raw("X-Bz-File-Name", percent_encode(file_name));
+    // b2/x-auto has B2 pick the MIME type based on the file extension
+    headers.set_raw("Content-Type", "b2/x-auto");
+    headers.set_raw("X-Bz-Content-Sha1", sha1.to_owned());
+    for (key, value) in info {
+        headers.set_raw(format!("X-Bz-Info-{}", key), percent_encode(value));
+    }
+    let mut resp = match session.client.post(&upload_auth.upload_url)
+        .headers(headers)
+        .body(reqwest::Body::sized(read, len))
+        .send() {
+        Ok(v) => v,
+        Err(e) => return Err(B2Error::ReqwestError(e))
+    };
+    if !resp.status().is_success() {
+        return Err(error_from_response(resp))
+    }
+    let response_string = match resp.text() {
+        Ok(v) => v,
+        Err(e) => return Err(B2Error::ReqwestError(e)),
+    };
+    match serde_json::from_str(&response_string) {
+        Ok(v) => Ok(v),
+        Err(e) => Err(B2Error::SerdeError(e)),
+    }
+}
+
+/// Downloads the file version with the given id
+///
+/// Returns the response, which can be read for the file contents
+///
+/// Official documentation: [b2_download_file_by_id](https://www.backblaze.com/b2/docs/b2_download_file_by_id.html)
+pub fn download_file_by_id(session: &Session, file_id: &str) -> Result<reqwest::Response, B2Error> {
+    session.call(|auth| {
+        let mut headers = reqwest::header::Headers::new();
+        headers.set(reqwest::header::Authorization(auth.authorization_token.clone()));
+        let resp = match session.client.get(&format!("{}/b2api/v1/b2_download_file_by_id?fileId={}", auth.download_url, file_id))
+            .headers(headers)
+            .send() {
+            Ok(v) => v,
+            Err(e) => return Err(B2Error::ReqwestError(e))
+        };
+        if !resp.status().is_success() {
+            return Err(error_from_response(resp))
+        }
+        Ok(resp)
+    })
+}
+
+/// Downloads 'length' bytes starting at 'offset' of the file version with the given id
+///
+/// Official documentation: [b2_download_file_by_id](https://www.backblaze.com/b2/docs/b2_download_file_by_id.html)
+pub fn download_file_range(session: &Session, file_id: &str, offset: u64, length: u64) -> Result<reqwest::Response, B2Error> {
+    session.call(|auth| {
+        let mut headers = reqwest::header::Headers::new();
+        headers.set(reqwest::header::Authorization(auth.authorization_token.clone()));
+        headers.set_raw("Range", format!("bytes={}-{}", offset, offset + length - 1));
+        let resp = match session.client.get(&format!("{}/b2api/v1/b2_download_file_by_id?fileId={}", auth.download_url, file_id))
+            .headers(headers)
+            .send() {
+            Ok(v) => v,
+            Err(e) => return Err(B2Error::ReqwestError(e))
+        };
+        if !resp.status().is_success() {
+            return Err(error_from_response(resp))
+        }
+        Ok(resp)
+    })
+}
+
+/// Turns an unsuccessful response into a B2Error, like raze does internally
+pub fn error_from_response(mut resp: reqwest::Response) -> B2Error {
+    let response_string = match resp.text() {
+        Ok(v) => v,
+        Err(e) => return B2Error::ReqwestError(e),
+    };
+    match serde_json::from_str::<B2ApiError>(&response_string) {
+        Ok(v) => B2Error::B2Error(v),
+        Err(e) => B2Error::SerdeError(e),
+    }
+}
+
+// Reads a string that may be null, as "none"
+fn null_as_none<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<String, D::Error> {
+    use serde::Deserialize;
+    Ok(Option::<String>::deserialize(deserializer)?.unwrap_or_else(|| "none".to_owned()))
+}
+
+// Percent-encodes a file name or info value for use in a header, as B2 requires
+// '/' is left alone since it's how B2 names emulate folders
+fn percent_encode(value: &str) -> String {
+    let mut encoded = String::new();
+    for byte in value.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' | b'/' => encoded.push(byte as char),
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    encoded
+}
+
+/// Limits reads to 'bandwidth' bytes per second by sleeping after each one, like raze's throttled upload
+///
+/// A bandwidth of 0 doesn't limit anything
+pub struct ThrottledRead<R: Read> {
+    inner: R,
+    bandwidth: usize,
+}
+
+impl<R: Read> ThrottledRead<R> {
+    pub fn new(inner: R, bandwidth: usize) -> ThrottledRead<R> {
+        ThrottledRead {
+            inner,
+            bandwidth,
+        }
+    }
+}
+
+impl<R: Read> Read for ThrottledRead<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let max = std::cmp::min(buf.len(), 2048);
+        let len = self.inner.read(&mut buf[..max])?;
+        if self.bandwidth == 0 {
+            return Ok(len);
+        }
+        let usage = (len as f64)/(self.bandwidth as f64);
+        std::thread::sleep(std::time::Duration::from_millis((usage*1000.) as u64));
+        Ok(len)
+    }
+}
+
+/// Appends the SHA-1 of everything read, as hex, once the inner reader is done
+///
+/// Used for uploads with a SHA-1 of SHA1_AT_END, so a file doesn't have to be read twice.
+/// The upload length must include the extra SHA1_AT_END_LEN bytes
+pub struct AppendSha1<R: Read> {
+    inner: HashingReader<R>,
+    digest: Option<Vec<u8>>,
+    position: usize,
+}
+
+impl<R: Read> AppendSha1<R> {
+    pub fn new(inner: R) -> AppendSha1<R> {
+        AppendSha1 {
+            inner: HashingReader::new(inner),
+            digest: None,
+            position: 0,
+        }
+    }
+}
+
+impl<R: Read> Read for AppendSha1<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.digest.is_none() {
+            let len = self.inner.read(buf)?;
+            if len > 0 || buf.is_empty() {
+                return Ok(len);
+            }
+            self.digest = Some(self.inner.digest().into_bytes());
+        }
+        let digest = self.digest.as_ref().unwrap();
+        let len = std::cmp::min(buf.len(), digest.len() - self.position);
+        buf[..len].copy_from_slice(&digest[self.position..self.position + len]);
+        self.position += len;
+        Ok(len)
+    }
+}
+
+#[test]
+fn test_percent_encode() {
+    assert_eq!(percent_encode("home/me/My Files/ä.txt"), "home/me/My%20Files/%C3%A4.txt");
+}
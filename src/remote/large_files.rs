@@ -0,0 +1,233 @@
+use std::io::Read;
+use std::collections::BTreeMap;
+use reqwest;
+use serde;
+use serde_json;
+use raze::B2Error;
+use remote::session::Session;
+use remote::files::{self, RemoteFile};
+
+/// A large file that was started, but not finished or cancelled yet
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct UnfinishedFile {
+    pub file_id: String,
+    pub file_name: String,
+    pub upload_timestamp: u64,
+    #[serde(default)]
+    pub file_info: BTreeMap<String, String>,
+}
+
+/// An uploaded part of a large file
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct Part {
+    pub part_number: u32,
+    pub content_length: u64,
+    pub content_sha1: String,
+}
+
+/// Url and token for uploading parts of a large file
+///
+/// Like UploadAuth, every thread needs its own
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct UploadPartAuth {
+    pub file_id: String,
+    pub upload_url: String,
+    pub authorization_token: String,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+/// Request body used for the [b2_start_large_file](https://www.backblaze.com/b2/docs/b2_start_large_file.html) call
+struct StartLargeFileBody<'a> {
+    bucket_id: &'a str,
+    file_name: &'a str,
+    content_type: &'a str,
+    file_info: &'a BTreeMap<String, String>,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+/// Request body for the calls that only need a file id
+struct FileIdBody<'a> {
+    file_id: &'a str,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+/// Request body used for the [b2_list_parts](https://www.backblaze.com/b2/docs/b2_list_parts.html) call
+struct ListPartsBody<'a> {
+    file_id: &'a str,
+    start_part_number: u32,
+    max_part_count: usize,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+/// Request body used for the [b2_finish_large_file](https://www.backblaze.com/b2/docs/b2_finish_large_file.html) call
+struct FinishLargeFileBody<'a> {
+    file_id: &'a str,
+    part_sha1_array: &'a [String],
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+/// Request body used for the [b2_list_unfinished_large_files](https://www.backblaze.com/b2/docs/b2_list_unfinished_large_files.html) call
+struct ListUnfinishedBody<'a> {
+    bucket_id: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    start_file_id: Option<&'a str>,
+    max_file_count: usize,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct PartList {
+    parts: Vec<Part>,
+    next_part_number: Option<u32>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct UnfinishedFileList {
+    files: Vec<UnfinishedFile>,
+    next_file_id: Option<String>,
+}
+
+/// Prepares for uploading the parts of a large file
+///
+/// Official documentation: [b2_start_large_file](https://www.backblaze.com/b2/docs/b2_start_large_file.html)
+pub fn start_large_file(session: &Session, bucket_id: &str, file_name: &str, info: &BTreeMap<String, String>) -> Result<UnfinishedFile, B2Error> {
+    api_call(session, "b2_start_large_file", &StartLargeFileBody {
+        bucket_id,
+        file_name,
+        // b2/x-auto has B2 pick the MIME type based on the file extension
+        content_type: "b2/x-auto",
+        file_info: info,
+    })
+}
+
+/// Gets an url and token for uploading parts of the large file
+///
+/// Official documentation: [b2_get_upload_part_url](https://www.backblaze.com/b2/docs/b2_get_upload_part_url.html)
+pub fn get_upload_part_url(session: &Session, file_id: &str) -> Result<UploadPartAuth, B2Error> {
+    api_call(session, "b2_get_upload_part_url", &FileIdBody {
+        file_id,
+    })
+}
+
+/// Uploads 'len' bytes from 'read' as part number 'part_number', starting at 1
+///
+/// Every part but the last must be at least absolute_minimum_part_size bytes \
+/// Wrap 'read' in a ThrottledRead to limit the upload speed
+///
+/// Official documentation: [b2_upload_part](https://www.backblaze.com/b2/docs/b2_upload_part.html)
+pub fn upload_part<R: Read + Send + 'static>(session: &Session, part_auth: &UploadPartAuth, part_number: u32, read: R, len: u64, sha1: &str) -> Result<Part, B2Error> {
+    let mut headers = reqwest::header::Headers::new();
+    headers.set(reqwest::header::Authorization(part_auth.authorization_token.clone()));
+    headers.set_raw("X-Bz-Part-Number", part_number.to_string());
+    headers.set_raw("X-Bz-Content-Sha1", sha1.to_owned());
+    let resp = match session.client.post(&part_auth.upload_url)
+        .headers(headers)
+        .body(reqwest::Body::sized(read, len))
+        .send() {
+        Ok(v) => v,
+        Err(e) => return Err(B2Error::ReqwestError(e))
+    };
+    parse_response(resp)
+}
+
+/// Lists every part uploaded so far
+///
+/// Official documentation: [b2_list_parts](https://www.backblaze.com/b2/docs/b2_list_parts.html)
+pub fn list_parts(session: &Session, file_id: &str) -> Result<Vec<Part>, B2Error> {
+    let mut parts = Vec::new();
+    let mut start = 1;
+    loop {
+        let mut list: PartList = api_call(session, "b2_list_parts", &ListPartsBody {
+            file_id,
+            start_part_number: start,
+            max_part_count: 1000,
+        })?;
+        parts.append(&mut list.parts);
+        match list.next_part_number {
+            Some(v) => start = v,
+            None => return Ok(parts),
+        }
+    }
+}
+
+/// Assembles the uploaded parts into the finished file
+///
+/// 'part_sha1s' has the SHA-1 of every part, in order
+///
+/// Official documentation: [b2_finish_large_file](https://www.backblaze.com/b2/docs/b2_finish_large_file.html)
+pub fn finish_large_file(session: &Session, file_id: &str, part_sha1s: &[String]) -> Result<RemoteFile, B2Error> {
+    api_call(session, "b2_finish_large_file", &FinishLargeFileBody {
+        file_id,
+        part_sha1_array: part_sha1s,
+    })
+}
+
+/// Cancels a large file, deleting the parts uploaded so far
+///
+/// Official documentation: [b2_cancel_large_file](https://www.backblaze.com/b2/docs/b2_cancel_large_file.html)
+pub fn cancel_large_file(session: &Session, file_id: &str) -> Result<(), B2Error> {
+    api_call::<_, serde_json::Value>(session, "b2_cancel_large_file", &FileIdBody {
+        file_id,
+    }).map(|_| ())
+}
+
+/// Lists every large file in the bucket that was started but not finished or cancelled
+///
+/// Official documentation: [b2_list_unfinished_large_files](https://www.backblaze.com/b2/docs/b2_list_unfinished_large_files.html)
+pub fn list_unfinished_large_files(session: &Session, bucket_id: &str) -> Result<Vec<UnfinishedFile>, B2Error> {
+    let mut unfinished = Vec::new();
+    let mut start: Option<String> = None;
+    loop {
+        let mut list: UnfinishedFileList = api_call(session, "b2_list_unfinished_large_files", &ListUnfinishedBody {
+            bucket_id,
+            start_file_id: start.as_ref().map(|s| s.as_ref()),
+            max_file_count: 100,
+        })?;
+        unfinished.append(&mut list.files);
+        match list.next_file_id {
+            Some(v) => start = Some(v),
+            None => return Ok(unfinished),
+        }
+    }
+}
+
+// Posts a JSON body to an API endpoint and parses the response
+fn api_call<B: serde::Serialize, T: serde::de::DeserializeOwned>(session: &Session, endpoint: &str, body: &B) -> Result<T, B2Error> {
+    let json = match serde_json::to_string(body) {
+        Ok(v) => v,
+        Err(e) => return Err(B2Error::SerdeError(e))
+    };
+    session.call(|auth| {
+        let resp = match session.client.post(&format!("{}/b2api/v1/{}", auth.api_url, endpoint))
+            .header(reqwest::header::Authorization(auth.authorization_token.clone()))
+            .body(reqwest::Body::from(json.clone()))
+            .send() {
+            Ok(v) => v,
+            Err(e) => return Err(B2Error::ReqwestError(e))
+        };
+        parse_response(resp)
+    })
+}
+
+fn parse_response<T: serde::de::DeserializeOwned>(mut resp: reqwest::Response) -> Result<T, B2Error> {
+    if !resp.status().is_success() {
+        return Err(files::error_from_response(resp))
+    }
+    let response_string = match resp.text() {
+        Ok(v) => v,
+        Err(e) => return Err(B2Error::ReqwestError(e)),
+    };
+    match serde_json::from_str(&response_string) {
+        Ok(v) => Ok(v),
+        Err(e) => Err(B2Error::SerdeError(e)),
+    }
+}
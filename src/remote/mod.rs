@@ -0,0 +1,5 @@
+pub mod session;
+
+pub mod files;
+
+pub mod large_files;
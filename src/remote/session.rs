@@ -0,0 +1,71 @@
+use std;
+use std::sync::{Arc, RwLock};
+use reqwest;
+use raze;
+use raze::B2Error;
+use raze::api::auth::B2Auth;
+
+/// Client and authorization for the raw API calls in the remote module
+///
+/// The raze engine keeps its authorization to itself, so calls it doesn't offer
+/// need their own client and B2Auth. \
+/// Authorization tokens expire after 24 hours, so the session authorizes again when a call finds its token expired
+#[derive(Debug, Clone)]
+pub struct Session {
+    pub client: reqwest::Client,
+    auth: Arc<RwLock<B2Auth>>,
+    credentials: String,
+}
+
+impl Session {
+    /// Authenticates with an auth string like "keyId:applicationKey"
+    pub fn new(auth: &str) -> Result<Session, B2Error> {
+        let client = reqwest::Client::builder()
+            // Same as the engine, set arbitrarily high so large transfers don't time out
+            .timeout(std::time::Duration::new(100000000,0))
+            .build()
+            .unwrap();
+        let b2_auth = raze::api::auth::authenticate(&client, auth)?;
+        Ok(Session {
+            client,
+            auth: Arc::new(RwLock::new(b2_auth)),
+            credentials: auth.to_owned(),
+        })
+    }
+
+    /// The current authorization
+    pub fn auth(&self) -> B2Auth {
+        self.auth.read().unwrap().clone()
+    }
+
+    /// Makes an API call with the current authorization
+    ///
+    /// If the token expired, the session authorizes again and the call is made once more
+    pub fn call<T, F: Fn(&B2Auth) -> Result<T, B2Error>>(&self, f: F) -> Result<T, B2Error> {
+        let auth = self.auth();
+        match f(&auth) {
+            Err(ref e) if is_expired(e) => {
+                self.reauthorize(&auth.authorization_token)?;
+                f(&self.auth())
+            },
+            result => result,
+        }
+    }
+
+    // Authorizes again, unless another thread already did since 'expired' was handed out
+    fn reauthorize(&self, expired: &str) -> Result<(), B2Error> {
+        let mut auth = self.auth.write().unwrap();
+        if auth.authorization_token == expired {
+            *auth = raze::api::auth::authenticate(&self.client, &self.credentials)?;
+        }
+        Ok(())
+    }
+}
+
+// Whether B2 refused a call because its authorization token expired
+fn is_expired(e: &B2Error) -> bool {
+    match *e {
+        B2Error::B2Error(ref e) => e.status == 401 && e.code == "expired_auth_token",
+        _ => false,
+    }
+}
@@ -0,0 +1,164 @@
+use std;
+use std::io::Write;
+use sha2::{Sha256, Digest};
+use fastcdc::v2020 as fastcdc;
+use rand;
+use zstd;
+use storage::crypto::Keys;
+use storage::compression;
+use storage::manifest::ChunkLocation;
+
+/// Name of the chunking settings in the bucket, only present for buckets in chunked mode
+pub const CHUNKING_CONFIG_NAME: &str = ".raze/chunking.json";
+/// Prefix of the pack objects holding the chunks
+pub const PACK_PREFIX: &str = ".raze/packs/";
+
+/// Settings of a bucket in chunked mode
+///
+/// Instead of uploading every file as a whole, files are split where their content says so,
+/// which means an edit only changes the chunks around it. Every unique chunk is stored once,
+/// collected into packs so there aren't millions of tiny files in the bucket
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct ChunkingConfig {
+    pub version: u32,
+    /// Bounds and target for the chunk sizes in bytes
+    pub min_size: u32,
+    pub avg_size: u32,
+    pub max_size: u32,
+    /// Chunks are collected until a pack is about this size, then it's uploaded
+    pub pack_size: usize,
+}
+
+impl Default for ChunkingConfig {
+    fn default() -> ChunkingConfig {
+        ChunkingConfig {
+            version: 1,
+            min_size: 256*1024,
+            avg_size: 1024*1024,
+            max_size: 4*1024*1024,
+            pack_size: 16*1000*1000,
+        }
+    }
+}
+
+impl ChunkingConfig {
+    /// Checks the sizes are ones the chunker accepts, describing the problem if not
+    pub fn validate(&self) -> Result<(), String> {
+        if self.version != 1 {
+            return Err(format!("Unsupported chunking version {}", self.version));
+        }
+        if self.min_size < fastcdc::MINIMUM_MIN || self.min_size > fastcdc::MINIMUM_MAX
+            || self.avg_size < fastcdc::AVERAGE_MIN || self.avg_size > fastcdc::AVERAGE_MAX
+            || self.max_size < fastcdc::MAXIMUM_MIN || self.max_size > fastcdc::MAXIMUM_MAX
+            || self.min_size > self.avg_size || self.avg_size > self.max_size {
+            return Err("Invalid chunk sizes".to_owned());
+        }
+        Ok(())
+    }
+
+    /// Splits everything from 'read' into chunks, calling 'f' with each one in order
+    pub fn for_each_chunk<R: std::io::Read, F: FnMut(&[u8]) -> Result<(), String>>(&self, read: R, mut f: F) -> Result<(), String> {
+        for chunk in fastcdc::StreamCDC::new(read, self.min_size, self.avg_size, self.max_size) {
+            match chunk {
+                Ok(c) => f(&c.data)?,
+                Err(e) => return Err(e.to_string()),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Identifies a chunk by its content
+///
+/// In encrypted buckets the id is keyed, so it can't be used to confirm what a chunk contains
+pub fn chunk_id(keys: Option<&Keys>, data: &[u8]) -> String {
+    match keys {
+        Some(k) => k.chunk_id(data),
+        None => format!("{:x}", Sha256::digest(data)),
+    }
+}
+
+/// Compresses a blob of data if that pays off and 'compress' is set, then encrypts it if there are keys
+///
+/// Returns the encoded data and the codec used, if any
+pub fn encode_blob(keys: Option<&Keys>, data: &[u8], compress: bool) -> std::io::Result<(Vec<u8>, Option<String>)> {
+    let mut codec = None;
+    let mut compressed = Vec::new();
+    if compress {
+        compressed = zstd::stream::encode_all(data, ::config().transfer.compression_level)?;
+        if compression::worth_it(data.len() as u64, compressed.len() as u64) {
+            codec = Some(compression::CODEC_ZSTD.to_owned());
+        }
+    }
+    let plain = match codec {
+        Some(_) => &compressed[..],
+        None => data,
+    };
+    match keys {
+        Some(k) => {
+            let mut encrypted = Vec::new();
+            k.encrypt_stream(plain, &mut encrypted)?;
+            Ok((encrypted, codec))
+        },
+        None => Ok((plain.to_vec(), codec)),
+    }
+}
+
+/// Reverses encode_blob
+pub fn decode_blob(keys: Option<&Keys>, codec: Option<&str>, data: &[u8]) -> std::io::Result<Vec<u8>> {
+    let mut decrypted = Vec::new();
+    let plain = match keys {
+        Some(k) => {
+            k.decrypt_stream(data, &mut decrypted)?;
+            &decrypted[..]
+        },
+        None => data,
+    };
+    match codec {
+        Some(compression::CODEC_ZSTD) => zstd::stream::decode_all(plain),
+        Some(c) => Err(std::io::Error::new(std::io::ErrorKind::InvalidData, format!("Unknown codec '{}'", c))),
+        None => Ok(plain.to_vec()),
+    }
+}
+
+/// Encoded chunks waiting to be uploaded together as one pack
+pub struct Pack {
+    /// Name the pack will be stored as
+    pub name: String,
+    pub data: Vec<u8>,
+}
+
+impl Pack {
+    pub fn new() -> Pack {
+        Pack {
+            name: format!("{}{:016x}{:016x}", PACK_PREFIX, rand::random::<u64>(), rand::random::<u64>()),
+            data: Vec::new(),
+        }
+    }
+
+    /// Appends an encoded chunk, returning where it is
+    ///
+    /// The location refers to the pack by name, until it's uploaded and its file id is known
+    pub fn add(&mut self, encoded: &[u8], codec: Option<String>, encrypted: bool) -> ChunkLocation {
+        let location = ChunkLocation {
+            pack: self.name.clone(),
+            offset: self.data.len() as u64,
+            length: encoded.len() as u64,
+            codec,
+            encrypted,
+        };
+        self.data.write_all(encoded).unwrap();
+        location
+    }
+}
+
+#[test]
+fn test_blob() {
+    let data = "some text that compresses quite well ".repeat(1000).into_bytes();
+    let (encoded, codec) = encode_blob(None, &data, true).unwrap();
+    assert_eq!(codec, Some(compression::CODEC_ZSTD.to_owned()));
+    assert!(encoded.len() < data.len());
+    assert_eq!(decode_blob(None, codec.as_ref().map(|c| c.as_ref()), &encoded).unwrap(), data);
+    assert_eq!(encode_blob(None, &data, false).unwrap(), (data.clone(), None));
+    assert_eq!(chunk_id(None, b"abc"), "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad");
+}
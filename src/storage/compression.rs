@@ -0,0 +1,65 @@
+use std;
+use std::io::Read;
+use std::path::Path;
+use zstd;
+
+/// Name of the codec recorded in the file info of compressed files
+pub const CODEC_ZSTD: &str = "zstd";
+
+// Extensions of formats that are compressed already, compressing them again only costs time
+const SKIP_EXTENSIONS: &[&str] = &[
+    "7z", "gz", "tgz", "bz2", "xz", "zst", "lz4", "zip", "rar", "jar", "apk",
+    "jpg", "jpeg", "png", "gif", "webp", "heic", "avif",
+    "mp3", "aac", "ogg", "opus", "flac", "m4a",
+    "mp4", "mkv", "webm", "mov", "avi",
+    "docx", "xlsx", "pptx", "odt", "ods", "epub",
+];
+// Files smaller than this are stored as they are, there is little to gain
+const MIN_SIZE: u64 = 4*1024;
+// Compressed files larger than this fraction of the original are stored uncompressed instead
+const MAX_RATIO: f64 = 0.9;
+// How much of a file is compressed to decide whether compressing all of it pays off
+const SAMPLE_SIZE: u64 = 4*1024*1024;
+
+/// Whether a file is worth trying to compress, based on its extension and size
+pub fn should_compress(path: &Path, len: u64) -> bool {
+    if len < MIN_SIZE {
+        return false;
+    }
+    match path.extension().and_then(|e| e.to_str()) {
+        Some(ext) => !SKIP_EXTENSIONS.contains(&ext.to_lowercase().as_ref()),
+        None => true,
+    }
+}
+
+/// The codec to store a file with, None if it's better stored as it is
+///
+/// Only the start of the file is compressed to find out, so files that don't compress are read just once to upload them
+pub fn choose_codec(path: &Path, len: u64) -> std::io::Result<Option<&'static str>> {
+    if !should_compress(path, len) {
+        return Ok(None);
+    }
+    let mut sample = Vec::new();
+    std::fs::File::open(path)?.take(SAMPLE_SIZE).read_to_end(&mut sample)?;
+    let compressed = zstd::stream::encode_all(&sample[..], ::config().transfer.compression_level)?;
+    if worth_it(sample.len() as u64, compressed.len() as u64) {
+        Ok(Some(CODEC_ZSTD))
+    } else {
+        Ok(None)
+    }
+}
+
+/// Whether compressing 'original' bytes down to 'compressed' saved enough to keep it
+pub fn worth_it(original: u64, compressed: u64) -> bool {
+    (compressed as f64) < (original as f64) * MAX_RATIO
+}
+
+#[test]
+fn test_should_compress() {
+    assert!(should_compress(Path::new("/var/log/syslog"), 1000000));
+    assert!(should_compress(Path::new("export.CSV"), 1000000));
+    assert!(!should_compress(Path::new("photo.JPG"), 1000000));
+    assert!(!should_compress(Path::new("notes.txt"), 100));
+    assert!(worth_it(1000, 200));
+    assert!(!worth_it(1000, 950));
+}
@@ -0,0 +1,520 @@
+use std;
+use std::path::{Path, PathBuf};
+use std::collections::BTreeMap;
+use std::sync::OnceLock;
+use toml;
+use storage::storage::{self as storage_helper, BackupRoot};
+use storage::filter::Filter;
+use storage::paths;
+use storage::dirs::Dirs;
+use formatting::size_formatter::parse_bytes;
+use formatting::time_formatter::parse_interval;
+
+// The configuration in use, set once at startup
+static CONFIG: OnceLock<Config> = OnceLock::new();
+
+/// Settings read from the configuration file, see CONFIG_FILE_NAME
+///
+/// Everything is optional, anything left out keeps its default. Unknown keys are an error,
+/// so a typo doesn't silently leave a setting at its default
+#[derive(Deserialize, Debug, Clone, PartialEq, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Name of the bucket to back up to, instead of the one picked with 'set_bucket'
+    pub bucket: Option<String>,
+    /// Directories and files to back up, instead of the backup list
+    pub roots: Option<Vec<RootConfig>>,
+    /// Exclude/include patterns applied to every root, instead of the exclude list
+    pub excludes: Option<Vec<String>>,
+    /// Maximum bytes per second sent while uploading, eg. "500K", instead of the one set with 'throttle'
+    pub throttle: Option<String>,
+    /// How often a backup should run, eg. "daily" or "12h", see 'backup --if-due'
+    pub schedule: Option<String>,
+    pub files: FileNames,
+    pub transfer: TransferConfig,
+    pub credentials: CredentialsConfig,
+    /// Named profiles, each backing up to its own bucket with its own state
+    pub profiles: BTreeMap<String, ProfileConfig>,
+    /// The profile in use, picked with '--profile'
+    #[serde(skip)]
+    pub profile: Option<String>,
+    /// Where the files named in 'files' are kept
+    #[serde(skip)]
+    pub dirs: Dirs,
+    // The settings at the top of the configuration, before a profile replaced them
+    #[serde(skip)]
+    top_level: Option<ProfileConfig>,
+}
+
+/// A named set of roots, bucket and settings, selected with '--profile'
+///
+/// Anything left out is taken from the top of the configuration. Every profile keeps its own
+/// state, index and pending uploads, in files named after the profile, eg. "backupdata.photos"
+#[derive(Deserialize, Debug, Clone, PartialEq, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct ProfileConfig {
+    pub bucket: Option<String>,
+    pub roots: Option<Vec<RootConfig>>,
+    pub excludes: Option<Vec<String>>,
+    pub throttle: Option<String>,
+    pub schedule: Option<String>,
+}
+
+/// A directory or file to back up, like a line in the backup list
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct RootConfig {
+    /// Name the files below it are stored under, see paths::remote_name
+    pub name: Option<String>,
+    pub path: String,
+    /// Exclude/include patterns for this root only
+    #[serde(default)]
+    pub exclude: Vec<String>,
+}
+
+/// Names of the files the program keeps its state in
+///
+/// Names that aren't absolute paths are put in the configuration or state directory, see Dirs
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct FileNames {
+    /// Program info and options
+    pub data: String,
+    /// The list of folders to back up
+    pub backup_list: String,
+    /// Exclude/include patterns applied to every folder in the backup list
+    pub exclude_list: String,
+    /// What has been uploaded to the active bucket
+    pub index: String,
+    /// Large file uploads that haven't finished yet
+    pub pending_uploads: String,
+    pub credentials: String,
+}
+
+impl Default for FileNames {
+    fn default() -> FileNames {
+        FileNames {
+            data: "backupdata".to_owned(),
+            backup_list: "backuplist".to_owned(),
+            exclude_list: "backupexclude".to_owned(),
+            index: "backupindex".to_owned(),
+            pending_uploads: "backupuploads".to_owned(),
+            credentials: "raze_credentials".to_owned(),
+        }
+    }
+}
+
+/// Where the B2 key comes from, besides the environment and the credentials file
+#[derive(Deserialize, Debug, Clone, PartialEq, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct CredentialsConfig {
+    /// A command printing 'keyId:applicationKey', eg. "pass show b2"
+    pub command: Option<String>,
+    /// What happens to credentials entered at the prompt
+    pub store: CredentialStore,
+}
+
+/// How credentials entered at the prompt are kept for the next run
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum CredentialStore {
+    /// In the credentials file, encrypted with a passphrase
+    #[default]
+    Encrypted,
+    /// In the credentials file as they are, only when asked for explicitly
+    Plaintext,
+    /// Not at all, they're asked for every run
+    None,
+}
+
+/// How uploads, downloads and deletes are carried out
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct TransferConfig {
+    /// The amount of simultaneous uploads
+    pub upload_threads: usize,
+    /// The amount of simultaneous downloads when restoring or verifying
+    pub download_threads: usize,
+    /// The amount of simultaneous delete request senders
+    pub delete_threads: usize,
+    /// How many times a request is attempted before giving up on the file
+    pub retries: usize,
+    /// Seconds to wait before retrying a failed request
+    pub retry_delay: u64,
+    /// Files larger than this many bytes are uploaded in parts, which can be resumed if the upload is interrupted
+    pub large_file_threshold: u64,
+    /// zstd level used when compressing files before upload, higher is smaller but slower
+    pub compression_level: i32,
+}
+
+impl Default for TransferConfig {
+    fn default() -> TransferConfig {
+        TransferConfig {
+            upload_threads: 4,
+            download_threads: 4,
+            // Deletes are tiny requests, so this isn't limited by bandwidth or CPU
+            delete_threads: 16,
+            retries: 5,
+            retry_delay: 5,
+            large_file_threshold: 200*1000*1000,
+            compression_level: 3,
+        }
+    }
+}
+
+// B2 doesn't accept parts smaller than 5MB, or single uploads over 5GB
+const MIN_LARGE_FILE_THRESHOLD: u64 = 5*1000*1000;
+const MAX_LARGE_FILE_THRESHOLD: u64 = 5*1000*1000*1000;
+// More threads than this is more likely a typo than a fast connection
+const MAX_THREADS: usize = 64;
+
+impl Config {
+    /// Reads the configuration, applying 'overrides' on top of it
+    ///
+    /// Without a 'path', CONFIG_FILE_NAME in the configuration directory is read if it exists. A path given
+    /// explicitly must exist. \
+    /// Overrides are 'key=value' pairs like 'transfer.upload_threads=8', the value is read as TOML
+    /// and taken as a string if that fails. Returns a description of the first problem found
+    pub fn load(path: Option<&Path>, overrides: &[String], profile: Option<&str>, dirs: Dirs) -> Result<Config, String> {
+        let default_path = dirs.config.join(::CONFIG_FILE_NAME);
+        let (path, required) = match path {
+            Some(p) => (p, true),
+            None => (default_path.as_path(), false),
+        };
+        let contents = match std::fs::read_to_string(path) {
+            Ok(v) => v,
+            Err(ref e) if e.kind() == std::io::ErrorKind::NotFound && !required => String::new(),
+            Err(e) => return Err(format!("Can't read {}: {}", path.display(), e)),
+        };
+        let mut config = Config::parse(&contents, overrides).map_err(|e| format!("Invalid configuration in {}: {}", path.display(), e))?;
+        config.dirs = dirs;
+        if let Some(name) = profile {
+            config.select_profile(name)?;
+        }
+        Ok(config)
+    }
+
+    /// Uses the settings of the named profile from here on
+    pub fn select_profile(&mut self, name: &str) -> Result<(), String> {
+        let profile = match self.profile_settings(name) {
+            Some(v) => v,
+            None if self.profiles.is_empty() => return Err(format!("No profile named '{}', the configuration has no profiles", name)),
+            None => return Err(format!("No profile named '{}', expected one of {}", name,
+                                       self.profiles.keys().cloned().collect::<Vec<String>>().join(", "))),
+        };
+        self.top_level = Some(self.top_level_settings());
+        self.bucket = profile.bucket;
+        self.roots = profile.roots;
+        self.excludes = profile.excludes;
+        self.throttle = profile.throttle;
+        self.schedule = profile.schedule;
+        self.profile = Some(name.to_owned());
+        Ok(())
+    }
+
+    /// The settings of a profile, with anything it leaves out taken from the top of the configuration
+    pub fn profile_settings(&self, name: &str) -> Option<ProfileConfig> {
+        let profile = self.profiles.get(name)?.clone();
+        let top_level = self.top_level_settings();
+        Some(ProfileConfig {
+            bucket: profile.bucket.or(top_level.bucket),
+            roots: profile.roots.or(top_level.roots),
+            excludes: profile.excludes.or(top_level.excludes),
+            throttle: profile.throttle.or(top_level.throttle),
+            schedule: profile.schedule.or(top_level.schedule),
+        })
+    }
+
+    // The settings at the top of the configuration, even after a profile was selected
+    fn top_level_settings(&self) -> ProfileConfig {
+        match self.top_level {
+            Some(ref v) => v.clone(),
+            None => ProfileConfig {
+                bucket: self.bucket.clone(),
+                roots: self.roots.clone(),
+                excludes: self.excludes.clone(),
+                throttle: self.throttle.clone(),
+                schedule: self.schedule.clone(),
+            },
+        }
+    }
+
+    /// The file holding the state of the profile in use, see files.data
+    pub fn data_file(&self) -> PathBuf {
+        self.profile_data_file(self.profile.as_deref())
+    }
+
+    /// The file holding the state of a profile, or of the top of the configuration without one
+    pub fn profile_data_file(&self, profile: Option<&str>) -> PathBuf {
+        self.dirs.state.join(profile_file(&self.files.data, profile))
+    }
+
+    /// The index of the profile in use, see files.index
+    pub fn index_file(&self) -> PathBuf {
+        self.dirs.state.join(profile_file(&self.files.index, self.profile.as_deref()))
+    }
+
+    /// The pending uploads of the profile in use, see files.pending_uploads
+    pub fn pending_uploads_file(&self) -> PathBuf {
+        self.dirs.state.join(profile_file(&self.files.pending_uploads, self.profile.as_deref()))
+    }
+
+    /// The backup list, see files.backup_list
+    pub fn backup_list_file(&self) -> PathBuf {
+        self.dirs.config.join(&self.files.backup_list)
+    }
+
+    /// The exclude list, see files.exclude_list
+    pub fn exclude_list_file(&self) -> PathBuf {
+        self.dirs.config.join(&self.files.exclude_list)
+    }
+
+    /// The credentials file, see files.credentials
+    pub fn credentials_file(&self) -> PathBuf {
+        self.dirs.config.join(&self.files.credentials)
+    }
+
+    /// Where the files older versions kept in the working directory are moved to, as pairs of old and new path
+    ///
+    /// Files given as absolute paths were never in the working directory, so they're left out
+    pub fn legacy_files(&self) -> Vec<(PathBuf, PathBuf)> {
+        let mut files = vec![
+            (self.files.backup_list.clone(), self.backup_list_file()),
+            (self.files.exclude_list.clone(), self.exclude_list_file()),
+            (self.files.credentials.clone(), self.credentials_file()),
+        ];
+        // Every profile has its own state files, whichever one is in use
+        let profiles = std::iter::once(None).chain(self.profiles.keys().map(|p| Some(p.as_str())));
+        for profile in profiles {
+            for name in &[&self.files.data, &self.files.index, &self.files.pending_uploads] {
+                let name = profile_file(name, profile);
+                let path = self.dirs.state.join(&name);
+                files.push((name, path));
+            }
+        }
+        files.into_iter()
+            .filter(|(name, _)| Path::new(name).is_relative())
+            .map(|(name, path)| (PathBuf::from(name), path))
+            .collect()
+    }
+
+    /// The throttle from the configuration in bytes per second, if it sets one
+    pub fn throttle_limit(&self) -> Option<usize> {
+        self.throttle.as_ref().and_then(|t| parse_bytes(t)).map(|n| n as usize)
+    }
+
+    /// Whether a backup is due, given when the last one ran in seconds since the epoch, 0 if never
+    ///
+    /// Without a schedule a backup is always due
+    pub fn backup_due(&self, last_backup: i64, now: i64) -> bool {
+        backup_due(&self.schedule, last_backup, now)
+    }
+
+    /// Parses and validates a configuration, see load
+    pub fn parse(contents: &str, overrides: &[String]) -> Result<Config, String> {
+        let mut value: toml::Value = match toml::from_str(contents) {
+            Ok(v) => v,
+            Err(e) => return Err(e.to_string()),
+        };
+        for setting in overrides {
+            apply_override(&mut value, setting)?;
+        }
+        let config: Config = match value.try_into() {
+            Ok(v) => v,
+            Err(e) => return Err(e.to_string()),
+        };
+        config.validate()?;
+        Ok(config)
+    }
+
+    // Checks for values that parse fine but can't work
+    fn validate(&self) -> Result<(), String> {
+        let t = &self.transfer;
+        for &(key, threads) in &[("upload_threads", t.upload_threads), ("download_threads", t.download_threads),
+                                 ("delete_threads", t.delete_threads)] {
+            if threads == 0 || threads > MAX_THREADS {
+                return Err(format!("transfer.{} must be between 1 and {}, not {}", key, MAX_THREADS, threads));
+            }
+        }
+        if t.retries == 0 {
+            return Err("transfer.retries must be at least 1".to_owned());
+        }
+        if t.large_file_threshold < MIN_LARGE_FILE_THRESHOLD || t.large_file_threshold > MAX_LARGE_FILE_THRESHOLD {
+            return Err(format!("transfer.large_file_threshold must be between {} and {} bytes, not {}",
+                               MIN_LARGE_FILE_THRESHOLD, MAX_LARGE_FILE_THRESHOLD, t.large_file_threshold));
+        }
+        if t.compression_level < 1 || t.compression_level > 22 {
+            return Err(format!("transfer.compression_level must be between 1 and 22, not {}", t.compression_level));
+        }
+        let f = &self.files;
+        for &(key, name) in &[("data", &f.data), ("backup_list", &f.backup_list), ("exclude_list", &f.exclude_list),
+                              ("index", &f.index), ("pending_uploads", &f.pending_uploads), ("credentials", &f.credentials)] {
+            if name.trim().is_empty() {
+                return Err(format!("files.{} can't be empty", key));
+            }
+        }
+        if self.credentials.command.as_ref().is_some_and(|c| c.trim().is_empty()) {
+            return Err("credentials.command can't be empty, leave it out to use the credentials file".to_owned());
+        }
+        validate_target("", &self.bucket, &self.roots, &self.throttle, &self.schedule, t.upload_threads)?;
+        for (name, profile) in &self.profiles {
+            if !paths::is_valid_name(name) {
+                return Err(format!("'{}' can't be used as a profile name, names are letters, digits, '-', '_' and '.'", name));
+            }
+            validate_target(&format!("profiles.{}.", name), &profile.bucket, &profile.roots, &profile.throttle, &profile.schedule,
+                            t.upload_threads)?;
+        }
+        Ok(())
+    }
+
+    /// The directories and files to back up, from the configuration or the backup list
+    pub fn backup_roots(&self) -> Result<Vec<BackupRoot>, std::io::Error> {
+        match self.roots {
+            Some(ref roots) => Ok(roots.iter().map(|r| BackupRoot {
+                name: r.name.clone(),
+                path: r.path.clone(),
+                filter: Filter::parse(&r.exclude),
+            }).collect()),
+            None => storage_helper::read_backup_list(&self.backup_list_file()),
+        }
+    }
+
+    /// The patterns applied to every root, from the configuration or the exclude list
+    pub fn exclude_filter(&self) -> Result<Filter, std::io::Error> {
+        match self.excludes {
+            Some(ref patterns) => Ok(Filter::parse(patterns)),
+            None => storage_helper::read_exclude_list(&self.exclude_list_file()),
+        }
+    }
+}
+
+// Checks the settings a profile can have, 'prefix' is put in front of the keys in errors
+// The throttle is split between the upload threads, so it can't be lower than their number, see settings::set_throttle
+fn validate_target(prefix: &str, bucket: &Option<String>, roots: &Option<Vec<RootConfig>>,
+                   throttle: &Option<String>, schedule: &Option<String>, upload_threads: usize) -> Result<(), String> {
+    if bucket.as_ref().is_some_and(|b| b.trim().is_empty()) {
+        return Err(format!("{}bucket can't be empty, leave it out to use the one set with 'set_bucket'", prefix));
+    }
+    if let Some(ref roots) = *roots {
+        if roots.is_empty() {
+            return Err(format!("{}roots can't be empty, leave it out to use the backup list", prefix));
+        }
+        for root in roots {
+            if root.path.trim().is_empty() {
+                return Err(format!("Every entry in {}roots needs a path", prefix));
+            }
+            if let Some(ref name) = root.name {
+                if !paths::is_valid_name(name) {
+                    return Err(format!("'{}' can't be used as the name of {}, names are letters, digits, '-', '_' and '.'", name, root.path));
+                }
+            }
+        }
+    }
+    if let Some(ref t) = *throttle {
+        match parse_bytes(t) {
+            None => return Err(format!("{}throttle must be a rate like \"500K\" or \"2M\", not '{}'", prefix, t)),
+            Some(n) if n > 0 && n < upload_threads as u64 => {
+                return Err(format!("{}throttle must be at least {} bytes per second, one for every upload thread, or 0 for no limit", prefix, upload_threads));
+            },
+            _ => (),
+        }
+    }
+    if let Some(ref s) = *schedule {
+        if parse_interval(s).is_none() {
+            return Err(format!("{}schedule must be hourly, daily, weekly or a number of hours or days like \"12h\" or \"3d\", not '{}'", prefix, s));
+        }
+    }
+    Ok(())
+}
+
+/// Whether a backup is due on the given schedule, see Config::backup_due
+pub fn backup_due(schedule: &Option<String>, last_backup: i64, now: i64) -> bool {
+    match schedule.as_ref().and_then(|s| parse_interval(s)) {
+        Some(interval) => last_backup == 0 || now - last_backup >= interval,
+        None => true,
+    }
+}
+
+/// The name of a state file for a profile, the file itself if no profile is used
+pub fn profile_file(name: &str, profile: Option<&str>) -> String {
+    match profile {
+        Some(p) => format!("{}.{}", name, p),
+        None => name.to_owned(),
+    }
+}
+
+// Sets a 'key=value' override in the parsed file, creating tables along the way
+fn apply_override(value: &mut toml::Value, setting: &str) -> Result<(), String> {
+    let (key, raw) = match setting.find('=') {
+        Some(i) => (setting[..i].trim(), setting[i+1..].trim()),
+        None => return Err(format!("'{}' isn't a setting, expected key=value", setting)),
+    };
+    // Reading it as the value of a key takes care of numbers, booleans, arrays and quoted strings
+    let parsed = match toml::from_str::<toml::Value>(&format!("v = {}", raw)) {
+        Ok(toml::Value::Table(mut t)) => t.remove("v").unwrap(),
+        _ => toml::Value::String(raw.to_owned()),
+    };
+    let mut table = value;
+    let parts: Vec<&str> = key.split('.').collect();
+    for (i, part) in parts.iter().enumerate() {
+        let current = match *table {
+            toml::Value::Table(ref mut t) => t,
+            _ => return Err(format!("'{}' isn't a table, can't set {}", parts[..i].join("."), key)),
+        };
+        if i + 1 == parts.len() {
+            current.insert(part.to_string(), parsed);
+            break;
+        }
+        table = current.entry(part.to_string()).or_insert_with(|| toml::Value::Table(Default::default()));
+    }
+    Ok(())
+}
+
+/// Makes 'config' the configuration used from here on, can only be done once
+pub fn install(config: Config) {
+    CONFIG.set(config).expect("The configuration was already set");
+}
+
+/// The configuration in use, the defaults if none was installed
+pub fn get() -> &'static Config {
+    CONFIG.get_or_init(Config::default)
+}
+
+#[test]
+fn test_config() {
+    let contents = "bucket = \"photos\"\n\
+                    [[roots]]\n\
+                    name = \"docs\"\n\
+                    path = \"/home/me/Documents\"\n\
+                    exclude = [\"*.tmp\"]\n\
+                    [transfer]\n\
+                    upload_threads = 8\n";
+    let config = Config::parse(contents, &["transfer.retries=3".to_owned(), "files.index=other index".to_owned()]).unwrap();
+    assert_eq!(config.bucket, Some("photos".to_owned()));
+    assert_eq!(config.transfer.upload_threads, 8);
+    assert_eq!(config.transfer.retries, 3);
+    assert_eq!(config.transfer.delete_threads, 16);
+    assert_eq!(config.files.index, "other index");
+    assert_eq!(config.backup_roots().unwrap()[0].name, Some("docs".to_owned()));
+    assert_eq!(Config::parse("", &[]), Ok(Config::default()));
+    assert!(Config::parse("[transfer]\nupload_thread = 8", &[]).unwrap_err().contains("upload_thread"));
+    assert!(Config::parse("", &["transfer.upload_threads=0".to_owned()]).is_err());
+    assert!(Config::parse("", &["transfer".to_owned()]).is_err());
+    assert_eq!(Config::parse("[credentials]\nstore = \"none\"", &[]).unwrap().credentials.store, CredentialStore::None);
+    assert!(Config::parse("", &["credentials.store=disk".to_owned()]).is_err());
+    let mut config = Config::parse("schedule = \"daily\"\n[profiles.photos]\nbucket = \"photos\"\nthrottle = \"500K\"", &[]).unwrap();
+    assert!(config.select_profile("work").is_err());
+    config.select_profile("photos").unwrap();
+    assert_eq!(config.bucket, Some("photos".to_owned()));
+    assert_eq!(config.throttle_limit(), Some(500*1000));
+    config.dirs = Dirs::single(Path::new("/srv/raze"));
+    assert_eq!(config.data_file(), PathBuf::from("/srv/raze/backupdata.photos"));
+    let legacy = config.legacy_files();
+    assert!(legacy.contains(&(PathBuf::from("backupdata"), PathBuf::from("/srv/raze/backupdata"))));
+    assert!(legacy.contains(&(PathBuf::from("backupindex.photos"), PathBuf::from("/srv/raze/backupindex.photos"))));
+    assert!(!config.backup_due(1528000000, 1528000000 + 3600));
+    assert!(config.backup_due(0, 1528000000));
+    assert!(Config::parse("[profiles.photos]\nschedule = \"sometimes\"", &[]).is_err());
+    assert!(Config::parse("throttle = \"2\"", &[]).is_err());
+    assert!(Config::parse("throttle = \"0\"", &[]).is_ok());
+}
@@ -0,0 +1,343 @@
+use std;
+use std::fmt;
+use std::io::{Read, Write};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce, Key, KeyInit};
+use chacha20poly1305::aead::Aead;
+use chacha20poly1305::aead::stream::{EncryptorBE32, DecryptorBE32};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use rand::RngCore;
+use rand::rngs::OsRng;
+use scrypt;
+use base64;
+
+// File contents are encrypted in chunks of this many bytes, each with its own tag
+const CHUNK_SIZE: usize = 64*1024;
+// Size of the authentication tag added to every chunk and name
+const TAG_SIZE: usize = 16;
+// Every encrypted file starts with this, followed by the nonce of its stream
+const MAGIC: &[u8] = b"RAZE\x01";
+// XChaCha20 has a 24 byte nonce, the stream construction uses the last 5 for its counter
+const NONCE_SIZE: usize = 24;
+const STREAM_NONCE_SIZE: usize = 19;
+// Known value encrypted into the repository header, used to tell whether a passphrase is correct
+const CHECK_VALUE: &[u8] = b"raze repository";
+// Key derivation cost for new repositories, 2^15 iterations with 32 MiB of memory
+const SCRYPT_LOG_N: u8 = 15;
+const SCRYPT_R: u32 = 8;
+const SCRYPT_P: u32 = 1;
+
+/// Describes how the keys of an encrypted bucket are derived
+///
+/// Stored unencrypted in the bucket, it contains nothing secret. \
+/// Whoever knows the passphrase can derive the same keys again from it
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct RepositoryHeader {
+    pub version: u32,
+    pub kdf: String,
+    pub log_n: u8,
+    pub r: u32,
+    pub p: u32,
+    /// Base64 encoded random salt
+    pub salt: String,
+    /// CHECK_VALUE encrypted with the content key, base64 encoded
+    pub check: String,
+}
+
+#[derive(Debug)]
+pub enum CryptoError {
+    /// The passphrase doesn't match the one the repository was created with
+    WrongPassphrase,
+    /// The header was written by a newer version, or is damaged
+    UnsupportedHeader(String),
+    /// Encrypted data was modified, truncated or isn't ours
+    Corrupted,
+}
+
+impl fmt::Display for CryptoError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            CryptoError::WrongPassphrase => write!(f, "Wrong passphrase"),
+            CryptoError::UnsupportedHeader(ref reason) => write!(f, "Unsupported repository header: {}", reason),
+            CryptoError::Corrupted => write!(f, "Encrypted data is damaged or was modified"),
+        }
+    }
+}
+
+/// The keys of an encrypted repository
+///
+/// Contents use random nonces, names use nonces derived from the name itself so the same path
+/// always encrypts to the same remote name. That is what lets the index and purge keep working
+pub struct Keys {
+    content: [u8; 32],
+    name: [u8; 32],
+    name_nonce: [u8; 32],
+    chunk_id: [u8; 32],
+}
+
+impl Keys {
+    /// Sets up a new repository, returning the header to store and its keys
+    pub fn create(passphrase: &str) -> (RepositoryHeader, Keys) {
+        Keys::create_with(passphrase, SCRYPT_LOG_N)
+    }
+
+    // Sets up new keys with the given key derivation cost
+    fn create_with(passphrase: &str, log_n: u8) -> (RepositoryHeader, Keys) {
+        let mut salt = [0u8; 16];
+        OsRng.fill_bytes(&mut salt);
+        let mut header = RepositoryHeader {
+            version: 1,
+            kdf: "scrypt".to_owned(),
+            log_n,
+            r: SCRYPT_R,
+            p: SCRYPT_P,
+            salt: base64::encode(salt),
+            check: String::new(),
+        };
+        let keys = Keys::derive(passphrase, &header).unwrap();
+        let mut nonce = [0u8; NONCE_SIZE];
+        OsRng.fill_bytes(&mut nonce);
+        let mut check = nonce.to_vec();
+        check.extend(cipher(&keys.content).encrypt(XNonce::from_slice(&nonce), CHECK_VALUE).unwrap());
+        header.check = base64::encode(&check);
+        (header, keys)
+    }
+
+    /// Derives the keys of an existing repository, failing if the passphrase is wrong
+    pub fn open(passphrase: &str, header: &RepositoryHeader) -> Result<Keys, CryptoError> {
+        if header.version != 1 {
+            return Err(CryptoError::UnsupportedHeader(format!("version {}", header.version)));
+        }
+        let keys = Keys::derive(passphrase, header)?;
+        let check = match base64::decode(&header.check) {
+            Ok(ref v) if v.len() > NONCE_SIZE => v.clone(),
+            _ => return Err(CryptoError::UnsupportedHeader("invalid check value".to_owned())),
+        };
+        match cipher(&keys.content).decrypt(XNonce::from_slice(&check[..NONCE_SIZE]), &check[NONCE_SIZE..]) {
+            Ok(ref v) if v.as_slice() == CHECK_VALUE => Ok(keys),
+            _ => Err(CryptoError::WrongPassphrase),
+        }
+    }
+
+    // Runs the key derivation described by the header
+    fn derive(passphrase: &str, header: &RepositoryHeader) -> Result<Keys, CryptoError> {
+        if header.kdf != "scrypt" {
+            return Err(CryptoError::UnsupportedHeader(format!("key derivation '{}'", header.kdf)));
+        }
+        let salt = match base64::decode(&header.salt) {
+            Ok(v) => v,
+            Err(_e) => return Err(CryptoError::UnsupportedHeader("invalid salt".to_owned())),
+        };
+        let params = match scrypt::Params::new(header.log_n, header.r, header.p, scrypt::Params::RECOMMENDED_LEN) {
+            Ok(v) => v,
+            Err(_e) => return Err(CryptoError::UnsupportedHeader("invalid scrypt parameters".to_owned())),
+        };
+        let mut output = [0u8; 96];
+        if scrypt::scrypt(passphrase.as_bytes(), &salt, &params, &mut output).is_err() {
+            return Err(CryptoError::UnsupportedHeader("invalid scrypt parameters".to_owned()));
+        }
+        let mut keys = Keys {
+            content: [0u8; 32],
+            name: [0u8; 32],
+            name_nonce: [0u8; 32],
+            chunk_id: [0u8; 32],
+        };
+        keys.content.copy_from_slice(&output[..32]);
+        keys.name.copy_from_slice(&output[32..64]);
+        keys.name_nonce.copy_from_slice(&output[64..]);
+        // Added with chunked mode, derived from the others so existing repositories get it too
+        let chunk_id = hmac(&keys.name_nonce, b"chunk id");
+        keys.chunk_id.copy_from_slice(&chunk_id);
+        Ok(keys)
+    }
+
+    /// Encrypts a remote file name into an opaque name without any '/'
+    ///
+    /// The result is a third longer than the name plus 54 bytes, see files::MAX_FILE_NAME_LEN
+    pub fn encrypt_name(&self, name: &str) -> String {
+        let digest = hmac(&self.name_nonce, name.as_bytes());
+        let nonce = XNonce::from_slice(&digest[..NONCE_SIZE]);
+        let mut encrypted = nonce.to_vec();
+        encrypted.extend(cipher(&self.name).encrypt(nonce, name.as_bytes()).unwrap());
+        base64::encode_config(&encrypted, base64::URL_SAFE_NO_PAD)
+    }
+
+    /// Reverses encrypt_name, None if the name wasn't encrypted with these keys
+    pub fn decrypt_name(&self, encrypted: &str) -> Option<String> {
+        let encrypted = base64::decode_config(encrypted, base64::URL_SAFE_NO_PAD).ok()?;
+        if encrypted.len() < NONCE_SIZE + TAG_SIZE {
+            return None;
+        }
+        let name = cipher(&self.name).decrypt(XNonce::from_slice(&encrypted[..NONCE_SIZE]), &encrypted[NONCE_SIZE..]).ok()?;
+        String::from_utf8(name).ok()
+    }
+
+    /// Identifies a chunk by its content without revealing anything about it, see storage::chunks
+    pub fn chunk_id(&self, data: &[u8]) -> String {
+        hmac(&self.chunk_id, data).iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    /// Encrypts everything from 'read' into 'write'
+    ///
+    /// The output is MAGIC, a random nonce and then the chunks, the last one marked as such
+    /// so a truncated file can't pass as complete
+    pub fn encrypt_stream<R: Read, W: Write>(&self, mut read: R, mut write: W) -> std::io::Result<()> {
+        let mut nonce = [0u8; STREAM_NONCE_SIZE];
+        OsRng.fill_bytes(&mut nonce);
+        write.write_all(MAGIC)?;
+        write.write_all(&nonce)?;
+        let mut encryptor = EncryptorBE32::from_aead(cipher(&self.content), nonce.as_ref().into());
+        let mut chunk = vec![0u8; CHUNK_SIZE];
+        let mut len = read_full(&mut read, &mut chunk)?;
+        loop {
+            // A full chunk may still be the last one, so look ahead before encrypting it
+            let mut next = vec![0u8; CHUNK_SIZE];
+            let next_len = if len == CHUNK_SIZE { read_full(&mut read, &mut next)? } else { 0 };
+            if next_len == 0 {
+                let encrypted = encryptor.encrypt_last(&chunk[..len]).map_err(|_e| corrupted())?;
+                return write.write_all(&encrypted);
+            }
+            let encrypted = encryptor.encrypt_next(&chunk[..len]).map_err(|_e| corrupted())?;
+            write.write_all(&encrypted)?;
+            chunk = next;
+            len = next_len;
+        }
+    }
+
+    /// Decrypts the output of encrypt_stream from 'read' into 'write'
+    ///
+    /// Fails with InvalidData if anything was modified or cut off
+    pub fn decrypt_stream<R: Read, W: Write>(&self, mut read: R, mut write: W) -> std::io::Result<()> {
+        let mut start = [0u8; MAGIC.len() + STREAM_NONCE_SIZE];
+        if read_full(&mut read, &mut start)? != start.len() || &start[..MAGIC.len()] != MAGIC {
+            return Err(corrupted());
+        }
+        let mut decryptor = DecryptorBE32::from_aead(cipher(&self.content), start[MAGIC.len()..].into());
+        let mut chunk = vec![0u8; CHUNK_SIZE + TAG_SIZE];
+        let mut len = read_full(&mut read, &mut chunk)?;
+        loop {
+            let mut next = vec![0u8; CHUNK_SIZE + TAG_SIZE];
+            let next_len = if len == chunk.len() { read_full(&mut read, &mut next)? } else { 0 };
+            if next_len == 0 {
+                let decrypted = decryptor.decrypt_last(&chunk[..len]).map_err(|_e| corrupted())?;
+                return write.write_all(&decrypted);
+            }
+            let decrypted = decryptor.decrypt_next(&chunk[..len]).map_err(|_e| corrupted())?;
+            write.write_all(&decrypted)?;
+            chunk = next;
+            len = next_len;
+        }
+    }
+}
+
+/// A small secret encrypted with a passphrase, like the credentials file
+///
+/// The key is derived the same way as the keys of a repository, so the header tells whether a passphrase is right
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct SealedSecret {
+    pub header: RepositoryHeader,
+    /// The nonce followed by the encrypted secret, base64 encoded
+    pub secret: String,
+}
+
+impl SealedSecret {
+    pub fn seal(passphrase: &str, secret: &str) -> SealedSecret {
+        let (header, keys) = Keys::create(passphrase);
+        SealedSecret::seal_with(header, &keys, secret)
+    }
+
+    // Encrypts the secret with keys made for the header
+    fn seal_with(header: RepositoryHeader, keys: &Keys, secret: &str) -> SealedSecret {
+        let mut nonce = [0u8; NONCE_SIZE];
+        OsRng.fill_bytes(&mut nonce);
+        let mut sealed = nonce.to_vec();
+        sealed.extend(cipher(&keys.content).encrypt(XNonce::from_slice(&nonce), secret.as_bytes()).unwrap());
+        SealedSecret {
+            header,
+            secret: base64::encode(&sealed),
+        }
+    }
+
+    /// Decrypts the secret, failing if the passphrase is wrong
+    pub fn open(&self, passphrase: &str) -> Result<String, CryptoError> {
+        let keys = Keys::open(passphrase, &self.header)?;
+        let sealed = match base64::decode(&self.secret) {
+            Ok(ref v) if v.len() > NONCE_SIZE => v.clone(),
+            _ => return Err(CryptoError::Corrupted),
+        };
+        match cipher(&keys.content).decrypt(XNonce::from_slice(&sealed[..NONCE_SIZE]), &sealed[NONCE_SIZE..]) {
+            Ok(v) => String::from_utf8(v).map_err(|_e| CryptoError::Corrupted),
+            Err(_e) => Err(CryptoError::Corrupted),
+        }
+    }
+}
+
+fn cipher(key: &[u8; 32]) -> XChaCha20Poly1305 {
+    XChaCha20Poly1305::new(Key::from_slice(key))
+}
+
+fn hmac(key: &[u8; 32], data: &[u8]) -> Vec<u8> {
+    let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(key).unwrap();
+    mac.update(data);
+    mac.finalize().into_bytes().to_vec()
+}
+
+fn corrupted() -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, CryptoError::Corrupted.to_string())
+}
+
+// Reads until 'buf' is full or the end is reached, returning how much was read
+fn read_full<R: Read>(read: &mut R, buf: &mut [u8]) -> std::io::Result<usize> {
+    let mut len = 0;
+    while len < buf.len() {
+        match read.read(&mut buf[len..]) {
+            Ok(0) => break,
+            Ok(n) => len += n,
+            Err(ref e) if e.kind() == std::io::ErrorKind::Interrupted => (),
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(len)
+}
+
+#[test]
+fn test_crypto() {
+    // Cheap parameters, the defaults take a while
+    let header = RepositoryHeader {
+        version: 1,
+        kdf: "scrypt".to_owned(),
+        log_n: 4,
+        r: 8,
+        p: 1,
+        salt: base64::encode(b"0123456789abcdef"),
+        check: String::new(),
+    };
+    let keys = Keys::derive("hunter2", &header).unwrap();
+    let name = keys.encrypt_name("home/me/Documents/notes.txt");
+    assert!(!name.contains('/'));
+    assert_eq!(name, keys.encrypt_name("home/me/Documents/notes.txt"));
+    assert_eq!(keys.decrypt_name(&name), Some("home/me/Documents/notes.txt".to_owned()));
+    assert_eq!(keys.decrypt_name("home/me/Documents/notes.txt"), None);
+    // The longest name that still fits in B2's limit once encrypted
+    assert_eq!(keys.encrypt_name(&"a".repeat(728)).len(), 1024);
+
+    for size in &[0, 10, CHUNK_SIZE, CHUNK_SIZE*2 + 7] {
+        let data: Vec<u8> = (0..*size).map(|i| i as u8).collect();
+        let mut encrypted = Vec::new();
+        keys.encrypt_stream(&data[..], &mut encrypted).unwrap();
+        let mut decrypted = Vec::new();
+        keys.decrypt_stream(&encrypted[..], &mut decrypted).unwrap();
+        assert_eq!(decrypted, data);
+        // Dropping the last chunk must not go unnoticed
+        if *size > CHUNK_SIZE {
+            let truncated = &encrypted[..MAGIC.len() + STREAM_NONCE_SIZE + CHUNK_SIZE + TAG_SIZE];
+            assert!(keys.decrypt_stream(truncated, &mut Vec::new()).is_err());
+        }
+    }
+
+    let (header, keys) = Keys::create_with("hunter2", 4);
+    let sealed = SealedSecret::seal_with(header, &keys, "keyId:applicationKey");
+    assert!(!sealed.secret.contains("applicationKey"));
+    assert_eq!(sealed.open("hunter2").unwrap(), "keyId:applicationKey");
+    assert!(sealed.open("hunter3").is_err());
+}
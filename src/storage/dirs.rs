@@ -0,0 +1,136 @@
+use std;
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+
+// Name of the directory the program's files are kept in, below each base directory
+const APP_DIR_NAME: &str = "raze-cli";
+
+/// Where the program keeps its files
+///
+/// The configuration file, backup list, exclude list and credentials go in 'config', the persistent data,
+/// index and pending uploads in 'state' and temporary upload files in 'cache'. \
+/// These follow the XDG base directory specification, on Windows they're below %APPDATA% and %LOCALAPPDATA%
+#[derive(Debug, Clone, PartialEq)]
+pub struct Dirs {
+    pub config: PathBuf,
+    pub state: PathBuf,
+    pub cache: PathBuf,
+}
+
+impl Default for Dirs {
+    // The working directory, where files were kept before they had directories of their own
+    fn default() -> Dirs {
+        Dirs {
+            config: PathBuf::new(),
+            state: PathBuf::new(),
+            cache: std::env::temp_dir(),
+        }
+    }
+}
+
+impl Dirs {
+    /// Every file in the same directory, as given with '--config-dir'
+    pub fn single(dir: &Path) -> Dirs {
+        Dirs {
+            config: dir.to_path_buf(),
+            state: dir.to_path_buf(),
+            cache: dir.to_path_buf(),
+        }
+    }
+
+    /// Looks up the directories for the current user
+    pub fn locate() -> Result<Dirs, String> {
+        Dirs::from_env(|key| std::env::var_os(key))
+    }
+
+    // Looks up the directories from the environment variables 'var' returns
+    fn from_env<F: Fn(&str) -> Option<OsString>>(var: F) -> Result<Dirs, String> {
+        // Relative paths are invalid according to the specification and are ignored
+        let absolute = |key: &str| var(key).map(PathBuf::from).filter(|p| p.is_absolute());
+        if cfg!(windows) {
+            let roaming = absolute("APPDATA");
+            let local = absolute("LOCALAPPDATA");
+            return match (roaming, local) {
+                (Some(roaming), Some(local)) => Ok(Dirs {
+                    config: roaming.join(APP_DIR_NAME),
+                    state: local.join(APP_DIR_NAME),
+                    cache: local.join(APP_DIR_NAME).join("cache"),
+                }),
+                _ => Err("Can't find the application data folder, set APPDATA and LOCALAPPDATA or use '--config-dir'".to_owned()),
+            };
+        }
+        let home = absolute("HOME");
+        let base = |key: &str, default: &str| match absolute(key) {
+            Some(v) => Some(v.join(APP_DIR_NAME)),
+            None => home.as_ref().map(|h| h.join(default).join(APP_DIR_NAME)),
+        };
+        match (base("XDG_CONFIG_HOME", ".config"), base("XDG_STATE_HOME", ".local/state"), base("XDG_CACHE_HOME", ".cache")) {
+            (Some(config), Some(state), Some(cache)) => Ok(Dirs { config, state, cache }),
+            _ => Err("Can't find the home directory, set HOME or use '--config-dir'".to_owned()),
+        }
+    }
+
+    /// Creates the directories that don't exist yet
+    pub fn create(&self) -> Result<(), String> {
+        for dir in &[&self.config, &self.state, &self.cache] {
+            if let Err(e) = std::fs::create_dir_all(dir) {
+                return Err(format!("Can't create {}: {}", dir.display(), e));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Moves files from the working directory to where they're kept now, given as pairs of old and new path
+///
+/// Older versions kept every file in the working directory. A file that already exists at its new path
+/// is left where it is, so nothing gets overwritten
+///
+/// Returns the new paths of the files that were moved
+pub fn migrate(files: &[(PathBuf, PathBuf)]) -> Result<Vec<PathBuf>, String> {
+    let mut moved = Vec::new();
+    for (old, new) in files {
+        if !old.is_file() || same_file(old, new) {
+            continue;
+        }
+        if new.exists() {
+            println!("Ignoring '{}' in the working directory, '{}' is used instead", old.display(), new.display());
+            continue;
+        }
+        // Renaming fails across file systems, copying doesn't
+        if std::fs::rename(old, new).is_err() {
+            if let Err(e) = std::fs::copy(old, new).and_then(|_| std::fs::remove_file(old)) {
+                return Err(format!("Can't move '{}' to {}: {}", old.display(), new.display(), e));
+            }
+        }
+        println!("Moved '{}' from the working directory to {}", old.display(), new.display());
+        moved.push(new.clone());
+    }
+    Ok(moved)
+}
+
+// Whether two paths lead to the same file, eg. when the working directory is the state directory
+fn same_file(a: &Path, b: &Path) -> bool {
+    match (a.canonicalize(), b.canonicalize()) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => false,
+    }
+}
+
+#[test]
+fn test_dirs() {
+    if cfg!(windows) {
+        return;
+    }
+    let env = |vars: &'static [(&'static str, &'static str)]| move |key: &str| {
+        vars.iter().find(|v| v.0 == key).map(|v| OsString::from(v.1))
+    };
+    let dirs = Dirs::from_env(env(&[("HOME", "/home/user")])).unwrap();
+    assert_eq!(dirs.config, PathBuf::from("/home/user/.config/raze-cli"));
+    assert_eq!(dirs.state, PathBuf::from("/home/user/.local/state/raze-cli"));
+    assert_eq!(dirs.cache, PathBuf::from("/home/user/.cache/raze-cli"));
+    let dirs = Dirs::from_env(env(&[("HOME", "/home/user"), ("XDG_CONFIG_HOME", "/etc/xdg"), ("XDG_CACHE_HOME", "relative")])).unwrap();
+    assert_eq!(dirs.config, PathBuf::from("/etc/xdg/raze-cli"));
+    assert_eq!(dirs.cache, PathBuf::from("/home/user/.cache/raze-cli"));
+    assert!(Dirs::from_env(env(&[])).is_err());
+}
@@ -0,0 +1,112 @@
+use glob::{Pattern, MatchOptions};
+
+/// A single gitignore-style pattern
+#[derive(Debug, Clone)]
+struct Rule {
+    pattern: Pattern,
+    /// Started with '!', matching paths are included again
+    negated: bool,
+    /// Ended with '/', only matches directories
+    directory_only: bool,
+    /// Contained a '/', so it's matched against the whole path relative to the root
+    /// instead of just the file name
+    anchored: bool,
+}
+
+/// A list of exclude and include patterns, applied while walking the backup list
+///
+/// Patterns follow the gitignore rules: \
+/// 'target/' excludes every directory named target, '*.tmp' excludes matching files anywhere, \
+/// '/cache' or 'build/out' only match relative to the root, '**' matches any number of directories
+/// and '!keep.tmp' includes a previously excluded file again. The last matching pattern wins
+///
+/// Excluded directories are never traversed, so like with git, files below them can't be included again
+#[derive(Debug, Clone, Default)]
+pub struct Filter {
+    rules: Vec<Rule>,
+}
+
+impl Filter {
+    /// Parses one pattern per line, skipping blank lines and '#' comments
+    ///
+    /// Invalid patterns are reported and skipped
+    pub fn parse(lines: &[String]) -> Filter {
+        let mut rules = Vec::new();
+        for line in lines {
+            let mut line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let negated = line.starts_with('!');
+            if negated {
+                line = &line[1..];
+            }
+            let directory_only = line.ends_with('/');
+            let line = line.trim_end_matches('/');
+            let anchored = line.contains('/');
+            match Pattern::new(line.trim_start_matches('/')) {
+                Ok(pattern) => rules.push(Rule {
+                    pattern,
+                    negated,
+                    directory_only,
+                    anchored,
+                }),
+                Err(e) => println!("Ignoring invalid pattern '{}': {}", line, e.msg),
+            }
+        }
+        Filter {
+            rules,
+        }
+    }
+
+    /// Combines two filters, the patterns in 'other' take precedence
+    pub fn combined(&self, other: &Filter) -> Filter {
+        let mut rules = self.rules.clone();
+        rules.extend(other.rules.iter().cloned());
+        Filter {
+            rules,
+        }
+    }
+
+    /// Whether a path should be left out of the backup
+    ///
+    /// 'relative' is the path relative to the backup root, using '/' as separator
+    pub fn is_excluded(&self, relative: &str, is_dir: bool) -> bool {
+        let options = MatchOptions {
+            case_sensitive: true,
+            require_literal_separator: true,
+            require_literal_leading_dot: false,
+        };
+        let file_name = relative.rsplit('/').next().unwrap_or(relative);
+        let mut excluded = false;
+        for rule in &self.rules {
+            if rule.directory_only && !is_dir {
+                continue;
+            }
+            let matched = if rule.anchored {
+                rule.pattern.matches_with(relative, &options)
+            } else {
+                rule.pattern.matches_with(file_name, &options)
+            };
+            if matched {
+                excluded = !rule.negated;
+            }
+        }
+        excluded
+    }
+}
+
+#[test]
+fn test_filter() {
+    let lines: Vec<String> = vec!["node_modules/", "*.tmp", "!keep.tmp", "/cache", "build/**/out", "# comment"]
+        .into_iter().map(|l| l.to_owned()).collect();
+    let filter = Filter::parse(&lines);
+    assert!(filter.is_excluded("web/node_modules", true));
+    assert!(!filter.is_excluded("web/node_modules", false));
+    assert!(filter.is_excluded("notes/draft.tmp", false));
+    assert!(!filter.is_excluded("notes/keep.tmp", false));
+    assert!(filter.is_excluded("cache", true));
+    assert!(!filter.is_excluded("web/cache", true));
+    assert!(filter.is_excluded("build/debug/x86/out", true));
+    assert!(!filter.is_excluded("notes/todo.txt", false));
+}
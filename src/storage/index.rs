@@ -0,0 +1,114 @@
+use std;
+use std::io::Read;
+use std::collections::BTreeMap;
+use serde_json;
+use storage::storage::{self as storage_helper, StorageError};
+use remote::files::{self, RemoteFile};
+
+/// What we know about a single uploaded file
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct IndexEntry {
+    pub file_id: String,
+    pub size: u64,
+    /// Local modification time in milliseconds when it was uploaded
+    /// For entries rebuilt from the bucket this is the upload timestamp instead
+    pub modified: u64,
+    /// SHA-1 of the uploaded content, "none" if B2 didn't compute one
+    /// For encrypted files, size and hash are those of the original content
+    pub sha1: String,
+}
+
+impl IndexEntry {
+    /// The entry for a file uploaded as is
+    ///
+    /// 'modified' is the local modification time in milliseconds at the time of upload
+    pub fn from_remote_file(remote_file: &RemoteFile, modified: u64) -> IndexEntry {
+        IndexEntry {
+            file_id: remote_file.file_id.clone(),
+            size: remote_file.content_length,
+            modified,
+            // Large files only have a hash if it was recorded in their file info
+            // Uploads with the hash at the end of the body may report it as unverified
+            sha1: match remote_file.file_info.get(files::INFO_SHA1) {
+                Some(v) => v.clone(),
+                None => remote_file.content_sha1.trim_start_matches("unverified:").to_owned(),
+            },
+        }
+    }
+}
+
+/// A local record of everything uploaded to a bucket, keyed by remote file name
+///
+/// This lets backup and purge find changes without listing the whole bucket every run \
+/// If the bucket is changed by something else, use 'rebuild-index' to bring it back in sync
+#[derive(Deserialize, Serialize, Debug, Default)]
+pub struct UploadIndex {
+    /// The bucket this index describes, an index for another bucket is never used
+    pub bucket_id: String,
+    pub files: BTreeMap<String, IndexEntry>,
+}
+
+impl UploadIndex {
+    pub fn from_file(file: &std::path::Path) -> Result<UploadIndex,StorageError> {
+        let mut read = match std::fs::File::open(file) {
+            Ok(f) => f,
+            Err(e) => return Err(StorageError::IOError(e)),
+        };
+        let mut contents = String::new();
+        if let Err(e) = read.read_to_string(&mut contents) {
+            return Err(StorageError::IOError(e));
+        }
+        match serde_json::from_str(&contents) {
+            Ok(v) => Ok(v),
+            Err(e) => Err(StorageError::SerdeError(e)),
+        }
+    }
+
+    /// Writes the index, see storage::write_atomic
+    pub fn save_to_file(&self, file: &std::path::Path) -> Result<(), StorageError> {
+        let json = match serde_json::to_string(&self) {
+            Ok(v) => v,
+            Err(e) => return Err(StorageError::SerdeError(e)),
+        };
+        match storage_helper::write_atomic(file, json.as_bytes()) {
+            Ok(_) => Ok(()),
+            Err(e) => Err(StorageError::IOError(e)),
+        }
+    }
+
+    /// Builds an index from a listing of the bucket
+    ///
+    /// Files this program keeps for itself below REPOSITORY_PREFIX are left out
+    pub fn from_remote_files(bucket_id: &str, remote_files: Vec<RemoteFile>) -> UploadIndex {
+        let mut index = UploadIndex {
+            bucket_id: bucket_id.to_owned(),
+            files: BTreeMap::new(),
+        };
+        for remote_file in remote_files {
+            if remote_file.file_name.starts_with(::REPOSITORY_PREFIX) {
+                continue;
+            }
+            // Transformed files record the size and hash of the original in their file info
+            let size = match remote_file.file_info.get(files::INFO_SIZE).and_then(|s| s.parse().ok()) {
+                Some(v) => v,
+                None => remote_file.content_length,
+            };
+            let sha1 = match remote_file.file_info.get(files::INFO_SHA1) {
+                Some(v) => v.clone(),
+                None => remote_file.content_sha1.trim_start_matches("unverified:").to_owned(),
+            };
+            index.record(&remote_file.file_name, IndexEntry {
+                file_id: remote_file.file_id,
+                size,
+                modified: remote_file.upload_timestamp,
+                sha1,
+            });
+        }
+        index
+    }
+
+    /// Records an uploaded file, replacing any previous entry with the same name
+    pub fn record(&mut self, file_name: &str, entry: IndexEntry) {
+        self.files.insert(file_name.to_owned(), entry);
+    }
+}
@@ -0,0 +1,128 @@
+use std;
+use std::collections::BTreeMap;
+use serde_json;
+use rand;
+use storage::crypto::Keys;
+use storage::chunks;
+
+/// Prefix of the manifests in the bucket, followed by the time of the backup and a random suffix
+pub const MANIFEST_PREFIX: &str = ".raze/manifests/";
+
+/// Where a chunk is stored
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct ChunkLocation {
+    /// File id of the pack containing the chunk
+    pub pack: String,
+    pub offset: u64,
+    pub length: u64,
+    /// Codec the chunk was compressed with, if any
+    #[serde(default)]
+    pub codec: Option<String>,
+    /// Whether the chunk was encrypted, chunks stored before encryption was set up aren't
+    #[serde(default)]
+    pub encrypted: bool,
+}
+
+/// A backed up file, either split into chunks or uploaded as a whole
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct ManifestFile {
+    pub size: u64,
+    /// Local modification time in milliseconds
+    pub modified: u64,
+    /// SHA-1 of the whole file, "none" if B2 didn't compute one
+    pub sha1: String,
+    /// Ids of the chunks making up the file, in order
+    #[serde(default)]
+    pub chunks: Vec<String>,
+    /// File id of the uploaded version, for files uploaded as a whole
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub file_id: Option<String>,
+    /// Permissions, ownership and modification time, see FileMetadata::encode
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub posix: Option<String>,
+    /// Extended attributes, if they were recorded, see FileMetadata::encode_xattrs
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub xattrs: Option<String>,
+    /// Where the file points if it's a symbolic link stored as itself, it has no chunks then
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub symlink: Option<String>,
+}
+
+/// A snapshot of everything backed up by a single backup
+///
+/// Each backup stores a new manifest listing every file and where its content is, so any
+/// manifest is enough to restore the files as they were at that time. \
+/// In chunked mode these are the chunks, otherwise the file version uploaded as a whole. \
+/// In encrypted buckets the whole manifest is encrypted, file names included
+#[derive(Deserialize, Serialize, Debug, Default)]
+pub struct Manifest {
+    /// Unix time in seconds the backup was started
+    pub time: i64,
+    /// Keeps backups started in the same second apart, empty for manifests stored before it was added
+    #[serde(default)]
+    pub suffix: String,
+    /// Keyed by remote file name, the same name the file would have outside chunked mode
+    pub files: BTreeMap<String, ManifestFile>,
+    /// Every chunk used by the files above, empty outside chunked mode
+    pub chunks: BTreeMap<String, ChunkLocation>,
+    /// Names of the packs used, keyed by file id
+    pub packs: BTreeMap<String, String>,
+}
+
+impl Manifest {
+    pub fn new(time: i64) -> Manifest {
+        Manifest {
+            time,
+            suffix: format!("{:08x}", rand::random::<u32>()),
+            ..Default::default()
+        }
+    }
+
+    /// The snapshot as listed by 'snapshots' and given to 'restore --snapshot'
+    pub fn id(&self) -> String {
+        match self.suffix.as_ref() {
+            "" => self.time.to_string(),
+            suffix => format!("{}-{}", self.time, suffix),
+        }
+    }
+
+    /// Name the manifest is stored as, these sort by time
+    pub fn object_name(&self) -> String {
+        match self.suffix.as_ref() {
+            "" => format!("{}{:012}", MANIFEST_PREFIX, self.time),
+            suffix => format!("{}{:012}-{}", MANIFEST_PREFIX, self.time, suffix),
+        }
+    }
+
+    /// Total size of all files
+    pub fn total_size(&self) -> u64 {
+        self.files.values().fold(0, |acc, f| acc + f.size)
+    }
+
+    /// Serializes, compresses and encrypts the manifest, returning the data and codec
+    pub fn encode(&self, keys: Option<&Keys>) -> std::io::Result<(Vec<u8>, Option<String>)> {
+        let json = serde_json::to_vec(&self).unwrap();
+        chunks::encode_blob(keys, &json, true)
+    }
+
+    /// Reverses encode
+    pub fn decode(keys: Option<&Keys>, codec: Option<&str>, data: &[u8]) -> std::io::Result<Manifest> {
+        let json = chunks::decode_blob(keys, codec, data)?;
+        match serde_json::from_slice(&json) {
+            Ok(v) => Ok(v),
+            Err(e) => Err(std::io::Error::new(std::io::ErrorKind::InvalidData, e)),
+        }
+    }
+}
+
+#[test]
+fn test_manifest_names() {
+    let manifest = Manifest::new(1528000000);
+    assert_ne!(manifest.object_name(), Manifest::new(1528000000).object_name());
+    assert_eq!(manifest.object_name(), format!("{}001528000000-{}", MANIFEST_PREFIX, manifest.suffix));
+    assert_eq!(manifest.id(), format!("1528000000-{}", manifest.suffix));
+    // Manifests stored before the suffix keep their names
+    let old: Manifest = serde_json::from_str("{\"time\":1528000000,\"files\":{},\"chunks\":{},\"packs\":{}}").unwrap();
+    assert_eq!(old.object_name(), format!("{}001528000000", MANIFEST_PREFIX));
+    assert_eq!(old.id(), "1528000000");
+}
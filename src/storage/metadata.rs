@@ -0,0 +1,198 @@
+use std;
+use std::path::Path;
+use std::collections::BTreeMap;
+use std::time::{Duration, UNIX_EPOCH};
+use serde_json;
+use base64;
+
+/// Largest encoded size of the extended attributes kept for a file
+/// B2 allows 7000 bytes of file info in total, anything larger is left out
+const MAX_XATTRS_SIZE: usize = 3000;
+
+/// Permissions, ownership, modification time and extended attributes of a file
+///
+/// Recorded when a file is uploaded and put back by restore. \
+/// Only the modification time is kept on systems other than unix
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct FileMetadata {
+    pub mode: Option<u32>,
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+    /// Modification time as seconds and nanoseconds since the unix epoch
+    pub mtime: Option<(u64, u32)>,
+    /// Extended attributes by name, only read when asked for
+    pub xattrs: BTreeMap<String, Vec<u8>>,
+}
+
+impl FileMetadata {
+    /// Reads the metadata of a file, including its extended attributes if 'xattrs' is set
+    pub fn read(path: &Path, xattrs: bool) -> std::io::Result<FileMetadata> {
+        let metadata = std::fs::metadata(path)?;
+        let mut result = FileMetadata {
+            mtime: metadata.modified().ok()
+                .and_then(|m| m.duration_since(UNIX_EPOCH).ok())
+                .map(|d| (d.as_secs(), d.subsec_nanos())),
+            ..Default::default()
+        };
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::MetadataExt;
+            result.mode = Some(metadata.mode());
+            result.uid = Some(metadata.uid());
+            result.gid = Some(metadata.gid());
+            if xattrs {
+                for name in ::xattr::list(path)? {
+                    let value = ::xattr::get(path, &name)?;
+                    if let (Some(name), Some(value)) = (name.to_str(), value) {
+                        result.xattrs.insert(name.to_owned(), value);
+                    }
+                }
+            }
+        }
+        #[cfg(not(unix))]
+        let _ = xattrs;
+        Ok(result)
+    }
+
+    /// Encodes everything but the extended attributes, eg. "mode=100644;uid=1000;gid=1000;mtime=1528000000.250000000"
+    pub fn encode(&self) -> String {
+        let mut fields = Vec::new();
+        if let Some(mode) = self.mode {
+            fields.push(format!("mode={:o}", mode));
+        }
+        if let Some(uid) = self.uid {
+            fields.push(format!("uid={}", uid));
+        }
+        if let Some(gid) = self.gid {
+            fields.push(format!("gid={}", gid));
+        }
+        if let Some((secs, nanos)) = self.mtime {
+            fields.push(format!("mtime={}.{:09}", secs, nanos));
+        }
+        fields.join(";")
+    }
+
+    /// Reverses encode, fields that can't be read are left out
+    pub fn decode(encoded: &str) -> FileMetadata {
+        let mut result = FileMetadata::default();
+        for field in encoded.split(';') {
+            let (key, value) = match field.find('=') {
+                Some(i) => (&field[..i], &field[i+1..]),
+                None => continue,
+            };
+            match key {
+                "mode" => result.mode = u32::from_str_radix(value, 8).ok(),
+                "uid" => result.uid = value.parse().ok(),
+                "gid" => result.gid = value.parse().ok(),
+                "mtime" => {
+                    let mut parts = value.splitn(2, '.');
+                    result.mtime = match (parts.next().map(|s| s.parse()), parts.next().map(|n| n.parse())) {
+                        (Some(Ok(secs)), Some(Ok(nanos))) => Some((secs, nanos)),
+                        (Some(Ok(secs)), None) => Some((secs, 0)),
+                        _ => None,
+                    };
+                },
+                _ => (),
+            }
+        }
+        result
+    }
+
+    /// Encodes the extended attributes, None if there are none or they don't fit in the file info
+    pub fn encode_xattrs(&self) -> Option<String> {
+        if self.xattrs.is_empty() {
+            return None;
+        }
+        let values: BTreeMap<&String, String> = self.xattrs.iter().map(|(k, v)| (k, base64::encode(v))).collect();
+        let encoded = base64::encode(serde_json::to_vec(&values).unwrap());
+        if encoded.len() > MAX_XATTRS_SIZE {
+            return None;
+        }
+        Some(encoded)
+    }
+
+    /// Reads extended attributes encoded by encode_xattrs
+    pub fn decode_xattrs(&mut self, encoded: &str) {
+        let values: BTreeMap<String, String> = match base64::decode(encoded).ok().and_then(|json| serde_json::from_slice(&json).ok()) {
+            Some(v) => v,
+            None => return,
+        };
+        for (name, value) in values {
+            if let Ok(v) = base64::decode(&value) {
+                self.xattrs.insert(name, v);
+            }
+        }
+    }
+
+    /// Puts the metadata back on a file
+    ///
+    /// Changing the owner usually takes root, so it can be skipped with 'owner'. The same goes for
+    /// extended attributes and 'xattrs'. Stops at the first thing that can't be applied
+    pub fn apply(&self, path: &Path, owner: bool, xattrs: bool) -> std::io::Result<()> {
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::MetadataExt;
+            if xattrs {
+                for (name, value) in &self.xattrs {
+                    ::xattr::set(path, name, value)?;
+                }
+            }
+            let current = std::fs::metadata(path)?;
+            if owner && (self.uid.unwrap_or(current.uid()) != current.uid() || self.gid.unwrap_or(current.gid()) != current.gid()) {
+                if let Err(e) = std::os::unix::fs::chown(path, self.uid, self.gid) {
+                    return Err(std::io::Error::new(e.kind(), format!("Can't change the owner ({}), restore as root or use --no-owner", e)));
+                }
+            }
+        }
+        #[cfg(not(unix))]
+        let _ = (owner, xattrs);
+        // The modification time goes before the mode, which might make the file unreadable
+        if let Some((secs, nanos)) = self.mtime {
+            open_for_times(path)?.set_modified(UNIX_EPOCH + Duration::new(secs, nanos))?;
+        }
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            if let Some(mode) = self.mode {
+                std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode & 0o7777))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+// Opens a file or directory so its times can be changed
+#[cfg(not(windows))]
+fn open_for_times(path: &Path) -> std::io::Result<std::fs::File> {
+    std::fs::File::open(path)
+}
+
+// Windows needs the right to write attributes for that, and directories only open with backup semantics
+#[cfg(windows)]
+fn open_for_times(path: &Path) -> std::io::Result<std::fs::File> {
+    use std::os::windows::fs::OpenOptionsExt;
+    const FILE_WRITE_ATTRIBUTES: u32 = 0x100;
+    const FILE_FLAG_BACKUP_SEMANTICS: u32 = 0x02000000;
+    std::fs::OpenOptions::new()
+        .access_mode(FILE_WRITE_ATTRIBUTES)
+        .custom_flags(FILE_FLAG_BACKUP_SEMANTICS)
+        .open(path)
+}
+
+#[test]
+fn test_metadata() {
+    let mut metadata = FileMetadata {
+        mode: Some(0o100755),
+        uid: Some(1000),
+        gid: Some(100),
+        mtime: Some((1528000000, 250)),
+        xattrs: BTreeMap::new(),
+    };
+    assert_eq!(metadata.encode(), "mode=100755;uid=1000;gid=100;mtime=1528000000.000000250");
+    assert_eq!(FileMetadata::decode(&metadata.encode()), metadata);
+    assert_eq!(metadata.encode_xattrs(), None);
+    metadata.xattrs.insert("user.origin".to_owned(), b"https://example.com".to_vec());
+    let mut decoded = FileMetadata::decode(&metadata.encode());
+    decoded.decode_xattrs(&metadata.encode_xattrs().unwrap());
+    assert_eq!(decoded, metadata);
+}
@@ -1 +1,27 @@
-pub mod storage;
\ No newline at end of file
+pub mod storage;
+
+pub mod index;
+
+pub mod filter;
+
+pub mod crypto;
+
+pub mod compression;
+
+pub mod chunks;
+
+pub mod manifest;
+
+pub mod uploads;
+
+pub mod paths;
+
+pub mod metadata;
+
+pub mod plan;
+
+pub mod retention;
+
+pub mod config;
+
+pub mod dirs;
\ No newline at end of file
@@ -0,0 +1,234 @@
+use std::path::{Path, PathBuf};
+use std::collections::BTreeMap;
+use storage::storage::{BackupRoot, BackupFile};
+
+/// Name of the empty file standing in for an empty directory, which B2 has no notion of
+pub const DIRECTORY_MARKER: &str = ".razedir";
+
+/// Splits a line of the backup list into the name of the root, if any, and its path
+pub fn parse_root(line: &str) -> (Option<String>, String) {
+    let line = line.trim();
+    if let Some(i) = line.find('=') {
+        let name = line[..i].trim();
+        if is_valid_name(name) {
+            return (Some(name.to_owned()), line[i+1..].trim().to_owned())
+        }
+    }
+    (None, line.to_owned())
+}
+
+/// Whether a root can be given this name
+///
+/// Names are a single component of letters, digits, '-', '_' and '.', and can't start with a '.'
+/// since those names are reserved for the program itself
+pub fn is_valid_name(name: &str) -> bool {
+    !name.is_empty() && !name.starts_with('.')
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.')
+}
+
+/// Returns the name a file below 'root' is stored under in the bucket
+///
+/// Files below a named root, like 'docs = /home/me/Documents', are stored as the name followed by their
+/// path relative to the root, eg. /home/me/Documents/taxes/2018.pdf -> docs/taxes/2018.pdf.
+/// A named root that is a single file is stored as the name followed by its file name. \
+/// Files below an unnamed root are stored by their absolute path with everything up to the first separator
+/// removed, eg. C:\Users\Kongou\notes.txt -> Users/Kongou/notes.txt. That's how every file was stored before
+/// roots could be named, so it's kept for existing buckets, but C:\data and D:\data end up with the same names
+///
+/// Both are reversed by local_path, '/' is used as separator either way
+pub fn remote_name(root: &BackupRoot, path: &Path) -> String {
+    let name = match root.name {
+        Some(ref n) => n,
+        None => return legacy_name(path),
+    };
+    let relative: Vec<String> = match path.strip_prefix(&root.path) {
+        Ok(rest) if rest.components().next().is_some() => rest.components()
+            .map(|c| c.as_os_str().to_string_lossy().into_owned())
+            .collect(),
+        // The root is the file itself
+        _ => vec![path.file_name().unwrap().to_string_lossy().into_owned()],
+    };
+    format!("{}/{}", name, relative.join("/"))
+}
+
+/// Returns the name of the marker for the directory stored as 'dir_name'
+pub fn marker_name(dir_name: &str) -> String {
+    format!("{}/{}", dir_name, DIRECTORY_MARKER)
+}
+
+/// Whether a remote file name is the marker of an empty directory, see marker_name
+pub fn is_marker(file_name: &str) -> bool {
+    file_name == DIRECTORY_MARKER || file_name.ends_with(&format!("/{}", DIRECTORY_MARKER))
+}
+
+/// Returns the remote form of any local path, for matching against names in the bucket
+///
+/// Paths below a root are mapped like the files in it, others like an unnamed root
+pub fn remote_prefix(roots: &[BackupRoot], path: &str) -> String {
+    let path = path.trim();
+    let mut best: Option<&BackupRoot> = None;
+    for root in roots.iter().filter(|r| r.name.is_some() && Path::new(path).starts_with(&r.path)) {
+        match best {
+            Some(b) if b.path.len() >= root.path.len() => (),
+            _ => best = Some(root),
+        }
+    }
+    match best {
+        Some(root) => remote_name(root, Path::new(path)).trim_end_matches('/').to_owned(),
+        None => strip_root(path),
+    }
+}
+
+/// Maps a remote file name back to where it was backed up from
+///
+/// If 'target' is given, the file goes below it with its full remote name instead. \
+/// Names that don't belong to a named root are matched against the unnamed roots, preferring the longest match.
+/// If none match, the leading '/' can only be put back on unix-like systems
+pub fn local_path(file_name: &str, roots: &[BackupRoot], target: &Option<PathBuf>) -> Option<PathBuf> {
+    if let Some(ref t) = *target {
+        return Some(join_remote(t, file_name));
+    }
+    let (first, rest) = match file_name.find('/') {
+        Some(i) => (&file_name[..i], &file_name[i+1..]),
+        None => (file_name, ""),
+    };
+    for root in roots {
+        if root.name.as_ref().map(|n| n == first).unwrap_or(false) {
+            let root_path = Path::new(&root.path);
+            // A root that is a single file was stored with its file name
+            if !root_path.is_dir() && root_path.file_name().map(|n| n.to_string_lossy() == rest).unwrap_or(false) {
+                return Some(root_path.to_owned());
+            }
+            return Some(join_remote(root_path, rest));
+        }
+    }
+
+    let mut best: Option<(usize, PathBuf)> = None;
+    for root in roots.iter().filter(|r| r.name.is_none()) {
+        let remote_root = strip_root(&root.path);
+        let rest = if file_name == remote_root {
+            ""
+        } else if remote_root.is_empty() {
+            file_name
+        } else if file_name.starts_with(&format!("{}/", remote_root)) {
+            &file_name[remote_root.len()+1..]
+        } else {
+            continue;
+        };
+        match best {
+            Some((len, _)) if len > remote_root.len() => (),
+            _ => best = Some((remote_root.len(), join_remote(Path::new(&root.path), rest))),
+        }
+    }
+    match best {
+        Some((_, path)) => Some(path),
+        None if cfg!(unix) => Some(join_remote(Path::new("/"), file_name)),
+        None => None,
+    }
+}
+
+/// Checks the backup list for roots that would make names ambiguous
+///
+/// Returns a description of every problem found
+pub fn check_roots(roots: &[BackupRoot]) -> Vec<String> {
+    let mut problems = Vec::new();
+    let mut seen: BTreeMap<&str, &str> = BTreeMap::new();
+    for root in roots {
+        if let Some(ref name) = root.name {
+            if let Some(other) = seen.insert(name, &root.path) {
+                problems.push(format!("The name '{}' is used for both {} and {}", name, other, root.path));
+            }
+        }
+    }
+    for root in roots.iter().filter(|r| r.name.is_none()) {
+        let remote_root = strip_root(&root.path);
+        let first = remote_root.split('/').next().unwrap_or("");
+        if let Some(path) = seen.get(first) {
+            problems.push(format!("{} is stored below '{}', which is also the name of {}", root.path, first, path));
+        }
+    }
+    problems
+}
+
+/// Finds files that would be stored under the same name, which would overwrite each other
+///
+/// Returns every such name along with the files mapping to it
+pub fn find_collisions(files: &[BackupFile]) -> Vec<(String, Vec<PathBuf>)> {
+    let mut by_name: BTreeMap<&str, Vec<PathBuf>> = BTreeMap::new();
+    for file in files {
+        let paths = by_name.entry(&file.name).or_default();
+        // The same file listed twice is harmless
+        if !paths.contains(&file.path) {
+            paths.push(file.path.clone());
+        }
+    }
+    by_name.into_iter()
+        .filter(|(_, paths)| paths.len() > 1)
+        .map(|(name, paths)| (name.to_owned(), paths))
+        .collect()
+}
+
+// The name of a file below an unnamed root, see remote_name
+fn legacy_name(path: &Path) -> String {
+    let mut prefix = "";
+    if let Some(parent) = path.parent() {
+        let parent_str = parent.to_str().unwrap();
+        match (parent_str.find('/'), parent_str.find('\\')) {
+            (Some(i), _) => prefix = &parent_str[i + 1..],
+            (None, Some(i)) => prefix = &parent_str[i + 1..],
+            (None, None) => (),
+        }
+    }
+    format!("{}/{}", prefix, path.file_name().unwrap().to_str().unwrap()).replace("\\", "/")
+}
+
+// Removes everything up to the first separator and normalizes to forward slashes,
+// giving the remote form of a local path, eg. C:\Users\Kongou -> Users/Kongou
+fn strip_root(path: &str) -> String {
+    let path = path.trim();
+    let stripped = match (path.find('/'), path.find('\\')) {
+        (Some(i), _) => &path[i+1..],
+        (None, Some(i)) => &path[i+1..],
+        (None, None) => path,
+    };
+    stripped.replace("\\", "/").trim_end_matches('/').to_owned()
+}
+
+// Appends a remote file name to a local path, one component at a time
+fn join_remote(base: &Path, file_name: &str) -> PathBuf {
+    let mut path = base.to_owned();
+    for component in file_name.split('/').filter(|c| !c.is_empty() && *c != "." && *c != "..") {
+        path.push(component);
+    }
+    path
+}
+
+#[test]
+fn test_paths() {
+    let root = |name: Option<&str>, path: &str| BackupRoot {
+        name: name.map(|n| n.to_owned()),
+        path: path.to_owned(),
+        filter: Default::default(),
+    };
+    let roots = vec![root(None, "/home/me/Documents"), root(None, "/home/me/Documents/work"), root(Some("music"), "/mnt/media/music")];
+    assert_eq!(parse_root("music = /mnt/media/music"), (Some("music".to_owned()), "/mnt/media/music".to_owned()));
+    assert_eq!(parse_root("/home/me/a=b"), (None, "/home/me/a=b".to_owned()));
+    assert_eq!(remote_name(&roots[2], Path::new("/mnt/media/music/live/1.flac")), "music/live/1.flac");
+    assert_eq!(remote_name(&roots[0], Path::new("/home/me/Documents/a.txt")), "home/me/Documents/a.txt");
+    assert_eq!(remote_prefix(&roots, "/mnt/media/music/live"), "music/live");
+    assert_eq!(local_path("music/live/1.flac", &roots, &None), Some(PathBuf::from("/mnt/media/music/live/1.flac")));
+    assert_eq!(local_path("home/me/Documents/a.txt", &roots, &None), Some(PathBuf::from("/home/me/Documents/a.txt")));
+    assert_eq!(local_path("home/me/Documents/work/b.txt", &roots, &None), Some(PathBuf::from("/home/me/Documents/work/b.txt")));
+    assert_eq!(local_path("home/me/Documents/a.txt", &roots, &Some(PathBuf::from("/tmp/restore"))),
+               Some(PathBuf::from("/tmp/restore/home/me/Documents/a.txt")));
+    assert_eq!(strip_root("C:\\Users\\Kongou"), "Users/Kongou");
+    assert!(is_marker(&marker_name("music/empty")));
+    assert!(!is_marker("music/not.razedir"));
+    let file = |path: &str, name: &str| BackupFile {
+        path: PathBuf::from(path),
+        name: name.to_owned(),
+        kind: ::storage::storage::EntryKind::File,
+    };
+    let files = vec![file("C:\\data\\a.txt", "data/a.txt"), file("D:\\data\\a.txt", "data/a.txt"), file("D:\\data\\b.txt", "data/b.txt")];
+    assert_eq!(find_collisions(&files).len(), 1);
+}
@@ -0,0 +1,121 @@
+use std;
+use serde_json;
+
+/// How a file in the backup list compares to what is stored in the bucket
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Change {
+    /// Not stored yet
+    New,
+    /// Stored, but changed locally since
+    Modified,
+    /// Stored and unchanged, it would be skipped
+    Unchanged,
+}
+
+impl Change {
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            Change::New => "new",
+            Change::Modified => "modified",
+            Change::Unchanged => "unchanged",
+        }
+    }
+}
+
+/// What a backup would do with a single file
+#[derive(Serialize, Debug, Clone)]
+pub struct PlanEntry {
+    pub change: Change,
+    /// Name the file is stored under
+    pub name: String,
+    pub path: String,
+    /// Bytes that would be read, before compression and encryption
+    pub size: u64,
+}
+
+/// Everything a backup would do, as worked out by 'backup --dry-run'
+#[derive(Serialize, Debug, Default)]
+pub struct BackupPlan {
+    pub entries: Vec<PlanEntry>,
+}
+
+/// How the plan is written out
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum PlanFormat {
+    /// A readable list of what would be uploaded, followed by a summary
+    #[default]
+    Text,
+    Json,
+    Csv,
+}
+
+impl PlanFormat {
+    pub fn parse(s: &str) -> Option<PlanFormat> {
+        match s.to_lowercase().as_ref() {
+            "text" => Some(PlanFormat::Text),
+            "json" => Some(PlanFormat::Json),
+            "csv" => Some(PlanFormat::Csv),
+            _ => None,
+        }
+    }
+}
+
+impl BackupPlan {
+    /// Number of files and their total size for one kind of change
+    pub fn total(&self, change: Change) -> (usize, u64) {
+        self.entries.iter()
+            .filter(|e| e.change == change)
+            .fold((0, 0), |(count, size), e| (count + 1, size + e.size))
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).unwrap()
+    }
+
+    /// One line per file with a header, fields are quoted when needed
+    pub fn to_csv(&self) -> String {
+        let mut csv = String::from("change,size,name,path\n");
+        for entry in &self.entries {
+            csv.push_str(&format!("{},{},{},{}\n", entry.change.as_str(), entry.size, csv_field(&entry.name), csv_field(&entry.path)));
+        }
+        csv
+    }
+
+    /// Writes the plan to 'path' in the given format
+    pub fn save_to_file(&self, path: &std::path::Path, format: PlanFormat) -> std::io::Result<()> {
+        let contents = match format {
+            PlanFormat::Json => self.to_json(),
+            PlanFormat::Csv => self.to_csv(),
+            PlanFormat::Text => self.entries.iter()
+                .map(|e| format!("{:<10} {:>12} {}\n", e.change.as_str(), e.size, e.name))
+                .collect(),
+        };
+        std::fs::write(path, contents)
+    }
+}
+
+// Quotes a CSV field if it contains anything that would break the line apart
+fn csv_field(value: &str) -> String {
+    if value.contains(',') || value.contains('"') || value.contains('\n') {
+        format!("\"{}\"", value.replace("\"", "\"\""))
+    } else {
+        value.to_owned()
+    }
+}
+
+#[test]
+fn test_plan() {
+    let entry = |change: Change, name: &str, size: u64| PlanEntry {
+        change,
+        name: name.to_owned(),
+        path: format!("/{}", name),
+        size,
+    };
+    let plan = BackupPlan {
+        entries: vec![entry(Change::New, "a.txt", 10), entry(Change::Modified, "b, c.txt", 5), entry(Change::New, "d.txt", 1)],
+    };
+    assert_eq!(plan.total(Change::New), (2, 11));
+    assert_eq!(plan.total(Change::Unchanged), (0, 0));
+    assert_eq!(plan.to_csv().lines().nth(2), Some("modified,5,\"b, c.txt\",\"/b, c.txt\""));
+}
@@ -0,0 +1,72 @@
+use time;
+
+// Milliseconds in a day, timestamps from B2 are in milliseconds
+const DAY: u64 = 24*60*60*1000;
+
+/// Which versions of a file, or which snapshots, to keep when pruning
+///
+/// 'keep_last' keeps the newest versions. 'keep_daily' keeps the newest version of each of the last
+/// days that have one, and 'keep_weekly' and 'keep_monthly' do the same for weeks and months. \
+/// A version kept by any rule is kept, everything else can go. Days, weeks and months are counted in UTC,
+/// weeks start on Monday
+#[derive(Debug, PartialEq, Default, Clone)]
+pub struct RetentionPolicy {
+    pub keep_last: usize,
+    pub keep_daily: usize,
+    pub keep_weekly: usize,
+    pub keep_monthly: usize,
+}
+
+impl RetentionPolicy {
+    /// Whether no rule is set, which would keep nothing
+    pub fn is_empty(&self) -> bool {
+        self.keep_last == 0 && self.keep_daily == 0 && self.keep_weekly == 0 && self.keep_monthly == 0
+    }
+
+    /// Decides which versions to keep, given their timestamps in milliseconds, newest first
+    pub fn keep(&self, timestamps: &[u64]) -> Vec<bool> {
+        let mut kept = vec![false; timestamps.len()];
+        for k in kept.iter_mut().take(self.keep_last) {
+            *k = true;
+        }
+        keep_periods(timestamps, &mut kept, self.keep_daily, |t| t / DAY);
+        // The epoch was a Thursday, shift by 3 days so weeks start on Monday
+        keep_periods(timestamps, &mut kept, self.keep_weekly, |t| (t / DAY + 3) / 7);
+        keep_periods(timestamps, &mut kept, self.keep_monthly, |t| {
+            let tm = time::at_utc(time::Timespec::new((t / 1000) as i64, 0));
+            tm.tm_year as u64 * 12 + tm.tm_mon as u64
+        });
+        kept
+    }
+}
+
+// Keeps the newest version in each of the last 'count' periods that have one
+fn keep_periods<F: Fn(u64) -> u64>(timestamps: &[u64], kept: &mut [bool], count: usize, period: F) {
+    let mut last = None;
+    let mut periods = 0;
+    for (i, &t) in timestamps.iter().enumerate() {
+        if periods == count {
+            break;
+        }
+        let p = period(t);
+        if last != Some(p) {
+            kept[i] = true;
+            last = Some(p);
+            periods += 1;
+        }
+    }
+}
+
+#[test]
+fn test_retention() {
+    // 2018-06-03 12:00 UTC, a Sunday
+    let base = 1528027200000;
+    let timestamps = vec![base, base - DAY/2, base - DAY, base - 2*DAY, base - 7*DAY, base - 40*DAY];
+    let policy = RetentionPolicy { keep_last: 1, ..Default::default() };
+    assert_eq!(policy.keep(&timestamps), vec![true, false, false, false, false, false]);
+    let policy = RetentionPolicy { keep_daily: 3, ..Default::default() };
+    assert_eq!(policy.keep(&timestamps), vec![true, false, true, true, false, false]);
+    let policy = RetentionPolicy { keep_weekly: 2, keep_monthly: 3, ..Default::default() };
+    assert_eq!(policy.keep(&timestamps), vec![true, false, false, false, true, true]);
+    assert!(RetentionPolicy::default().is_empty());
+}
@@ -1,69 +1,392 @@
 use std;
 use std::io::{Read, Write, BufRead};
 use serde_json;
-use glob::glob;
+use glob::{glob, Pattern};
+use storage::filter::Filter;
+use storage::paths;
+use sha1;
+use rand;
 
-#[derive(Deserialize, Serialize, Debug)]
+/// The version of the persistent data format written by this build, see PersistentData::from_file
+pub const DATA_VERSION: u32 = 1;
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct PersistentData {
+    /// Format version of the file, files written before it was added count as version 0
+    #[serde(default)]
+    pub version: u32,
     pub last_backup: i64,
+    /// When the last successful backup started, anything modified after it may not be in the bucket
+    #[serde(default)]
+    pub last_backup_start: i64,
     pub active_bucket: String,
     pub bandwidth_limit: usize,
+    /// What to do with symbolic links found below the backup roots
+    #[serde(default)]
+    pub symlinks: SymlinkPolicy,
+    /// Purge refuses to hide more than this percentage of the stored files unless forced
+    #[serde(default = "default_purge_threshold")]
+    pub purge_threshold: u8,
+    /// When the last purge that hid anything ran, so 'unhide --last-purge' can undo it
+    #[serde(default)]
+    pub last_purge: Option<PurgeRun>,
+    // The bucket and throttle the configuration replaced for this run, saved instead of the ones in use
+    #[serde(skip)]
+    saved_bucket: Option<String>,
+    #[serde(skip)]
+    saved_bandwidth_limit: Option<usize>,
+}
+
+/// The time span, in seconds since the epoch, during which a purge was hiding files
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq)]
+pub struct PurgeRun {
+    pub start: i64,
+    pub end: i64,
+}
+
+pub fn default_purge_threshold() -> u8 {
+    ::DEFAULT_PURGE_THRESHOLD
+}
+
+/// How the directory walk treats symbolic links
+///
+/// Links given directly in the backup list are always followed
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum SymlinkPolicy {
+    /// Leave links out of the backup
+    Skip,
+    /// Back up what links point to as if it was there, skipping links back to a directory containing them
+    /// Links were always followed, so this stays the default for existing setups
+    #[default]
+    Follow,
+    /// Store the link itself, restore recreates it
+    Store,
 }
 
+impl SymlinkPolicy {
+    pub fn parse(s: &str) -> Option<SymlinkPolicy> {
+        match s.to_lowercase().as_ref() {
+            "skip" => Some(SymlinkPolicy::Skip),
+            "follow" => Some(SymlinkPolicy::Follow),
+            "store" => Some(SymlinkPolicy::Store),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug)]
 pub enum StorageError {
     SerdeError(serde_json::Error),
     IOError(std::io::Error),
+    /// The file was written by a newer version of the program
+    UnsupportedVersion(u32),
+}
+
+impl std::fmt::Display for StorageError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match *self {
+            StorageError::SerdeError(ref e) => write!(f, "invalid contents, {}", e),
+            StorageError::IOError(ref e) => write!(f, "{}", e),
+            StorageError::UnsupportedVersion(v) => write!(f, "written in format version {}, this version of the program only reads up to {}", v, DATA_VERSION),
+        }
+    }
+}
+
+// Brings the data of an older version up to the next one, the one at index N turns version N into N+1
+// Fields that are missing are filled in here, so the struct never has to guess what an old file meant
+const MIGRATIONS: [fn(&mut serde_json::Map<String, serde_json::Value>); 1] = [
+    migrate_v0,
+];
+
+// Version 0 files predate symlinks, purge_threshold and last_purge, they kept the behaviour of the time
+fn migrate_v0(data: &mut serde_json::Map<String, serde_json::Value>) {
+    data.entry("symlinks").or_insert_with(|| serde_json::to_value(SymlinkPolicy::Follow).unwrap());
+    data.entry("purge_threshold").or_insert_with(|| default_purge_threshold().into());
+    data.entry("last_purge").or_insert(serde_json::Value::Null);
 }
 
 impl PersistentData {
+    /// Settings for a setup that hasn't been used yet
+    pub fn new() -> PersistentData {
+        PersistentData {
+            version: DATA_VERSION,
+            // Never, so a scheduled backup is due right away
+            last_backup: 0,
+            last_backup_start: 0,
+            active_bucket: String::new(),
+            bandwidth_limit: 0,
+            symlinks: Default::default(),
+            purge_threshold: default_purge_threshold(),
+            last_purge: None,
+            saved_bucket: None,
+            saved_bandwidth_limit: None,
+        }
+    }
+
+    /// Uses another bucket for this run only, the one picked with 'set_bucket' stays the one that is saved
+    pub fn override_bucket(&mut self, bucket_id: String) {
+        let previous = std::mem::replace(&mut self.active_bucket, bucket_id);
+        self.saved_bucket.get_or_insert(previous);
+    }
+
+    /// Uses another throttle for this run only, the one set with 'throttle' stays the one that is saved
+    pub fn override_bandwidth_limit(&mut self, limit: usize) {
+        let previous = std::mem::replace(&mut self.bandwidth_limit, limit);
+        self.saved_bandwidth_limit.get_or_insert(previous);
+    }
+
+    /// Picks the bucket to back up to, from now on and in the saved data
+    pub fn set_bucket(&mut self, bucket_id: String) {
+        self.active_bucket = bucket_id;
+        self.saved_bucket = None;
+    }
+
+    /// Sets the throttle, from now on and in the saved data
+    pub fn set_bandwidth_limit(&mut self, limit: usize) {
+        self.bandwidth_limit = limit;
+        self.saved_bandwidth_limit = None;
+    }
+
+    /// Reads the data, migrating files written by older versions
+    ///
+    /// Files from a newer version are refused instead of being read partially and saved without what was lost
     pub fn from_file(file: &std::path::Path) -> Result<PersistentData,StorageError> {
-        let mut read = match std::fs::File::open(file) {
-            Ok(f) => f,
+        let contents = match std::fs::read_to_string(file) {
+            Ok(v) => v,
             Err(e) => return Err(StorageError::IOError(e)),
         };
-        let mut contents = String::new();
-        read.read_to_string(&mut contents).unwrap();
-        let persdata: PersistentData = match serde_json::from_str(&contents) {
+        PersistentData::parse(&contents)
+    }
+
+    pub fn parse(contents: &str) -> Result<PersistentData,StorageError> {
+        let mut data: serde_json::Map<String, serde_json::Value> = match serde_json::from_str(contents) {
             Ok(v) => v,
             Err(e) => return Err(StorageError::SerdeError(e)),
         };
-        Ok(persdata)
+        let version = data.get("version").and_then(|v| v.as_u64()).unwrap_or(0) as u32;
+        if version > DATA_VERSION {
+            return Err(StorageError::UnsupportedVersion(version));
+        }
+        for migration in &MIGRATIONS[version as usize..] {
+            migration(&mut data);
+        }
+        data.insert("version".to_owned(), DATA_VERSION.into());
+        match serde_json::from_value(serde_json::Value::Object(data)) {
+            Ok(v) => Ok(v),
+            Err(e) => Err(StorageError::SerdeError(e)),
+        }
     }
 
-    pub fn save_to_file(&self, file: &std::path::Path){
-        // Serialize the struct
-        let json = serde_json::to_string(&self).unwrap();
-        // Write it to a file
-        let mut write = std::fs::File::create(file).unwrap();
-        write.write_all(json.as_bytes()).unwrap();
+    // The data as it is saved, without what the configuration replaced for this run
+    fn saved(&self) -> PersistentData {
+        let mut saved = self.clone();
+        if let Some(ref bucket) = self.saved_bucket {
+            saved.active_bucket = bucket.clone();
+        }
+        if let Some(limit) = self.saved_bandwidth_limit {
+            saved.bandwidth_limit = limit;
+        }
+        saved
+    }
+
+    /// Writes the data to a temporary file next to it first, then moves that over the old one
+    ///
+    /// A crash while saving leaves either the old or the new file behind, never a partial one
+    pub fn save_to_file(&self, file: &std::path::Path) -> Result<(), StorageError> {
+        let json = match serde_json::to_string(&self.saved()) {
+            Ok(v) => v,
+            Err(e) => return Err(StorageError::SerdeError(e)),
+        };
+        match write_atomic(file, json.as_bytes()) {
+            Ok(_) => Ok(()),
+            Err(e) => Err(StorageError::IOError(e)),
+        }
     }
 }
 
-// Given a list of files and directories in ABSOLUTE PATH, as strings, returns a list of
-// all files contained in the directories and recursively in subdirectories
-pub fn create_file_list(entry_list: Vec<String>) -> Vec<std::path::PathBuf> {
-    let mut paths = std::vec::Vec::new();
-    for entry in entry_list {
-        let p = std::path::Path::new(&entry);
-        if p.is_file() {
-            paths.push(p.to_owned());
+/// Replaces the contents of a file so that it never ends up half written
+///
+/// The contents go to 'file.tmp', which is synced to disk and then renamed over the file. The directory
+/// is synced after that as well, otherwise the rename itself may not survive a power loss
+pub fn write_atomic(file: &std::path::Path, contents: &[u8]) -> std::io::Result<()> {
+    let mut temp_name = file.as_os_str().to_owned();
+    temp_name.push(".tmp");
+    let temp = std::path::PathBuf::from(temp_name);
+    let mut write = std::fs::File::create(&temp)?;
+    write.write_all(contents)?;
+    write.sync_all()?;
+    std::fs::rename(&temp, file)?;
+    // Windows can't open directories as files, renames there are durable once they return
+    #[cfg(unix)]
+    {
+        let dir = match file.parent() {
+            Some(p) if !p.as_os_str().is_empty() => p,
+            _ => std::path::Path::new("."),
+        };
+        std::fs::File::open(dir)?.sync_all()?;
+    }
+    Ok(())
+}
+
+/// A single entry in the backup list, along with the patterns that only apply to it
+#[derive(Debug, Clone)]
+pub struct BackupRoot {
+    /// Name the files below it are stored under, see paths::remote_name
+    pub name: Option<String>,
+    pub path: String,
+    pub filter: Filter,
+}
+
+/// A file to back up and the name it's stored under
+#[derive(Debug, Clone)]
+pub struct BackupFile {
+    pub path: std::path::PathBuf,
+    pub name: String,
+    pub kind: EntryKind,
+}
+
+/// What an entry in the backup is
+#[derive(Debug, Clone, PartialEq)]
+pub enum EntryKind {
+    File,
+    /// A symbolic link stored as itself, pointing at this path
+    Symlink(std::path::PathBuf),
+    /// An empty directory, stored as a marker so restore can recreate it
+    Directory,
+}
+
+// Reads the backup list, where every line is a file or directory to back up
+// Indented lines below a directory are exclude/include patterns for that directory only
+// A line can start with a name for the directory, which is used in the bucket instead of its path, eg.
+// projects = /home/MyUser/Projects
+//     target/
+//     *.tmp
+pub fn read_backup_list(file_path: &std::path::Path) -> Result<Vec<BackupRoot>, std::io::Error> {
+    let mut roots: Vec<(String, Vec<String>)> = Vec::new();
+    for line in read_lines_to_vec(file_path)? {
+        if line.starts_with(' ') || line.starts_with('\t') {
+            match roots.last_mut() {
+                Some(root) => root.1.push(line),
+                None => println!("Ignoring pattern '{}' that doesn't belong to a directory", line.trim()),
+            }
         } else {
-            paths.append(&mut glob_directory(&entry));
+            roots.push((line.trim().to_owned(), Vec::new()));
         }
     }
-    paths
+    Ok(roots.into_iter().map(|(line, patterns)| {
+        let (name, path) = paths::parse_root(&line);
+        BackupRoot {
+            name,
+            path,
+            filter: Filter::parse(&patterns),
+        }
+    }).collect())
 }
 
-// Recursively globs a directory, returning a vec of all files found
-fn glob_directory(dir: &str) -> Vec<std::path::PathBuf>{
+// Reads the global exclude/include patterns, applied to every entry in the backup list
+pub fn read_exclude_list(file_path: &std::path::Path) -> Result<Filter, std::io::Error> {
+    Ok(Filter::parse(&read_lines_to_vec(file_path)?))
+}
+
+// Given a list of files and directories in ABSOLUTE PATH, returns a list of
+// all files contained in the directories and recursively in subdirectories,
+// along with the names they are stored under
+// Anything matched by the global filter or the entry's own filter is skipped,
+// excluded directories aren't even traversed
+// Symbolic links below the entries are handled according to 'symlinks'
+// Directories with nothing to back up in them are listed as well, see paths::marker_name
+pub fn create_file_list(entry_list: &[BackupRoot], global_filter: &Filter, symlinks: SymlinkPolicy) -> Vec<BackupFile> {
+    let mut files = std::vec::Vec::new();
+    for entry in entry_list {
+        let p = std::path::Path::new(&entry.path);
+        let filter = global_filter.combined(&entry.filter);
+        let paths = if p.is_file() {
+            vec![(p.to_owned(), EntryKind::File)]
+        } else {
+            let mut ancestors = dir_id(p).into_iter().collect();
+            glob_directory(&entry.path, "", &filter, symlinks, &mut ancestors)
+        };
+        files.extend(paths.into_iter().map(|(path, kind)| BackupFile {
+            name: match kind {
+                EntryKind::Directory => paths::marker_name(&paths::remote_name(entry, &path)),
+                _ => paths::remote_name(entry, &path),
+            },
+            path,
+            kind,
+        }));
+    }
+    files
+}
+
+// Identifies a directory no matter which path leads to it
+#[cfg(unix)]
+type DirId = (u64, u64);
+#[cfg(not(unix))]
+type DirId = std::path::PathBuf;
+
+// Returns the device and inode of a directory, or its canonical path where those aren't available
+fn dir_id(path: &std::path::Path) -> Option<DirId> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        std::fs::metadata(path).ok().map(|m| (m.dev(), m.ino()))
+    }
+    #[cfg(not(unix))]
+    {
+        std::fs::canonicalize(path).ok()
+    }
+}
+
+// Recursively globs a directory, returning a vec of all files found that aren't excluded
+// 'relative' is the path of 'dir' relative to the backup root, used for matching patterns
+// 'ancestors' are the directories currently being globbed, a link to one of them would never end
+fn glob_directory(dir: &str, relative: &str, filter: &Filter, symlinks: SymlinkPolicy,
+                  ancestors: &mut Vec<DirId>) -> Vec<(std::path::PathBuf, EntryKind)>{
     let mut paths = std::vec::Vec::new();
-    for entry in glob(&format!("{}/*",dir)).unwrap() {
+    for entry in glob(&format!("{}/*",Pattern::escape(dir))).unwrap() {
         match entry {
             Ok(path) => {
+                let name = path.file_name().unwrap().to_string_lossy().into_owned();
+                let child = match relative {
+                    "" => name,
+                    _ => format!("{}/{}", relative, name),
+                };
+                let is_link = std::fs::symlink_metadata(&path).map(|m| m.file_type().is_symlink()).unwrap_or(false);
+                match symlinks {
+                    SymlinkPolicy::Skip if is_link => continue,
+                    SymlinkPolicy::Store if is_link => {
+                        match std::fs::read_link(&path) {
+                            Ok(target) => if !filter.is_excluded(&child, false) {
+                                paths.push((path, EntryKind::Symlink(target)));
+                            },
+                            Err(e) => println!("Failed to read link {}: {}", path.display(), e),
+                        }
+                        continue;
+                    },
+                    _ => (),
+                }
                 if path.is_file() {
-                    paths.push(path);
-                } else if path.is_dir() {
-                    paths.append(&mut glob_directory(path.to_str().unwrap()));
+                    if !filter.is_excluded(&child, false) {
+                        paths.push((path, EntryKind::File));
+                    }
+                } else if path.is_dir() && !filter.is_excluded(&child, true) {
+                    let id = match dir_id(&path) {
+                        Some(v) => v,
+                        None => continue,
+                    };
+                    if ancestors.contains(&id) {
+                        println!("Skipping {}, it links back to a folder containing it", path.display());
+                        continue;
+                    }
+                    ancestors.push(id);
+                    let mut contents = glob_directory(path.to_str().unwrap(), &child, filter, symlinks, ancestors);
+                    ancestors.pop();
+                    if contents.is_empty() {
+                        paths.push((path, EntryKind::Directory));
+                    } else {
+                        paths.append(&mut contents);
+                    }
                 }
             }
             // Debug info if we run glob an unreadable path
@@ -73,10 +396,126 @@ fn glob_directory(dir: &str) -> Vec<std::path::PathBuf>{
     paths
 }
 
-// Returns the total size of all files in the supplied vector of paths
-// Panics if any of the paths aren't a file, use with create_file_list
-pub fn get_total_size(paths: &Vec<std::path::PathBuf>) -> u64 {
-    paths.into_iter().fold(0, |acc, p| acc + std::fs::metadata(p).unwrap().len())
+// Returns the total size of all files in the supplied list, use with create_file_list
+// Stored links take no space, and neither do files deleted since the list was made
+pub fn get_total_size(files: &[BackupFile]) -> u64 {
+    files.iter().filter(|f| f.kind == EntryKind::File).fold(0, |acc, f| acc + std::fs::metadata(&f.path).map(|m| m.len()).unwrap_or(0))
+}
+
+// SHA-1 of no content at all, what B2 expects for an empty file
+pub const EMPTY_SHA1: &str = "da39a3ee5e6b4b0d3255bfef95601890afd80709";
+
+// Returns the SHA-1 of a file's contents as a lowercase hex string, the same format B2 uses
+// Reads the file in chunks, so it's safe to use on large files
+pub fn sha1_file(path: &std::path::Path) -> Result<String, std::io::Error> {
+    let mut read = std::fs::File::open(path)?;
+    let mut hash = sha1::Sha1::new();
+    let mut buf = vec![0u8; 64*1024];
+    loop {
+        let len = read.read(&mut buf)?;
+        if len == 0 {
+            break;
+        }
+        hash.update(&buf[..len]);
+    }
+    Ok(hash.digest().to_string())
+}
+
+/// A file in the cache directory that is removed again when dropped
+///
+/// Kept files outlive the run, eg. for a large upload that can be resumed, so they don't go in the system's temporary directory
+pub struct TempFile {
+    pub path: std::path::PathBuf,
+    kept: bool,
+}
+
+impl TempFile {
+    pub fn new() -> TempFile {
+        TempFile {
+            path: ::config().dirs.cache.join(format!("raze-{:016x}.tmp", rand::random::<u64>())),
+            kept: false,
+        }
+    }
+
+    /// Leaves the file in place after all, the caller is now responsible for removing it
+    pub fn keep(mut self) -> std::path::PathBuf {
+        self.kept = true;
+        self.path.clone()
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        if !self.kept {
+            let _ = std::fs::remove_file(&self.path);
+        }
+    }
+}
+
+/// Passes reads through while hashing them, for when the original has to be hashed on the way to somewhere else
+pub struct HashingReader<R: Read> {
+    inner: R,
+    hash: sha1::Sha1,
+    pub len: u64,
+}
+
+impl<R: Read> HashingReader<R> {
+    pub fn new(inner: R) -> HashingReader<R> {
+        HashingReader {
+            inner,
+            hash: sha1::Sha1::new(),
+            len: 0,
+        }
+    }
+
+    /// SHA-1 of everything read so far
+    pub fn digest(&self) -> String {
+        self.hash.digest().to_string()
+    }
+}
+
+impl<R: Read> Read for HashingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let len = self.inner.read(buf)?;
+        self.hash.update(&buf[..len]);
+        self.len += len as u64;
+        Ok(len)
+    }
+}
+
+/// Passes writes through while hashing them
+pub struct HashingWriter<W: Write> {
+    inner: W,
+    hash: sha1::Sha1,
+    pub len: u64,
+}
+
+impl<W: Write> HashingWriter<W> {
+    pub fn new(inner: W) -> HashingWriter<W> {
+        HashingWriter {
+            inner,
+            hash: sha1::Sha1::new(),
+            len: 0,
+        }
+    }
+
+    /// SHA-1 of everything written so far
+    pub fn digest(&self) -> String {
+        self.hash.digest().to_string()
+    }
+}
+
+impl<W: Write> Write for HashingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let len = self.inner.write(buf)?;
+        self.hash.update(&buf[..len]);
+        self.len += len as u64;
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
 }
 
 // Given a file path, read all non-whitespace lines to a Vec<String>
@@ -103,8 +542,31 @@ pub fn read_lines_to_vec(file_path: &std::path::Path) -> Result<Vec<String>, std
 // Test only works if a backup list file exists and is valid
 #[test]
 fn test_whatever() {
-    let n = read_lines_to_vec(std::path::Path::new("backuplist")).unwrap();
-    let h = create_file_list(n);
+    let n = read_backup_list(std::path::Path::new("backuplist")).unwrap();
+    let h = create_file_list(&n, &Filter::default(), SymlinkPolicy::default());
     let o = get_total_size(&h);
     println!("{}",o);
-}
\ No newline at end of file
+}
+#[test]
+fn test_persistent_data_versions() {
+    // Written before the format had a version
+    let old = PersistentData::parse(r#"{"last_backup":1528027200,"active_bucket":"abc","bandwidth_limit":0}"#).unwrap();
+    assert_eq!(old.version, DATA_VERSION);
+    assert_eq!(old.symlinks, SymlinkPolicy::Follow);
+    assert_eq!(old.purge_threshold, ::DEFAULT_PURGE_THRESHOLD);
+    assert_eq!(old.active_bucket, "abc");
+    let saved = serde_json::to_string(&old).unwrap();
+    assert_eq!(PersistentData::parse(&saved).unwrap().last_backup, 1528027200);
+    let mut overridden = old.clone();
+    overridden.override_bucket("other".to_owned());
+    overridden.override_bandwidth_limit(500);
+    assert_eq!(overridden.active_bucket, "other");
+    assert_eq!(PersistentData::parse(&serde_json::to_string(&overridden.saved()).unwrap()).unwrap().active_bucket, "abc");
+    overridden.set_bucket("chosen".to_owned());
+    assert_eq!(overridden.saved().active_bucket, "chosen");
+    assert_eq!(overridden.saved().bandwidth_limit, 0);
+    match PersistentData::parse(&saved.replace(&format!("\"version\":{}", DATA_VERSION), "\"version\":99")) {
+        Err(StorageError::UnsupportedVersion(99)) => (),
+        _ => panic!("a newer version should be refused"),
+    }
+}
@@ -0,0 +1,79 @@
+use std;
+use std::io::Read;
+use std::collections::BTreeMap;
+use serde_json;
+use storage::storage::{self as storage_helper, StorageError};
+
+/// A large file upload that was started but not finished yet
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct PendingUpload {
+    pub file_id: String,
+    /// The local file being backed up, with its size and modification time in milliseconds when the upload started
+    pub source: String,
+    pub source_size: u64,
+    pub source_modified: u64,
+    /// The file that is actually uploaded
+    /// This is the source itself, or a temporary file for encrypted and compressed uploads
+    pub content: String,
+    pub part_size: u64,
+    /// File info the upload was started with
+    pub info: BTreeMap<String, String>,
+    /// SHA-1 of every part uploaded so far, by part number
+    pub parts: BTreeMap<u32, String>,
+}
+
+/// Large file uploads that haven't finished yet, keyed by remote file name
+///
+/// Kept on disk so the next backup continues where an interrupted one stopped
+#[derive(Deserialize, Serialize, Debug, Default)]
+pub struct PendingUploads {
+    pub bucket_id: String,
+    pub uploads: BTreeMap<String, PendingUpload>,
+}
+
+impl PendingUploads {
+    pub fn from_file(file: &std::path::Path) -> Result<PendingUploads,StorageError> {
+        let mut read = match std::fs::File::open(file) {
+            Ok(f) => f,
+            Err(e) => return Err(StorageError::IOError(e)),
+        };
+        let mut contents = String::new();
+        if let Err(e) = read.read_to_string(&mut contents) {
+            return Err(StorageError::IOError(e));
+        }
+        match serde_json::from_str(&contents) {
+            Ok(v) => Ok(v),
+            Err(e) => Err(StorageError::SerdeError(e)),
+        }
+    }
+
+    /// Writes the pending uploads, see storage::write_atomic
+    pub fn save_to_file(&self, file: &std::path::Path) -> Result<(), StorageError> {
+        let json = match serde_json::to_string(&self) {
+            Ok(v) => v,
+            Err(e) => return Err(StorageError::SerdeError(e)),
+        };
+        match storage_helper::write_atomic(file, json.as_bytes()) {
+            Ok(_) => Ok(()),
+            Err(e) => Err(StorageError::IOError(e)),
+        }
+    }
+}
+
+impl PendingUpload {
+    /// Whether the upload can still be continued
+    ///
+    /// The source must be unchanged since the upload started, and the uploaded content still around
+    pub fn is_resumable(&self) -> bool {
+        let metadata = match std::fs::metadata(&self.source) {
+            Ok(v) => v,
+            Err(_e) => return false,
+        };
+        let modified = match metadata.modified().map(|m| m.duration_since(std::time::UNIX_EPOCH)) {
+            Ok(Ok(v)) => v.as_secs()*1000,
+            _ => 0u64,
+        };
+        metadata.len() == self.source_size && modified == self.source_modified
+            && std::path::Path::new(&self.content).is_file()
+    }
+}